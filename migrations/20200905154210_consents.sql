@@ -0,0 +1,11 @@
+CREATE TABLE consents (
+    user_id     varchar(64) NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+    client_id   varchar(64) NOT NULL,
+
+    inserted_at timestamptz NOT NULL,
+    updated_at  timestamptz NOT NULL,
+
+    scopes      text[]      NOT NULL,
+
+    PRIMARY KEY (user_id, client_id)
+);
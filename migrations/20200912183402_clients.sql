@@ -0,0 +1,17 @@
+CREATE TABLE clients (
+    id            varchar(64)  NOT NULL PRIMARY KEY,
+
+    inserted_at   timestamptz  NOT NULL,
+    updated_at    timestamptz  NOT NULL,
+
+    name          varchar(64)  NOT NULL,
+    logo_uri      text,
+
+    secret        varchar(256),
+    redirect_urls text[]       NOT NULL,
+    scopes        text[]       NOT NULL,
+    trusted       boolean      NOT NULL,
+
+    disabled      boolean      NOT NULL DEFAULT FALSE,
+    seeded        boolean      NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,5 @@
+-- Authorization codes waiting to be redeemed, deleted once exchanged for a token so they can't be redeemed twice
+CREATE TABLE authorization_codes (
+    id         varchar(32) NOT NULL PRIMARY KEY,
+    expires_at timestamptz NOT NULL
+);
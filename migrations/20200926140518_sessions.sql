@@ -0,0 +1,15 @@
+CREATE TABLE sessions (
+    id            varchar(64)  NOT NULL PRIMARY KEY,
+    user_id       varchar(64)  NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+
+    inserted_at   timestamptz  NOT NULL,
+    updated_at    timestamptz  NOT NULL,
+    expires_at    timestamptz  NOT NULL,
+    auth_time     timestamptz  NOT NULL,
+
+    provider_name varchar(32)  NOT NULL,
+    provider_id   varchar(256) NOT NULL,
+    user_agent    text
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
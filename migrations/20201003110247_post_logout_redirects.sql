@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN post_logout_redirect_urls text[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,22 @@
+ALTER TABLE clients ADD COLUMN backchannel_logout_url text;
+
+ALTER TABLE sessions ADD COLUMN clients text[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE vaulth ADD COLUMN disabled_at timestamptz;
+
+CREATE TABLE logout_deliveries (
+    id              bigserial   NOT NULL PRIMARY KEY,
+    client_id       varchar(64) NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+    user_id         varchar(64) NOT NULL,
+    session_id      varchar(64),
+
+    inserted_at     timestamptz NOT NULL,
+    updated_at      timestamptz NOT NULL,
+
+    status          varchar(16) NOT NULL,
+    attempts        integer     NOT NULL,
+    next_attempt_at timestamptz NOT NULL,
+    last_error      text
+);
+
+CREATE INDEX logout_deliveries_pending_idx ON logout_deliveries (next_attempt_at) WHERE status = 'pending';
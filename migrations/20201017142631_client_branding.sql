@@ -0,0 +1,2 @@
+ALTER TABLE clients ADD COLUMN primary_color varchar(7);
+ALTER TABLE clients ADD COLUMN background_color varchar(7);
@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN theme varchar(64);
@@ -0,0 +1,5 @@
+ALTER TABLE vaulth ADD COLUMN delete_at timestamptz;
+
+CREATE INDEX vaulth_delete_at_idx ON vaulth (delete_at) WHERE delete_at IS NOT NULL;
+
+ALTER TABLE logout_deliveries ADD COLUMN event varchar(16) NOT NULL DEFAULT 'logout';
@@ -0,0 +1,26 @@
+CREATE TABLE audit_events (
+    id          bigserial   NOT NULL PRIMARY KEY,
+    user_id     varchar(64) NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+
+    inserted_at timestamptz NOT NULL,
+
+    event       varchar(32) NOT NULL,
+    client_id   varchar(64),
+    user_agent  text
+);
+
+CREATE INDEX audit_events_user_id_idx ON audit_events (user_id, inserted_at);
+
+CREATE TABLE exports (
+    id          varchar(64) NOT NULL PRIMARY KEY,
+    user_id     varchar(64) NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+
+    inserted_at timestamptz NOT NULL,
+    updated_at  timestamptz NOT NULL,
+    expires_at  timestamptz NOT NULL,
+
+    status      varchar(16) NOT NULL,
+    data        text
+);
+
+CREATE INDEX exports_user_id_idx ON exports (user_id);
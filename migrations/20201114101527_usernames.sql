@@ -0,0 +1,3 @@
+ALTER TABLE vaulth ADD COLUMN username varchar(32);
+
+CREATE UNIQUE INDEX vaulth_username_idx ON vaulth (lower(username));
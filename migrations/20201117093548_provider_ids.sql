@@ -0,0 +1,4 @@
+-- Identities from providers belong to a single user, so signing up twice with one can't register two
+CREATE UNIQUE INDEX vaulth_github_id_idx ON vaulth (github_id);
+CREATE UNIQUE INDEX vaulth_discord_id_idx ON vaulth (discord_id);
+CREATE UNIQUE INDEX vaulth_google_id_idx ON vaulth (google_id);
@@ -0,0 +1,16 @@
+ALTER TABLE vaulth ALTER COLUMN username TYPE varchar(64);
+ALTER TABLE vaulth ADD COLUMN username_skeleton varchar(64);
+
+UPDATE vaulth SET username_skeleton = lower(username) WHERE username IS NOT NULL;
+
+CREATE UNIQUE INDEX vaulth_username_skeleton_idx ON vaulth (username_skeleton);
+
+-- Usernames released by renamed or deleted users, which aren't reusable by others for a while
+CREATE TABLE username_history (
+    skeleton    varchar(64) NOT NULL,
+    username    varchar(64) NOT NULL,
+    user_id     varchar(64) NOT NULL,
+    released_at timestamptz NOT NULL
+);
+
+CREATE INDEX username_history_skeleton_idx ON username_history (skeleton, released_at);
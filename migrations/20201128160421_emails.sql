@@ -0,0 +1,5 @@
+ALTER TABLE vaulth ADD COLUMN email varchar(256);
+ALTER TABLE vaulth ADD COLUMN email_verified boolean NOT NULL DEFAULT false;
+
+-- Unverified addresses can be claimed by anyone, so only verified ones have to be unique
+CREATE UNIQUE INDEX vaulth_email_idx ON vaulth (lower(email)) WHERE email_verified;
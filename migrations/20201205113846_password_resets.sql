@@ -0,0 +1,12 @@
+CREATE TABLE password_resets (
+    id          varchar(64)  NOT NULL PRIMARY KEY,
+    user_id     varchar(64)  NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+
+    inserted_at timestamptz  NOT NULL,
+    expires_at  timestamptz  NOT NULL,
+    used_at     timestamptz,
+
+    secret      varchar(256) NOT NULL
+);
+
+CREATE INDEX password_resets_user_id_idx ON password_resets (user_id);
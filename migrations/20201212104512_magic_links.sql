@@ -0,0 +1,13 @@
+CREATE TABLE magic_links (
+    id          varchar(64)  NOT NULL PRIMARY KEY,
+    user_id     varchar(64)  NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+
+    inserted_at timestamptz  NOT NULL,
+    expires_at  timestamptz  NOT NULL,
+    used_at     timestamptz,
+
+    secret      varchar(256) NOT NULL,
+    params      text         NOT NULL
+);
+
+CREATE INDEX magic_links_user_id_idx ON magic_links (user_id);
@@ -0,0 +1,19 @@
+ALTER TABLE vaulth
+    ADD COLUMN totp_secret       text,
+    ADD COLUMN totp_confirmed_at timestamptz,
+    ADD COLUMN totp_last_step    bigint;
+
+ALTER TABLE sessions
+    ADD COLUMN amr text[] NOT NULL DEFAULT '{}';
+
+CREATE TABLE recovery_codes (
+    id          bigserial    NOT NULL PRIMARY KEY,
+    user_id     varchar(64)  NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+
+    inserted_at timestamptz  NOT NULL,
+    used_at     timestamptz,
+
+    code        varchar(256) NOT NULL
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,13 @@
+CREATE TABLE webauthn_credentials (
+    id           text         NOT NULL PRIMARY KEY,
+    user_id      varchar(64)  NOT NULL REFERENCES vaulth (id) ON DELETE CASCADE,
+    name         varchar(64)  NOT NULL,
+
+    inserted_at  timestamptz  NOT NULL,
+    last_used_at timestamptz,
+
+    public_key   bytea        NOT NULL,
+    sign_count   bigint       NOT NULL
+);
+
+CREATE INDEX webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
@@ -0,0 +1,5 @@
+-- Challenges handed out for WebAuthn ceremonies, deleted once answered so assertions can't be replayed
+CREATE TABLE webauthn_challenges (
+    challenge  varchar(64) NOT NULL PRIMARY KEY,
+    expires_at timestamptz NOT NULL
+);
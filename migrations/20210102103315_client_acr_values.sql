@@ -0,0 +1 @@
+ALTER TABLE clients ADD COLUMN acr_values text[] NOT NULL DEFAULT '{}';
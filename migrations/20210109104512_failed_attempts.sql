@@ -0,0 +1,8 @@
+CREATE TABLE failed_attempts (
+    key          varchar(320) NOT NULL PRIMARY KEY,
+
+    failures     integer NOT NULL,
+    last_failure timestamptz NOT NULL,
+    -- Only unlocked by admins
+    locked       boolean NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,96 @@
+//! Deletion of user accounts, optionally after a grace period during which they can be restored
+
+use crate::{
+    config::Config,
+    db::{audit_events, AuditEvent, Consent, LogoutDelivery, Session, User, UsernameRelease},
+    logout,
+};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use std::time;
+
+/// Scope clients need to manage the account of users on their behalf
+pub const SCOPE: &str = "account";
+
+/// How often scheduled deletions are checked for
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// Deletes a user, or schedules their deletion if a grace period is configured
+/// Scheduled users are logged out everywhere and can't log back in
+#[tracing::instrument(level = "debug")]
+pub async fn delete(user_id: &str, config: &'static Config, pool: &PgPool) -> Result<Option<User>> {
+    match config.account.deletion_grace_period {
+        Some(grace_period) => {
+            let delete_at = Utc::now() + Duration::minutes(grace_period);
+            let user = User::update_delete_at(user_id, Some(delete_at), pool).await?;
+            if user.is_some() {
+                logout::end_all(user_id, pool).await?;
+                AuditEvent::insert(user_id, audit_events::DELETION_REQUESTED, None, None, pool)
+                    .await?;
+            }
+            Ok(user)
+        }
+        None => purge(user_id, pool).await,
+    }
+}
+
+/// Cancels the scheduled deletion of a user
+#[tracing::instrument(level = "debug")]
+pub async fn restore(user_id: &str, pool: &PgPool) -> Result<Option<User>> {
+    let user = User::update_delete_at(user_id, None, pool).await?;
+    if user.is_some() {
+        AuditEvent::insert(user_id, audit_events::DELETION_CANCELED, None, None, pool).await?;
+    }
+    Ok(user)
+}
+
+/// Deletes a user along with their sessions, consents and identities,
+/// then notifies every client they used so they can purge their own data
+#[tracing::instrument(level = "debug")]
+pub async fn purge(user_id: &str, pool: &PgPool) -> Result<Option<User>> {
+    let mut clients = LogoutDelivery::select_clients_by_user(user_id, pool).await?;
+    for consent in Consent::select_by_user(user_id, pool).await? {
+        clients.push(consent.client_id);
+    }
+    for session in Session::select_by_user(user_id, pool).await? {
+        clients.extend(session.clients);
+    }
+    clients.sort();
+    clients.dedup();
+
+    // Sessions and consents are deleted along with the user,
+    // clients must be notified of every deletion that went through
+    let mut tx = pool.begin().await?;
+    let user = User::delete(user_id, &mut tx).await?;
+    if let Some(user) = &user {
+        logout::notify_deletion(user_id, &clients, &mut tx).await?;
+
+        // Keep others from impersonating the deleted user right away
+        if let (Some(username), Some(skeleton)) = (&user.username, &user.username_skeleton) {
+            UsernameRelease::insert(skeleton, username, user_id, &mut tx).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(user)
+}
+
+/// Starts the background task deleting users once their grace period is over
+pub fn spawn(pool: &'static PgPool) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = purge_due(pool).await {
+                tracing::error!("couldn't delete scheduled users: {}", e);
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[tracing::instrument(level = "debug")]
+async fn purge_due(pool: &'static PgPool) -> Result<()> {
+    for user_id in User::select_due_deletions(pool).await? {
+        purge(&user_id, pool).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Authentication context classes, levels of assurance clients can require from users
+//!
+//! Clients list the levels they accept in their configuration and in the `acr_values` parameter,
+//! the lowest known one of each being required. Users whose session doesn't reach it are asked
+//! for their second factor before being sent back.
+
+use crate::{
+    db::Client,
+    providers::{Params, AMR_MFA},
+};
+
+/// Users authenticated with a single factor, like a password, a login link or a provider
+pub const SINGLE_FACTOR: &str = "1fa";
+/// Users also authenticated with a second factor, or with a passkey verifying them
+pub const MULTI_FACTOR: &str = "2fa";
+
+/// Known levels, by increasing assurance
+const LEVELS: &[&str] = &[SINGLE_FACTOR, MULTI_FACTOR];
+
+/// Whether a value is a level Vaulth knows
+pub fn valid(value: &str) -> bool {
+    LEVELS.contains(&value)
+}
+
+/// Level reached by a user who authenticated with the given methods
+pub fn reached(amr: &[String]) -> &'static str {
+    if amr.iter().any(|m| m == AMR_MFA) {
+        MULTI_FACTOR
+    } else {
+        SINGLE_FACTOR
+    }
+}
+
+/// Level users must reach to be sent back to the client, the highest of the ones the client
+/// and the request require, unknown requested values being ignored since they are only voluntary
+pub fn required(params: &Params, client: &Client) -> &'static str {
+    let configured = lowest(client.acr_values.iter().map(String::as_str));
+    let requested = lowest(params.acr_values.as_deref().unwrap_or_default().split(' '));
+    LEVELS[configured.max(requested)]
+}
+
+/// Whether methods a user authenticated with reach a level
+pub fn satisfies(amr: &[String], required: &str) -> bool {
+    level(reached(amr)) >= level(required)
+}
+
+fn level(value: &str) -> usize {
+    LEVELS.iter().position(|l| *l == value).unwrap_or(0)
+}
+
+/// Lowest of the known levels, or the lowest level overall if none are known
+fn lowest<'a>(values: impl Iterator<Item = &'a str>) -> usize {
+    values.filter(|v| valid(v)).map(level).min().unwrap_or(0)
+}
@@ -1,27 +1,64 @@
 use anyhow::Result;
-use serde::Deserialize;
+use derivative::Derivative;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 use std::{
     collections::HashMap,
+    net::IpAddr,
     path::{Path, PathBuf},
 };
 use tokio::fs;
 
-#[derive(Debug, Deserialize)]
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub port: u16,
+    /// May contain the password of the database
+    #[derivative(Debug = "ignore")]
     pub database_url: String,
     pub user_agent: Option<String>,
     pub log_level: Option<String>,
     pub token: TokenConfig,
     pub tls: Option<TlsConfig>,
     pub hash: HashConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub ui: UiConfig,
+    #[serde(default)]
+    pub profile: ProfileConfig,
+    #[serde(default)]
+    pub account: AccountConfig,
+    #[serde(default)]
+    pub usernames: UsernameConfig,
+    #[serde(default)]
+    pub password: PasswordConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
     pub root_uri: String,
+    /// IDs of the users allowed to use the admin API
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Addresses of the reverse proxies whose `X-Forwarded-For` header is trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
 
+    #[serde(default)]
     pub clients: HashMap<String, ClientConfig>,
+    pub registration: Option<RegistrationConfig>,
+    /// Outgoing mail, emails can't be verified nor passwords reset if absent
+    pub mail: Option<MailConfig>,
+    /// Passwordless login through mailed links, disabled if absent or if mail isn't configured
+    pub magic_links: Option<MagicLinkConfig>,
+    /// Second factor through authenticator apps, disabled if absent
+    pub totp: Option<TotpConfig>,
+    /// Passkeys and security keys, disabled if absent
+    pub webauthn: Option<WebAuthnConfig>,
 
     pub github: Option<OAuth2Config>,
     pub discord: Option<OAuth2Config>,
+    pub google: Option<OAuth2Config>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,9 +66,16 @@ pub struct Config {
 pub struct TokenConfig {
     pub public_key: PathBuf,
     pub private_key: PathBuf,
+    /// Random bytes signing the tokens only Vaulth reads back, like session cookies
+    #[serde(default = "default_secret_key")]
+    pub secret_key: PathBuf,
     pub duration: i64,
 }
 
+fn default_secret_key() -> PathBuf {
+    PathBuf::from("secret.key")
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct TlsConfig {
@@ -39,7 +83,8 @@ pub struct TlsConfig {
     pub key: PathBuf,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct HashConfig {
     pub hash_len: Option<u32>,
@@ -47,20 +92,371 @@ pub struct HashConfig {
     pub lanes: Option<u32>,
     pub mem_cost: Option<u32>,
     pub time_cost: Option<u32>,
+    #[derivative(Debug = "ignore")]
     pub secret: Option<String>,
+    /// Secrets used before the current one, still accepted until hashes made with them are replaced
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub previous_secrets: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SessionConfig {
+    /// Duration for which users stay logged in to Vaulth, in minutes
+    pub duration: i64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            duration: 60 * 24 * 14,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
+pub struct UiConfig {
+    /// Directory containing templates overriding the built-in ones
+    pub templates: Option<PathBuf>,
+    /// Directory containing translations
+    pub locales: Option<PathBuf>,
+    /// Language used when none of the ones preferred by the user are available
+    #[serde(default = "default_locale")]
+    pub default_locale: String,
+}
+
+fn default_locale() -> String {
+    "en".to_owned()
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            templates: None,
+            locales: None,
+            default_locale: default_locale(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProfileConfig {
+    /// Words users can't put in their profile, matched regardless of case
+    #[serde(default)]
+    pub blocked_words: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AccountConfig {
+    /// How recently users must have logged in to delete their account or change their passkeys without their password, in minutes
+    pub reauthentication_window: i64,
+    /// Delay before deleted accounts are actually removed, in minutes, accounts are removed immediately if absent
+    pub deletion_grace_period: Option<i64>,
+    /// How the IDs of new users are generated
+    pub id_strategy: IdStrategy,
+    /// ID of this instance, embedded in snowflake IDs
+    pub worker_id: u16,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdStrategy {
+    /// Random version 4 UUIDs
+    Uuid,
+    /// Time sortable ULIDs
+    Ulid,
+    /// Time sortable 64 bit integers, unique across instances with different worker IDs
+    Snowflake,
+}
+
+impl Default for AccountConfig {
+    fn default() -> Self {
+        Self {
+            reauthentication_window: 5,
+            deletion_grace_period: None,
+            id_strategy: IdStrategy::Uuid,
+            worker_id: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct UsernameConfig {
+    /// Pattern usernames must match, which should be anchored
+    #[serde(deserialize_with = "regex")]
+    pub pattern: Regex,
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Names nobody can pick, in addition to the built-in ones
+    pub reserved: Vec<String>,
+    /// How long a username stays unavailable to others after it was changed or its user deleted, in days
+    pub reuse_delay: i64,
+}
+
+impl Default for UsernameConfig {
+    fn default() -> Self {
+        Self {
+            pattern: Regex::new("^[A-Za-z0-9_.-]+$").unwrap(),
+            min_length: 3,
+            max_length: 32,
+            reserved: Vec::new(),
+            reuse_delay: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PasswordConfig {
+    /// Minimum length of passwords, in characters
+    pub min_length: usize,
+    /// Maximum length of passwords, in characters, bounding the work needed to hash them
+    pub max_length: usize,
+    /// Minimum strength of passwords, from 0 to 4 as estimated by zxcvbn
+    pub min_score: u8,
+    /// File of SHA-1 hashes of breached passwords nobody can pick, one per line in ascending order
+    pub breached_passwords: Option<PathBuf>,
+    /// Duration for which password reset links stay valid, in minutes
+    pub reset_duration: i64,
+    /// How many password resets can be requested per hour for a single address
+    pub reset_limit_per_address: u32,
+    /// How many password resets can be requested per hour from a single IP address
+    pub reset_limit_per_ip: u32,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            min_score: 2,
+            breached_passwords: None,
+            reset_duration: 60,
+            reset_limit_per_address: 3,
+            reset_limit_per_ip: 20,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LockoutConfig {
+    /// Where failed attempts are counted
+    pub store: LockoutStore,
+    /// Failed attempts allowed per account or client before further ones are delayed
+    pub free_attempts: u32,
+    /// Failed attempts allowed per IP address, which may be shared by many users
+    pub free_attempts_per_ip: u32,
+    /// Delay after the last allowed failed attempt, doubling with every further one, in seconds
+    pub base_delay: i64,
+    /// Longest delay between attempts, in seconds
+    pub max_delay: i64,
+    /// Failed attempts after which accounts stay locked until an admin unlocks them, never if absent
+    pub permanent_after: Option<u32>,
+    /// Duration without failed attempts after which they are forgotten, in minutes
+    pub reset_after: i64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            store: LockoutStore::default(),
+            free_attempts: 5,
+            free_attempts_per_ip: 20,
+            base_delay: 1,
+            max_delay: 15 * 60,
+            permanent_after: None,
+            reset_after: 60 * 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockoutStore {
+    /// Only counts attempts made to this instance
+    Memory,
+    /// Shares counts between the instances using the same database
+    Postgres,
+}
+
+impl Default for LockoutStore {
+    fn default() -> Self {
+        Self::Memory
+    }
+}
+
+fn regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct ClientConfig {
-    pub client_secret: String,
+    pub name: Option<String>,
+    pub logo_uri: Option<String>,
+    /// Public clients don't have a secret
+    #[derivative(Debug = "ignore")]
+    pub client_secret: Option<String>,
     pub redirect_urls: Vec<String>,
+    /// Scopes the client is allowed to request, only `profile` if absent so clients configured before scopes
+    /// existed can still read the profile of users
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// First-party clients don't ask users for consent
+    #[serde(default)]
+    pub trusted: bool,
+    /// URLs users can be sent back to after logging out
+    #[serde(default)]
+    pub post_logout_redirect_urls: Vec<String>,
+    /// URL notified when a session the client took part in ends
+    pub backchannel_logout_url: Option<String>,
+    /// Colors used on the hosted pages, as `#rrggbb` or `#rgb`
+    pub primary_color: Option<String>,
+    pub background_color: Option<String>,
+    /// Subdirectory of the templates directory overriding templates for this client
+    pub theme: Option<String>,
+    /// Authentication levels users must reach to be sent back to the client, the lowest one being required
+    #[serde(default)]
+    pub acr_values: Vec<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["profile".to_owned()]
+}
+
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistrationConfig {
+    /// Tokens allowed to register new clients
+    #[derivative(Debug = "ignore")]
+    pub initial_access_tokens: Vec<String>,
+    /// Scopes registered clients are allowed to request
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MailConfig {
+    /// Sender of the mail, like `Vaulth <noreply@example.com>`
+    pub from: String,
+    /// Duration for which email verification links stay valid, in minutes
+    #[serde(default = "default_verification_duration")]
+    pub verification_duration: i64,
+    #[serde(flatten)]
+    pub transport: MailTransport,
+}
+
+fn default_verification_duration() -> i64 {
+    60 * 24
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct MagicLinkConfig {
+    /// Duration for which login links stay valid, in minutes
+    pub duration: i64,
+    /// How many links can be requested per hour for a single address
+    pub limit_per_address: u32,
+    /// How many links can be requested per hour from a single IP address
+    pub limit_per_ip: u32,
+}
+
+impl Default for MagicLinkConfig {
+    fn default() -> Self {
+        Self {
+            duration: 15,
+            limit_per_address: 5,
+            limit_per_ip: 20,
+        }
+    }
+}
+
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct TotpConfig {
+    /// Name authenticator apps show next to the codes
+    #[serde(default = "default_name")]
+    pub issuer: String,
+    /// Key encrypting the stored secrets, 32 bytes encoded in base64
+    #[derivative(Debug = "ignore")]
+    pub encryption_key: String,
+}
+
+fn default_name() -> String {
+    "Vaulth".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebAuthnConfig {
+    /// Name authenticators show when passkeys are created
+    #[serde(default = "default_name")]
+    pub rp_name: String,
+    /// Domain passkeys are bound to, defaults to the host of the root URI
+    pub rp_id: Option<String>,
+    /// Origins passkeys can be used from, defaults to the origin of the root URI
+    #[serde(default)]
+    pub origins: Vec<String>,
+}
+
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
+#[serde(rename_all = "kebab-case", tag = "transport")]
+pub enum MailTransport {
+    Smtp {
+        host: String,
+        /// Defaults to the standard port of the TLS mode
+        port: Option<u16>,
+        username: Option<String>,
+        #[derivative(Debug = "ignore")]
+        password: Option<String>,
+        #[serde(default)]
+        tls: SmtpTls,
+    },
+    /// Appends mail to a file, or prints it if absent, for testing
+    File { path: Option<PathBuf> },
+    /// Writes mail to the standard input of a command, like `sendmail -t`
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    None,
+    StartTls,
+    Implicit,
+}
+
+impl Default for SmtpTls {
+    fn default() -> Self {
+        Self::StartTls
+    }
+}
+
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct OAuth2Config {
     pub client_id: String,
+    #[derivative(Debug = "ignore")]
     pub client_secret: String,
 }
 
@@ -0,0 +1,85 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Done, PgPool};
+
+pub const SIGNUP: &str = "signup";
+pub const IMPORTED: &str = "imported";
+pub const LOGIN: &str = "login";
+pub const LOGOUT: &str = "logout";
+pub const CONSENT_GRANTED: &str = "consent_granted";
+pub const CONSENT_REVOKED: &str = "consent_revoked";
+pub const PROFILE_UPDATED: &str = "profile_updated";
+pub const USERNAME_CHANGED: &str = "username_changed";
+pub const EMAIL_CHANGED: &str = "email_changed";
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const TOTP_ENABLED: &str = "totp_enabled";
+pub const TOTP_DISABLED: &str = "totp_disabled";
+pub const RECOVERY_CODES_GENERATED: &str = "recovery_codes_generated";
+pub const PASSKEY_ADDED: &str = "passkey_added";
+pub const PASSKEY_REMOVED: &str = "passkey_removed";
+pub const LOCKED_OUT: &str = "locked_out";
+pub const UNLOCKED: &str = "unlocked";
+pub const DISABLED: &str = "disabled";
+pub const ENABLED: &str = "enabled";
+pub const DELETION_REQUESTED: &str = "deletion_requested";
+pub const DELETION_CANCELED: &str = "deletion_canceled";
+pub const EXPORT_REQUESTED: &str = "export_requested";
+
+/// Something that happened to the account of a user
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+
+    pub event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl AuditEvent {
+    #[tracing::instrument(level = "debug")]
+    pub async fn insert(
+        user_id: &str,
+        event: &str,
+        client_id: Option<&str>,
+        user_agent: Option<&str>,
+        pool: &PgPool,
+    ) -> sqlx::Result<u64> {
+        sqlx::query(
+            "
+INSERT INTO audit_events (user_id, inserted_at, event, client_id, user_agent)
+VALUES ($1, $2, $3, $4, $5)
+            ",
+        )
+        .bind(user_id)
+        .bind(now())
+        .bind(event)
+        .bind(client_id)
+        .bind(user_agent)
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM audit_events WHERE user_id = $1 ORDER BY inserted_at")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn count_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<i64> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM audit_events WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+    }
+}
@@ -0,0 +1,41 @@
+use super::now;
+use chrono::Duration;
+use sqlx::{Done, PgPool};
+
+/// Authorization code issued to a client, which can only be redeemed once
+#[derive(Debug)]
+pub struct AuthorizationCode;
+
+impl AuthorizationCode {
+    #[tracing::instrument(level = "debug")]
+    pub async fn insert(id: &str, duration: Duration, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("INSERT INTO authorization_codes (id, expires_at) VALUES ($1, $2)")
+            .bind(id)
+            .bind(now() + duration)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Deletes a code, returning `false` if it was already redeemed or expired
+    #[tracing::instrument(level = "debug")]
+    pub async fn consume(id: &str, pool: &PgPool) -> sqlx::Result<bool> {
+        let deleted =
+            sqlx::query("DELETE FROM authorization_codes WHERE id = $1 AND expires_at > $2")
+                .bind(id)
+                .bind(now())
+                .execute(pool)
+                .await?
+                .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM authorization_codes WHERE expires_at <= $1")
+            .bind(now())
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+}
@@ -0,0 +1,266 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use sqlx::{Done, PgPool};
+
+/// OAuth client allowed to authenticate users through Vaulth
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Client {
+    pub id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+
+    /// Hashed secret, public clients don't have one
+    #[serde(rename = "type", serialize_with = "serialize_type")]
+    pub secret: Option<String>,
+    pub redirect_urls: Vec<String>,
+    pub scopes: Vec<String>,
+    pub trusted: bool,
+
+    pub disabled: bool,
+    /// Whether the client comes from the config file, in which case it can't be modified
+    pub seeded: bool,
+    /// Hashed token used by dynamically registered clients to manage their registration
+    #[serde(skip_serializing)]
+    pub registration_token: Option<String>,
+    pub post_logout_redirect_urls: Vec<String>,
+    /// URL notified when a session the client took part in ends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backchannel_logout_url: Option<String>,
+    /// Colors used on the hosted pages, as `#rrggbb` or `#rgb`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<String>,
+    /// Subdirectory of the templates directory overriding templates for this client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    /// Authentication levels users must reach to be sent back to the client, the lowest one being required
+    pub acr_values: Vec<String>,
+}
+
+/// Fields used to create or seed a client
+#[derive(Debug)]
+pub struct NewClient<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub logo_uri: Option<&'a str>,
+    pub secret: Option<&'a str>,
+    pub redirect_urls: &'a [String],
+    pub scopes: &'a [String],
+    pub trusted: bool,
+    pub registration_token: Option<&'a str>,
+    pub post_logout_redirect_urls: &'a [String],
+    pub backchannel_logout_url: Option<&'a str>,
+    pub primary_color: Option<&'a str>,
+    pub background_color: Option<&'a str>,
+    pub theme: Option<&'a str>,
+    pub acr_values: &'a [String],
+}
+
+/// Fields a dynamically registered client can update
+#[derive(Debug)]
+pub struct ClientMetadata<'a> {
+    pub name: &'a str,
+    pub logo_uri: Option<&'a str>,
+    pub redirect_urls: &'a [String],
+    pub scopes: &'a [String],
+    pub post_logout_redirect_urls: &'a [String],
+    pub backchannel_logout_url: Option<&'a str>,
+}
+
+fn serialize_type<S>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(match secret {
+        Some(_) => "confidential",
+        None => "public",
+    })
+}
+
+impl Client {
+    #[tracing::instrument(level = "debug")]
+    pub async fn select(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM clients WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Selects a client only if it is allowed to authenticate users
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_enabled(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM clients WHERE id = $1 AND NOT disabled")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_all(pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM clients ORDER BY id")
+            .fetch_all(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn insert(client: NewClient<'_>, pool: &PgPool) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO clients (id, inserted_at, updated_at, name, logo_uri, secret, redirect_urls, scopes, trusted, registration_token, post_logout_redirect_urls, backchannel_logout_url, primary_color, background_color, theme, acr_values)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+RETURNING *
+            ",
+        )
+        .bind(client.id)
+        .bind(now)
+        .bind(now)
+        .bind(client.name)
+        .bind(client.logo_uri)
+        .bind(client.secret)
+        .bind(client.redirect_urls)
+        .bind(client.scopes)
+        .bind(client.trusted)
+        .bind(client.registration_token)
+        .bind(client.post_logout_redirect_urls)
+        .bind(client.backchannel_logout_url)
+        .bind(client.primary_color)
+        .bind(client.background_color)
+        .bind(client.theme)
+        .bind(client.acr_values)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Inserts or overwrites a client coming from the config file
+    #[tracing::instrument(level = "debug")]
+    pub async fn seed(client: NewClient<'_>, pool: &PgPool) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO clients (id, inserted_at, updated_at, name, logo_uri, secret, redirect_urls, scopes, trusted, post_logout_redirect_urls, backchannel_logout_url, primary_color, background_color, theme, acr_values, seeded)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, TRUE)
+ON CONFLICT (id) DO UPDATE
+SET updated_at = EXCLUDED.updated_at,
+    name = EXCLUDED.name,
+    logo_uri = EXCLUDED.logo_uri,
+    secret = EXCLUDED.secret,
+    redirect_urls = EXCLUDED.redirect_urls,
+    scopes = EXCLUDED.scopes,
+    trusted = EXCLUDED.trusted,
+    post_logout_redirect_urls = EXCLUDED.post_logout_redirect_urls,
+    backchannel_logout_url = EXCLUDED.backchannel_logout_url,
+    primary_color = EXCLUDED.primary_color,
+    background_color = EXCLUDED.background_color,
+    theme = EXCLUDED.theme,
+    acr_values = EXCLUDED.acr_values,
+    disabled = FALSE,
+    seeded = TRUE
+RETURNING *
+            ",
+        )
+        .bind(client.id)
+        .bind(now)
+        .bind(now)
+        .bind(client.name)
+        .bind(client.logo_uri)
+        .bind(client.secret)
+        .bind(client.redirect_urls)
+        .bind(client.scopes)
+        .bind(client.trusted)
+        .bind(client.post_logout_redirect_urls)
+        .bind(client.backchannel_logout_url)
+        .bind(client.primary_color)
+        .bind(client.background_color)
+        .bind(client.theme)
+        .bind(client.acr_values)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Deletes the seeded clients that were removed from the config file
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_stale_seeds(ids: &[String], pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM clients WHERE seeded AND NOT (id = ANY($1))")
+            .bind(ids)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_secret(
+        id: &str,
+        secret: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "UPDATE clients SET secret = $2, updated_at = $3 WHERE id = $1 AND NOT seeded RETURNING *",
+        )
+        .bind(id)
+        .bind(secret)
+        .bind(now())
+        .fetch_optional(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_metadata(
+        id: &str,
+        metadata: ClientMetadata<'_>,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE clients
+SET updated_at = $2, name = $3, logo_uri = $4, redirect_urls = $5, scopes = $6,
+    post_logout_redirect_urls = $7, backchannel_logout_url = $8
+WHERE id = $1 AND NOT seeded
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(metadata.name)
+        .bind(metadata.logo_uri)
+        .bind(metadata.redirect_urls)
+        .bind(metadata.scopes)
+        .bind(metadata.post_logout_redirect_urls)
+        .bind(metadata.backchannel_logout_url)
+        .fetch_optional(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_disabled(
+        id: &str,
+        disabled: bool,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "UPDATE clients SET disabled = $2, updated_at = $3 WHERE id = $1 AND NOT seeded RETURNING *",
+        )
+        .bind(id)
+        .bind(disabled)
+        .bind(now())
+        .fetch_optional(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("DELETE FROM clients WHERE id = $1 AND NOT seeded RETURNING *")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+}
@@ -0,0 +1,87 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// Scopes a user approved for a client, remembered so they aren't asked again
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Consent {
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub client_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+
+    pub scopes: Vec<String>,
+}
+
+impl Consent {
+    #[tracing::instrument(level = "debug")]
+    pub async fn select(
+        user_id: &str,
+        client_id: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM consents WHERE user_id = $1 AND client_id = $2")
+            .bind(user_id)
+            .bind(client_id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM consents WHERE user_id = $1 ORDER BY client_id")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Remembers the given scopes, merging them with any previously approved ones
+    #[tracing::instrument(level = "debug")]
+    pub async fn upsert(
+        user_id: &str,
+        client_id: &str,
+        scopes: &[String],
+        pool: &PgPool,
+    ) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO consents (user_id, client_id, inserted_at, updated_at, scopes)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (user_id, client_id) DO UPDATE
+SET updated_at = EXCLUDED.updated_at,
+    scopes = ARRAY(SELECT DISTINCT unnest(consents.scopes || EXCLUDED.scopes))
+RETURNING *
+            ",
+        )
+        .bind(user_id)
+        .bind(client_id)
+        .bind(now)
+        .bind(now)
+        .bind(scopes)
+        .fetch_one(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete(
+        user_id: &str,
+        client_id: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("DELETE FROM consents WHERE user_id = $1 AND client_id = $2 RETURNING *")
+            .bind(user_id)
+            .bind(client_id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Whether every requested scope has already been approved
+    pub fn covers(&self, scopes: &[String]) -> bool {
+        scopes.iter().all(|s| self.scopes.contains(s))
+    }
+}
@@ -0,0 +1,93 @@
+use super::now;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::{Done, PgPool};
+
+pub const PENDING: &str = "pending";
+pub const READY: &str = "ready";
+pub const FAILED: &str = "failed";
+
+/// Archive of the data of a user, generated in the background
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Export {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+
+    pub status: String,
+    #[serde(skip_serializing)]
+    pub data: Option<String>,
+}
+
+impl Export {
+    /// Whether the archive is still being generated
+    pub fn pending(&self) -> bool {
+        self.status == PENDING
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn insert(
+        id: &str,
+        user_id: &str,
+        duration: Duration,
+        pool: &PgPool,
+    ) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO exports (id, user_id, inserted_at, updated_at, expires_at, status)
+VALUES ($1, $2, $3, $3, $4, $5)
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(now)
+        .bind(now + duration)
+        .bind(PENDING)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Selects an export of a user that hasn't expired yet
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_for_user(
+        id: &str,
+        user_id: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM exports WHERE id = $1 AND user_id = $2 AND expires_at > $3")
+            .bind(id)
+            .bind(user_id)
+            .bind(now())
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Stores the generated data, or marks the export as failed if `None`
+    #[tracing::instrument(level = "debug", skip(data))]
+    pub async fn complete(id: &str, data: Option<&str>, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("UPDATE exports SET updated_at = $2, status = $3, data = $4 WHERE id = $1")
+            .bind(id)
+            .bind(now())
+            .bind(if data.is_some() { READY } else { FAILED })
+            .bind(data)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM exports WHERE expires_at <= $1")
+            .bind(now())
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+}
@@ -0,0 +1,112 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Done, Executor, PgPool, Postgres, Transaction};
+
+/// Failed attempts at guessing credentials, counted per account, IP address or client
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct FailedAttempts {
+    pub key: String,
+
+    pub failures: i32,
+    pub last_failure: DateTime<Utc>,
+    /// Whether attempts are refused until an admin unlocks the key
+    pub locked: bool,
+}
+
+impl FailedAttempts {
+    /// Selects the attempts of keys, creating those without any, and locks them until the end of the transaction
+    /// so concurrent attempts with the same keys are counted one after the other
+    #[tracing::instrument(level = "debug", skip(tx))]
+    pub async fn select_for_update(
+        keys: &[String],
+        now: DateTime<Utc>,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query(
+            "
+INSERT INTO failed_attempts (key, failures, last_failure)
+SELECT key, 0, $2 FROM UNNEST($1::varchar[]) AS key
+ON CONFLICT (key) DO NOTHING
+            ",
+        )
+        .bind(keys)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query_as("SELECT * FROM failed_attempts WHERE key = ANY($1) ORDER BY key FOR UPDATE")
+            .bind(keys)
+            .fetch_all(&mut *tx)
+            .await
+    }
+
+    /// Records a failed attempt, starting the count over if the previous one was before `forget_before`
+    #[tracing::instrument(level = "debug", skip(executor))]
+    pub async fn record<'e, E>(
+        key: &str,
+        now: DateTime<Utc>,
+        forget_before: DateTime<Utc>,
+        executor: E,
+    ) -> sqlx::Result<Self>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        sqlx::query_as(
+            "
+INSERT INTO failed_attempts (key, failures, last_failure)
+VALUES ($1, 1, $2)
+ON CONFLICT (key) DO UPDATE
+SET failures = CASE
+        WHEN failed_attempts.last_failure < $3 AND NOT failed_attempts.locked THEN 1
+        ELSE failed_attempts.failures + 1
+    END,
+    last_failure = $2
+RETURNING *
+            ",
+        )
+        .bind(key)
+        .bind(now)
+        .bind(forget_before)
+        .fetch_one(executor)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn lock(key: &str, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("UPDATE failed_attempts SET locked = TRUE WHERE key = $1")
+            .bind(key)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Forgets the failed attempts of keys, locked or not
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete(keys: &[String], pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM failed_attempts WHERE key = ANY($1)")
+            .bind(keys)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Uncounts an attempt of each key, rows left without any being forgotten along with stale ones
+    #[tracing::instrument(level = "debug")]
+    pub async fn take_back(keys: &[String], pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query(
+            "UPDATE failed_attempts SET failures = failures - 1 WHERE key = ANY($1) AND failures > 0",
+        )
+        .bind(keys)
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+
+    /// Forgets the failed attempts of unlocked keys that didn't fail since `before`
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_stale(before: DateTime<Utc>, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM failed_attempts WHERE last_failure < $1 AND NOT locked")
+            .bind(before)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+}
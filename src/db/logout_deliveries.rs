@@ -0,0 +1,146 @@
+use super::now;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::{Done, Executor, PgPool, Postgres};
+
+pub const PENDING: &str = "pending";
+pub const DELIVERED: &str = "delivered";
+pub const FAILED: &str = "failed";
+
+/// Sent when a session ends
+pub const LOGOUT: &str = "logout";
+/// Sent when an account is deleted, which also logs the user out of every session
+pub const DELETION: &str = "deletion";
+
+/// Back-channel notification sent to a client
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LogoutDelivery {
+    pub id: i64,
+    pub client_id: String,
+    pub user_id: String,
+    pub session_id: Option<String>,
+
+    pub inserted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+
+    pub event: String,
+}
+
+impl LogoutDelivery {
+    /// Queues notifications for the given clients, skipping the ones without a back-channel logout URL
+    #[tracing::instrument(level = "debug", skip(executor))]
+    pub async fn queue<'e, E>(
+        client_ids: &[String],
+        user_id: &str,
+        session_id: Option<&str>,
+        event: &str,
+        executor: E,
+    ) -> sqlx::Result<u64>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        sqlx::query(
+            "
+INSERT INTO logout_deliveries (client_id, user_id, session_id, inserted_at, updated_at, status, attempts, next_attempt_at, event)
+SELECT id, $2, $3, $4, $4, $5, 0, $4, $6
+FROM clients
+WHERE id = ANY($1) AND backchannel_logout_url IS NOT NULL
+            ",
+        )
+        .bind(client_ids)
+        .bind(user_id)
+        .bind(session_id)
+        .bind(now())
+        .bind(PENDING)
+        .bind(event)
+        .execute(executor)
+        .await
+        .map(|done| done.rows_affected())
+    }
+
+    /// Selects the clients that were ever notified about a user
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_clients_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar("SELECT DISTINCT client_id FROM logout_deliveries WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Claims pending notifications that are due,
+    /// pushing their next attempt back so concurrent workers don't pick them up too
+    #[tracing::instrument(level = "debug")]
+    pub async fn claim_due(limit: i64, lease: Duration, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+UPDATE logout_deliveries
+SET next_attempt_at = $3
+WHERE id IN (
+    SELECT id FROM logout_deliveries
+    WHERE status = $4 AND next_attempt_at <= $2
+    ORDER BY next_attempt_at
+    LIMIT $1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING *
+            ",
+        )
+        .bind(limit)
+        .bind(now)
+        .bind(now + lease)
+        .bind(PENDING)
+        .fetch_all(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn mark_delivered(id: i64, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query(
+            "UPDATE logout_deliveries SET status = $2, attempts = attempts + 1, updated_at = $3, last_error = NULL WHERE id = $1",
+        )
+        .bind(id)
+        .bind(DELIVERED)
+        .bind(now())
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+
+    /// Records a failed attempt, either retrying later or giving up if `next_attempt_at` is `None`
+    #[tracing::instrument(level = "debug")]
+    pub async fn mark_attempt_failed(
+        id: i64,
+        error: &str,
+        next_attempt_at: Option<DateTime<Utc>>,
+        pool: &PgPool,
+    ) -> sqlx::Result<u64> {
+        let now = now();
+
+        sqlx::query(
+            "
+UPDATE logout_deliveries
+SET status = $2, attempts = attempts + 1, updated_at = $3, next_attempt_at = $4, last_error = $5
+WHERE id = $1
+            ",
+        )
+        .bind(id)
+        .bind(if next_attempt_at.is_some() {
+            PENDING
+        } else {
+            FAILED
+        })
+        .bind(now)
+        .bind(next_attempt_at.unwrap_or(now))
+        .bind(error)
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+}
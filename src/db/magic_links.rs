@@ -0,0 +1,87 @@
+use super::now;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Done, PgPool};
+
+/// Single-use login link mailed to a user
+#[derive(Debug, sqlx::FromRow)]
+pub struct MagicLink {
+    pub id: String,
+    pub user_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+
+    /// Hash of the secret part of the link
+    pub secret: String,
+    /// Authorization request the user started, as JSON
+    pub params: String,
+}
+
+impl MagicLink {
+    #[tracing::instrument(level = "debug", skip(secret))]
+    pub async fn insert(
+        id: &str,
+        user_id: &str,
+        secret: &str,
+        params: &str,
+        duration: Duration,
+        pool: &PgPool,
+    ) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO magic_links (id, user_id, inserted_at, expires_at, secret, params)
+VALUES ($1, $2, $3, $4, $5, $6)
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(now)
+        .bind(now + duration)
+        .bind(secret)
+        .bind(params)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Selects a link that wasn't used and hasn't expired yet
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_valid(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM magic_links WHERE id = $1 AND used_at IS NULL AND expires_at > $2",
+        )
+        .bind(id)
+        .bind(now())
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Marks a link as used, returning `None` if it was already used or expired in the meantime
+    #[tracing::instrument(level = "debug")]
+    pub async fn consume(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE magic_links
+SET used_at = $2
+WHERE id = $1 AND used_at IS NULL AND expires_at > $2
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .fetch_optional(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM magic_links WHERE expires_at <= $1")
+            .bind(now())
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+}
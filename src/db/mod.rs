@@ -0,0 +1,47 @@
+mod authorization_codes;
+mod clients;
+mod consents;
+mod exports;
+mod failed_attempts;
+mod logout_deliveries;
+mod magic_links;
+mod password_resets;
+mod recovery_codes;
+mod sessions;
+mod username_history;
+mod users;
+mod webauthn_challenges;
+mod webauthn_credentials;
+
+pub mod audit_events;
+
+pub use audit_events::AuditEvent;
+pub use authorization_codes::AuthorizationCode;
+pub use clients::{Client, ClientMetadata, NewClient};
+pub use consents::Consent;
+pub use exports::Export;
+pub use failed_attempts::FailedAttempts;
+pub use logout_deliveries::{LogoutDelivery, DELETION, LOGOUT};
+pub use magic_links::MagicLink;
+pub use password_resets::PasswordReset;
+pub use recovery_codes::RecoveryCode;
+pub use sessions::{NewSession, Session};
+pub use username_history::UsernameRelease;
+pub use users::{ImportedUser, User};
+pub use webauthn_challenges::WebAuthnChallenge;
+pub use webauthn_credentials::WebAuthnCredential;
+
+use chrono::{DateTime, Utc};
+
+#[inline]
+fn now() -> DateTime<Utc> {
+    Utc::now()
+}
+
+/// Whether a query failed because a row with the same unique values already exists
+pub fn unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("23505"),
+        _ => false,
+    }
+}
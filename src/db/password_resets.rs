@@ -0,0 +1,87 @@
+use super::now;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Done, PgPool};
+
+/// Single-use link mailed to a user who forgot their password
+#[derive(Debug, sqlx::FromRow)]
+pub struct PasswordReset {
+    pub id: String,
+    pub user_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+
+    /// Hash of the secret part of the link
+    pub secret: String,
+}
+
+impl PasswordReset {
+    #[tracing::instrument(level = "debug", skip(secret))]
+    pub async fn insert(
+        id: &str,
+        user_id: &str,
+        secret: &str,
+        duration: Duration,
+        pool: &PgPool,
+    ) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO password_resets (id, user_id, inserted_at, expires_at, secret)
+VALUES ($1, $2, $3, $4, $5)
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(now)
+        .bind(now + duration)
+        .bind(secret)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Selects a reset that wasn't used and hasn't expired yet
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_valid(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM password_resets WHERE id = $1 AND used_at IS NULL AND expires_at > $2",
+        )
+        .bind(id)
+        .bind(now())
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Marks a reset as used, returning `None` if it was already used or expired in the meantime
+    #[tracing::instrument(level = "debug")]
+    pub async fn consume(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE password_resets
+SET used_at = $2
+WHERE id = $1 AND used_at IS NULL AND expires_at > $2
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Invalidates the other resets a user requested, along with expired ones
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_unused(user_id: &str, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query(
+            "DELETE FROM password_resets WHERE (user_id = $1 AND used_at IS NULL) OR expires_at <= $2",
+        )
+        .bind(user_id)
+        .bind(now())
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+}
@@ -0,0 +1,66 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use sqlx::{Done, PgPool};
+
+/// One-time code letting a user log in without their authenticator app
+#[derive(Debug, sqlx::FromRow)]
+pub struct RecoveryCode {
+    pub id: i64,
+    pub user_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+
+    /// Hash of the code
+    pub code: String,
+}
+
+impl RecoveryCode {
+    /// Replaces the codes of a user with new ones
+    #[tracing::instrument(level = "debug", skip(codes))]
+    pub async fn replace(user_id: &str, codes: &[String], pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query(
+            "
+WITH deleted AS (DELETE FROM recovery_codes WHERE user_id = $1)
+INSERT INTO recovery_codes (user_id, inserted_at, code)
+SELECT $1, $2, unnest($3::varchar[])
+            ",
+        )
+        .bind(user_id)
+        .bind(now())
+        .bind(codes)
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_unused(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Marks a code as used, returning `false` if it was already used in the meantime
+    #[tracing::instrument(level = "debug")]
+    pub async fn consume(id: i64, pool: &PgPool) -> sqlx::Result<bool> {
+        let updated =
+            sqlx::query("UPDATE recovery_codes SET used_at = $2 WHERE id = $1 AND used_at IS NULL")
+                .bind(id)
+                .bind(now())
+                .execute(pool)
+                .await?
+                .rows_affected();
+        Ok(updated > 0)
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+}
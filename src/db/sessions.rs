@@ -0,0 +1,174 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Done, PgPool};
+
+/// Browser session keeping a user logged in to Vaulth across clients
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Session {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Last time the user actively authenticated
+    pub auth_time: DateTime<Utc>,
+
+    pub provider_name: String,
+    #[serde(skip_serializing)]
+    pub provider_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Clients the user was sent to during this session
+    pub clients: Vec<String>,
+    /// Methods the user authenticated with
+    pub amr: Vec<String>,
+}
+
+/// Fields used to create a session
+#[derive(Debug)]
+pub struct NewSession<'a> {
+    pub id: &'a str,
+    pub user_id: &'a str,
+    pub expires_at: DateTime<Utc>,
+    pub provider_name: &'a str,
+    pub provider_id: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub amr: &'a [String],
+}
+
+impl Session {
+    /// Selects a session only if it hasn't expired yet
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_active(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM sessions WHERE id = $1 AND expires_at > $2")
+            .bind(id)
+            .bind(now())
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn insert(session: NewSession<'_>, pool: &PgPool) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO sessions (id, user_id, inserted_at, updated_at, expires_at, auth_time, provider_name, provider_id, user_agent, amr)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+RETURNING *
+            ",
+        )
+        .bind(session.id)
+        .bind(session.user_id)
+        .bind(now)
+        .bind(now)
+        .bind(session.expires_at)
+        .bind(now)
+        .bind(session.provider_name)
+        .bind(session.provider_id)
+        .bind(session.user_agent)
+        .bind(session.amr)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Marks the session as recently used
+    #[tracing::instrument(level = "debug")]
+    pub async fn touch(id: &str, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("UPDATE sessions SET updated_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(now())
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Records the methods the user of a session authenticated with after they stepped up,
+    /// unless the session ended in the meantime
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_amr(
+        id: &str,
+        user_id: &str,
+        amr: &[String],
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        let now = now();
+
+        sqlx::query_as(
+            "UPDATE sessions SET amr = $3, updated_at = $4 WHERE id = $1 AND user_id = $2 AND expires_at > $4 RETURNING *",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(amr)
+        .bind(now)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Remembers that the user was sent to a client during this session
+    #[tracing::instrument(level = "debug")]
+    pub async fn add_client(id: &str, client_id: &str, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query(
+            "UPDATE sessions SET clients = array_append(clients, $2) WHERE id = $1 AND NOT ($2 = ANY(clients))",
+        )
+        .bind(id)
+        .bind(client_id)
+        .execute(pool)
+        .await
+        .map(|done| done.rows_affected())
+    }
+
+    /// Selects the sessions of a user that haven't expired yet
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_active_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM sessions WHERE user_id = $1 AND expires_at > $2 ORDER BY updated_at DESC",
+        )
+        .bind(user_id)
+        .bind(now())
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Selects every session of a user, including expired ones
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM sessions WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("DELETE FROM sessions WHERE id = $1 RETURNING *")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Deletes a session only if it belongs to the given user
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_for_user(
+        id: &str,
+        user_id: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("DELETE FROM sessions WHERE id = $1 AND user_id = $2 RETURNING *")
+            .bind(id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("DELETE FROM sessions WHERE user_id = $1 RETURNING *")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+}
@@ -0,0 +1,86 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use sqlx::{Done, Executor, PgPool, Postgres};
+
+/// Username a user stopped using, by changing it or deleting their account
+#[derive(Debug, sqlx::FromRow)]
+pub struct UsernameRelease {
+    pub skeleton: String,
+    pub username: String,
+    pub user_id: String,
+    pub released_at: DateTime<Utc>,
+}
+
+impl UsernameRelease {
+    #[tracing::instrument(level = "debug", skip(executor))]
+    pub async fn insert<'e, E>(
+        skeleton: &str,
+        username: &str,
+        user_id: &str,
+        executor: E,
+    ) -> sqlx::Result<Self>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        sqlx::query_as(
+            "
+INSERT INTO username_history (skeleton, username, user_id, released_at)
+VALUES ($1, $2, $3, $4)
+RETURNING *
+            ",
+        )
+        .bind(skeleton)
+        .bind(username)
+        .bind(user_id)
+        .bind(now())
+        .fetch_one(executor)
+        .await
+    }
+
+    /// Selects every released username along with its skeleton
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_usernames(pool: &PgPool) -> sqlx::Result<Vec<(String, String)>> {
+        sqlx::query_as("SELECT DISTINCT username, skeleton FROM username_history")
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Changes the skeleton of a released username, after the way it is computed changed
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_skeleton(
+        username: &str,
+        skeleton: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<u64> {
+        sqlx::query("UPDATE username_history SET skeleton = $2 WHERE username = $1")
+            .bind(username)
+            .bind(skeleton)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Whether another user released a username since the given time,
+    /// users can always take back their own
+    #[tracing::instrument(level = "debug")]
+    pub async fn released_by_other(
+        skeleton: &str,
+        user_id: Option<&str>,
+        since: DateTime<Utc>,
+        pool: &PgPool,
+    ) -> sqlx::Result<bool> {
+        sqlx::query_scalar(
+            "
+SELECT EXISTS (
+    SELECT 1 FROM username_history
+    WHERE skeleton = $1 AND released_at > $3 AND user_id IS DISTINCT FROM $2
+)
+            ",
+        )
+        .bind(skeleton)
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(pool)
+        .await
+    }
+}
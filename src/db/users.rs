@@ -0,0 +1,489 @@
+use super::now;
+use crate::providers;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Done, Executor, PgPool, Postgres};
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct User {
+    pub id: String,
+
+    pub inserted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+
+    /// Unique name chosen by the user, compared regardless of case and lookalike characters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub username_skeleton: Option<String>,
+
+    /// Private, only shown to the user themselves
+    #[serde(skip_serializing)]
+    pub email: Option<String>,
+    #[serde(skip_serializing)]
+    pub email_verified: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub about: Option<String>,
+
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// Encrypted secret of the authenticator app of the user, only in use once confirmed
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    #[serde(skip_serializing)]
+    pub totp_confirmed_at: Option<DateTime<Utc>>,
+    /// Last time step a code was accepted for, so codes can't be replayed
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub google_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub microsoft_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facebook_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub twitter_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discord_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// When the account will be deleted, if its deletion was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delete_at: Option<DateTime<Utc>>,
+}
+
+/// User imported from another system, along with their password hash and identities
+#[derive(Debug)]
+pub struct ImportedUser<'a> {
+    pub id: &'a str,
+    pub username: &'a str,
+    pub username_skeleton: &'a str,
+    pub email: Option<&'a str>,
+    pub email_verified: bool,
+    pub name: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub google_id: Option<&'a str>,
+    pub github_id: Option<&'a str>,
+    pub discord_id: Option<&'a str>,
+}
+
+impl User {
+    /// Whether the user is allowed to log in
+    pub fn active(&self) -> bool {
+        self.disabled_at.is_none() && self.delete_at.is_none()
+    }
+
+    /// Whether the user has to enter a code from their authenticator app after logging in
+    pub fn totp_enabled(&self) -> bool {
+        self.totp_secret.is_some() && self.totp_confirmed_at.is_some()
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM vaulth WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug", skip(executor))]
+    pub async fn delete<'e, E>(id: &str, executor: E) -> sqlx::Result<Option<Self>>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        sqlx::query_as("DELETE FROM vaulth WHERE id = $1 RETURNING *")
+            .bind(id)
+            .fetch_optional(executor)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_provider(
+        name: &str,
+        id: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<String>> {
+        // Local users are identified by their own ID
+        let column = if [providers::LOCAL, providers::EMAIL, providers::WEBAUTHN].contains(&name) {
+            "id".to_owned()
+        } else {
+            format!("{}_id", name)
+        };
+        sqlx::query_scalar(&format!("SELECT id FROM vaulth WHERE {} = $1", column))
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_username(username: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM vaulth WHERE lower(username) = lower($1)")
+            .bind(username)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Selects the user who verified an email address
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_email(email: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM vaulth WHERE lower(email) = lower($1) AND email_verified")
+            .bind(email)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Selects the ID of the user holding a username, or one that looks like it
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_skeleton(skeleton: &str, pool: &PgPool) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar("SELECT id FROM vaulth WHERE username_skeleton = $1")
+            .bind(skeleton)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Registers a new user, either with a password or with the identity of a provider
+    #[tracing::instrument(level = "debug", skip(password))]
+    pub async fn insert(
+        id: &str,
+        username: &str,
+        skeleton: &str,
+        password: Option<&str>,
+        provider: Option<(&str, &str)>,
+        pool: &PgPool,
+    ) -> sqlx::Result<Self> {
+        let now = now();
+
+        // Provider names are never user input
+        let (column, provider_id) = match provider {
+            Some((name, id)) => (format!(", {}_id", name), Some(id)),
+            None => (String::new(), None),
+        };
+        let value = if provider_id.is_some() { ", $7" } else { "" };
+        let sql = format!(
+            "
+INSERT INTO vaulth (id, inserted_at, updated_at, username, username_skeleton, password{})
+VALUES ($1, $2, $3, $4, $5, $6{})
+RETURNING *
+            ",
+            column, value
+        );
+
+        let mut query = sqlx::query_as(&sql)
+            .bind(id)
+            .bind(now)
+            .bind(now)
+            .bind(username)
+            .bind(skeleton)
+            .bind(password);
+        if let Some(provider_id) = provider_id {
+            query = query.bind(provider_id);
+        }
+        query.fetch_one(pool).await
+    }
+
+    /// Registers a user imported from another system
+    #[tracing::instrument(level = "debug", skip(user))]
+    pub async fn import(user: ImportedUser<'_>, pool: &PgPool) -> sqlx::Result<Self> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+INSERT INTO vaulth (id, inserted_at, updated_at, username, username_skeleton, email, email_verified, name, password, google_id, github_id, discord_id)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+RETURNING *
+            ",
+        )
+        .bind(user.id)
+        .bind(now)
+        .bind(now)
+        .bind(user.username)
+        .bind(user.username_skeleton)
+        .bind(user.email)
+        .bind(user.email_verified)
+        .bind(user.name)
+        .bind(user.password)
+        .bind(user.google_id)
+        .bind(user.github_id)
+        .bind(user.discord_id)
+        .fetch_one(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn disabled(id: &str, pool: &PgPool) -> sqlx::Result<bool> {
+        let disabled: Option<bool> = sqlx::query_scalar(
+            "SELECT disabled_at IS NOT NULL OR delete_at IS NOT NULL FROM vaulth WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(disabled.unwrap_or_default())
+    }
+
+    /// Updates the profile of a user, `None` fields being left untouched
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_profile(
+        id: &str,
+        name: Option<Option<&str>>,
+        about: Option<Option<&str>>,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2,
+    name = CASE WHEN $3 THEN $4 ELSE name END,
+    about = CASE WHEN $5 THEN $6 ELSE about END
+WHERE id = $1
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(name.is_some())
+        .bind(name.flatten())
+        .bind(about.is_some())
+        .bind(about.flatten())
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Changes the username of a user, recording the previous one unless it only changed case
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_username(
+        id: &str,
+        username: &str,
+        skeleton: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+WITH released AS (
+    INSERT INTO username_history (skeleton, username, user_id, released_at)
+    SELECT username_skeleton, username, id, $2 FROM vaulth
+    WHERE id = $1 AND username IS NOT NULL AND username_skeleton IS DISTINCT FROM $4
+)
+UPDATE vaulth
+SET updated_at = $2, username = $3, username_skeleton = $4
+WHERE id = $1
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(username)
+        .bind(skeleton)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Changes or removes the email address of a user
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_email(
+        id: &str,
+        email: Option<&str>,
+        verified: bool,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2, email = $3, email_verified = $4
+WHERE id = $1
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(email)
+        .bind(verified)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Marks the email address of a user as verified, unless it changed in the meantime
+    #[tracing::instrument(level = "debug")]
+    pub async fn verify_email(id: &str, email: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2, email_verified = true
+WHERE id = $1 AND lower(email) = lower($3)
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(email)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Replaces the password hash of a user
+    #[tracing::instrument(level = "debug", skip(password))]
+    pub async fn update_password(
+        id: &str,
+        password: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2, password = $3
+WHERE id = $1
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(password)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Starts setting up an authenticator app, or removes it if `secret` is `None`
+    #[tracing::instrument(level = "debug", skip(secret))]
+    pub async fn update_totp(
+        id: &str,
+        secret: Option<&str>,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2, totp_secret = $3, totp_confirmed_at = NULL, totp_last_step = NULL
+WHERE id = $1
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(secret)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Starts requiring codes once the user proved their app works, with the code they entered
+    #[tracing::instrument(level = "debug")]
+    pub async fn confirm_totp(id: &str, step: i64, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2, totp_confirmed_at = $2, totp_last_step = $3
+WHERE id = $1 AND totp_secret IS NOT NULL AND totp_confirmed_at IS NULL
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(step)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Records the time step a code was accepted for,
+    /// returning `false` if a code for the same or a later step was already accepted
+    #[tracing::instrument(level = "debug")]
+    pub async fn use_totp_step(id: &str, step: i64, pool: &PgPool) -> sqlx::Result<bool> {
+        let updated = sqlx::query(
+            "
+UPDATE vaulth
+SET totp_last_step = $2
+WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+            ",
+        )
+        .bind(id)
+        .bind(step)
+        .execute(pool)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+
+    /// Schedules the deletion of a user, or cancels it if `delete_at` is `None`
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_delete_at(
+        id: &str,
+        delete_at: Option<DateTime<Utc>>,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "UPDATE vaulth SET updated_at = $2, delete_at = $3 WHERE id = $1 RETURNING *",
+        )
+        .bind(id)
+        .bind(now())
+        .bind(delete_at)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Selects the users registered before usernames existed
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_without_username(pool: &PgPool) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar("SELECT id FROM vaulth WHERE username IS NULL ORDER BY inserted_at")
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Selects the ID, username and skeleton of every user with a username
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_usernames(
+        pool: &PgPool,
+    ) -> sqlx::Result<Vec<(String, String, Option<String>)>> {
+        sqlx::query_as(
+            "SELECT id, username, username_skeleton FROM vaulth WHERE username IS NOT NULL",
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Changes the skeleton of a username, after the way it is computed changed
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_skeleton(id: &str, skeleton: &str, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("UPDATE vaulth SET username_skeleton = $2 WHERE id = $1")
+            .bind(id)
+            .bind(skeleton)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Selects the users whose scheduled deletion is due
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_due_deletions(pool: &PgPool) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar("SELECT id FROM vaulth WHERE delete_at <= $1")
+            .bind(now())
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Disables or re-enables a user, disabled users can't log in
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_disabled(
+        id: &str,
+        disabled: bool,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        let now = now();
+
+        sqlx::query_as(
+            "
+UPDATE vaulth
+SET updated_at = $2, disabled_at = CASE WHEN $3 THEN COALESCE(disabled_at, $2) END
+WHERE id = $1
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(now)
+        .bind(disabled)
+        .fetch_optional(pool)
+        .await
+    }
+}
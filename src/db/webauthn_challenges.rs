@@ -0,0 +1,41 @@
+use super::now;
+use chrono::Duration;
+use sqlx::{Done, PgPool};
+
+/// Challenge issued for a WebAuthn ceremony, which can only be answered once
+#[derive(Debug)]
+pub struct WebAuthnChallenge;
+
+impl WebAuthnChallenge {
+    #[tracing::instrument(level = "debug")]
+    pub async fn insert(challenge: &str, duration: Duration, pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("INSERT INTO webauthn_challenges (challenge, expires_at) VALUES ($1, $2)")
+            .bind(challenge)
+            .bind(now() + duration)
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Deletes a challenge, returning `false` if it was already answered or expired
+    #[tracing::instrument(level = "debug")]
+    pub async fn consume(challenge: &str, pool: &PgPool) -> sqlx::Result<bool> {
+        let deleted =
+            sqlx::query("DELETE FROM webauthn_challenges WHERE challenge = $1 AND expires_at > $2")
+                .bind(challenge)
+                .bind(now())
+                .execute(pool)
+                .await?
+                .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_expired(pool: &PgPool) -> sqlx::Result<u64> {
+        sqlx::query("DELETE FROM webauthn_challenges WHERE expires_at <= $1")
+            .bind(now())
+            .execute(pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+}
@@ -0,0 +1,105 @@
+use super::now;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Done, PgPool};
+
+/// Passkey or security key a user can log in with
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct WebAuthnCredential {
+    /// ID picked by the authenticator, encoded in base64url
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    /// Name given by the user to tell their passkeys apart
+    pub name: String,
+
+    pub inserted_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+
+    /// Public key in the COSE format
+    #[serde(skip_serializing)]
+    pub public_key: Vec<u8>,
+    /// Signature counter last reported by the authenticator, which stays at 0 for those without one
+    #[serde(skip_serializing)]
+    pub sign_count: i64,
+}
+
+impl WebAuthnCredential {
+    #[tracing::instrument(level = "debug", skip(public_key))]
+    pub async fn insert(
+        id: &str,
+        user_id: &str,
+        name: &str,
+        public_key: &[u8],
+        sign_count: i64,
+        pool: &PgPool,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "
+INSERT INTO webauthn_credentials (id, user_id, name, inserted_at, public_key, sign_count)
+VALUES ($1, $2, $3, $4, $5, $6)
+RETURNING *
+            ",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(name)
+        .bind(now())
+        .bind(public_key)
+        .bind(sign_count)
+        .fetch_one(pool)
+        .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select(id: &str, pool: &PgPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM webauthn_credentials WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    #[tracing::instrument(level = "debug")]
+    pub async fn select_by_user(user_id: &str, pool: &PgPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM webauthn_credentials WHERE user_id = $1 ORDER BY inserted_at")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Records a use of the credential, returning `false` if the counter didn't increase,
+    /// which means the authenticator was cloned or the assertion replayed
+    #[tracing::instrument(level = "debug")]
+    pub async fn update_sign_count(id: &str, sign_count: i64, pool: &PgPool) -> sqlx::Result<bool> {
+        let updated = sqlx::query(
+            "
+UPDATE webauthn_credentials SET sign_count = $2, last_used_at = $3
+WHERE id = $1 AND (sign_count < $2 OR (sign_count = 0 AND $2 = 0))
+            ",
+        )
+        .bind(id)
+        .bind(sign_count)
+        .bind(now())
+        .execute(pool)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+
+    /// Deletes a credential only if it belongs to the given user
+    #[tracing::instrument(level = "debug")]
+    pub async fn delete_for_user(
+        id: &str,
+        user_id: &str,
+        pool: &PgPool,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2 RETURNING *",
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+    }
+}
@@ -0,0 +1,109 @@
+//! Email addresses of users and their verification
+
+use crate::{
+    config::{Config, MailConfig},
+    db::User,
+    jwt,
+    mail::{self, Message},
+    templates::Locale,
+};
+use anyhow::Result;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use url::form_urlencoded;
+
+/// Scope clients need to receive the email address of users
+pub const SCOPE: &str = "email";
+
+/// Maximum length of email addresses, matching the database column
+const MAX_LEN: usize = 256;
+
+/// Proof that a user received mail at an address
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerificationJwt {
+    pub sub: String,
+    pub verify_email: String,
+}
+
+impl jwt::Purpose for VerificationJwt {
+    const PURPOSE: &'static str = "email_verification";
+}
+
+/// Checks whether an email address looks deliverable, actual validation being done by sending mail
+pub fn valid(email: &str) -> bool {
+    let (local, domain) = match email.rfind('@') {
+        Some(at) => (&email[..at], &email[at + 1..]),
+        None => return false,
+    };
+    email.len() <= MAX_LEN
+        && !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && email
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && !matches!(c, '<' | '>' | ','))
+}
+
+/// Whether two addresses are the same, ignoring case like the database does
+pub fn same(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// Gives a user an address verified by a provider, unless they already have another one
+/// or it belongs to someone else
+#[tracing::instrument(level = "debug")]
+pub async fn import(user_id: &str, email: &str, pool: &PgPool) -> Result<()> {
+    if !valid(email) {
+        return Ok(());
+    }
+    let user = match User::select(user_id, pool).await? {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    let replaceable = match &user.email {
+        Some(current) => !user.email_verified && same(current, email),
+        None => true,
+    };
+    if replaceable && User::select_by_email(email, pool).await?.is_none() {
+        User::update_email(user_id, Some(email), true, pool).await?;
+    }
+    Ok(())
+}
+
+/// Mails a user a link proving they own their address
+#[tracing::instrument(level = "debug", skip(mail_config, config))]
+pub async fn send_verification(
+    user_id: &str,
+    email: &str,
+    locale: &Locale,
+    mail_config: &MailConfig,
+    config: &Config,
+) -> Result<()> {
+    let token = jwt::encode_for(
+        VerificationJwt {
+            sub: user_id.to_owned(),
+            verify_email: email.to_owned(),
+        },
+        Duration::minutes(mail_config.verification_duration),
+        &config.token,
+    )
+    .await?;
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", &token)
+        .append_pair("ui_locales", &locale.language)
+        .finish();
+    let link = format!("{}/verify-email?{}", config.root_uri, query);
+
+    mail::send(
+        &Message {
+            to: email,
+            subject: &locale.text("mail.verify.subject", &[]),
+            body: &locale.text("mail.verify.body", &[("link", &link)]),
+        },
+        mail_config,
+    )
+    .await
+}
@@ -1,8 +1,13 @@
-use crate::providers::Params;
+use crate::{
+    config::Config,
+    providers::Params,
+    redirect,
+    templates::{self, Locale},
+};
 use serde::Serialize;
 use std::fmt::{Debug, Display};
 use warp::{
-    http::{StatusCode, Uri},
+    http::StatusCode,
     reject::{Reject, Rejection},
     Reply,
 };
@@ -11,8 +16,40 @@ use warp::{
 struct InternalServerError;
 impl Reject for InternalServerError {}
 
+/// Standard OAuth2 error codes sent back to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OAuthError {
+    InvalidRequest,
+    AccessDenied,
+    InvalidScope,
+    ServerError,
+    LoginRequired,
+    ConsentRequired,
+    InteractionRequired,
+    UnmetAuthenticationRequirements,
+}
+
+impl OAuthError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::InvalidRequest => "invalid_request",
+            Self::AccessDenied => "access_denied",
+            Self::InvalidScope => "invalid_scope",
+            Self::ServerError => "server_error",
+            Self::LoginRequired => "login_required",
+            Self::ConsentRequired => "consent_required",
+            Self::InteractionRequired => "interaction_required",
+            Self::UnmetAuthenticationRequirements => "unmet_authentication_requirements",
+        }
+    }
+}
+
 #[derive(Debug)]
-struct Redirect(Uri);
+struct Redirect {
+    params: Params,
+    error: OAuthError,
+    description: String,
+}
 impl Reject for Redirect {}
 
 #[derive(Debug, Serialize)]
@@ -24,11 +61,31 @@ pub struct JsonError {
 struct Json(JsonError, StatusCode);
 impl Reject for Json {}
 
+/// Error shown to the user as a page, when they can't be sent back to the client
+#[derive(Debug)]
+struct Page {
+    error: &'static str,
+    status: StatusCode,
+    languages: Vec<String>,
+}
+impl Reject for Page {}
+
 pub trait TryExt<T> {
     fn or_ise(self) -> Result<T, Rejection>;
     fn or_nf(self) -> Result<T, Rejection>;
-    fn or_redirect<M: Display>(self, msg: M, params: &Params) -> Result<T, Rejection>;
+    fn or_redirect<M: Display>(
+        self,
+        error: OAuthError,
+        description: M,
+        params: &Params,
+    ) -> Result<T, Rejection>;
     fn or_json(self, json: JsonError, status: StatusCode) -> Result<T, Rejection>;
+    fn or_page(
+        self,
+        error: &'static str,
+        status: StatusCode,
+        languages: &[String],
+    ) -> Result<T, Rejection>;
 }
 
 impl<T, E: Display> TryExt<T> for Result<T, E> {
@@ -46,18 +103,19 @@ impl<T, E: Display> TryExt<T> for Result<T, E> {
         })
     }
 
-    fn or_redirect<M: Display>(self, msg: M, params: &Params) -> Result<T, Rejection> {
+    fn or_redirect<M: Display>(
+        self,
+        error: OAuthError,
+        description: M,
+        params: &Params,
+    ) -> Result<T, Rejection> {
         self.map_err(|e| {
             tracing::error!("{}", e);
-
-            let uri = match &params.state {
-                Some(s) => format!("{}?error={}&state={}", params.redirect_uri, msg, s),
-                None => format!("{}?error={}", params.redirect_uri, msg),
-            };
-            match Uri::from_maybe_shared(uri).or_ise() {
-                Ok(uri) => warp::reject::custom(Redirect(uri)),
-                Err(e) => e,
-            }
+            warp::reject::custom(Redirect {
+                params: params.clone(),
+                error,
+                description: description.to_string(),
+            })
         })
     }
 
@@ -67,6 +125,22 @@ impl<T, E: Display> TryExt<T> for Result<T, E> {
             warp::reject::custom(Json(json, status))
         })
     }
+
+    fn or_page(
+        self,
+        error: &'static str,
+        status: StatusCode,
+        languages: &[String],
+    ) -> Result<T, Rejection> {
+        self.map_err(|e| {
+            tracing::error!("{}", e);
+            warp::reject::custom(Page {
+                error,
+                status,
+                languages: languages.to_vec(),
+            })
+        })
+    }
 }
 
 impl<T> TryExt<T> for Option<T> {
@@ -78,27 +152,56 @@ impl<T> TryExt<T> for Option<T> {
         self.ok_or_else(warp::reject::not_found)
     }
 
-    fn or_redirect<M: Display>(self, msg: M, params: &Params) -> Result<T, Rejection> {
+    fn or_redirect<M: Display>(
+        self,
+        error: OAuthError,
+        description: M,
+        params: &Params,
+    ) -> Result<T, Rejection> {
         self.ok_or_else(|| {
-            let uri = match &params.state {
-                Some(s) => format!("{}?error={}&state={}", params.redirect_uri, msg, s),
-                None => format!("{}?error={}", params.redirect_uri, msg),
-            };
-            match Uri::from_maybe_shared(uri).or_ise() {
-                Ok(uri) => warp::reject::custom(Redirect(uri)),
-                Err(e) => e,
-            }
+            warp::reject::custom(Redirect {
+                params: params.clone(),
+                error,
+                description: description.to_string(),
+            })
         })
     }
 
     fn or_json(self, json: JsonError, status: StatusCode) -> Result<T, Rejection> {
         self.ok_or_else(|| warp::reject::custom(Json(json, status)))
     }
+
+    fn or_page(
+        self,
+        error: &'static str,
+        status: StatusCode,
+        languages: &[String],
+    ) -> Result<T, Rejection> {
+        self.ok_or_else(|| {
+            warp::reject::custom(Page {
+                error,
+                status,
+                languages: languages.to_vec(),
+            })
+        })
+    }
 }
 
 pub async fn handle_redirects(err: Rejection) -> Result<impl Reply, Rejection> {
-    if let Some(Redirect(uri)) = err.find() {
-        Ok(warp::redirect::temporary(uri.clone()))
+    if let Some(Redirect {
+        params,
+        error,
+        description,
+    }) = err.find()
+    {
+        redirect::respond(
+            params,
+            &[
+                ("error", error.as_str()),
+                ("error_description", description),
+            ],
+        )
+        .or_ise()
     } else {
         Err(err)
     }
@@ -111,3 +214,26 @@ pub async fn handle_json(err: Rejection) -> Result<impl Reply, Rejection> {
         Err(err)
     }
 }
+
+pub async fn handle_pages(
+    err: Rejection,
+    config: &'static Config,
+) -> Result<impl Reply, Rejection> {
+    if let Some(Page {
+        error,
+        status,
+        languages,
+    }) = err.find()
+    {
+        let locale = Locale::negotiate(languages, config).await.or_ise()?;
+        let message = locale.text(&format!("error.{}", error), &[]);
+        let mut response =
+            templates::render("error", &[("message", &message)], &locale, None, config)
+                .await
+                .or_ise()?;
+        *response.status_mut() = *status;
+        Ok(response)
+    } else {
+        Err(err)
+    }
+}
@@ -0,0 +1,139 @@
+//! Export of everything Vaulth stores about a user, as a JSON archive
+//!
+//! Small archives are generated on the fly, larger ones in the background
+//! and kept for a while so they can be downloaded later
+
+use crate::db::{AuditEvent, Client, Consent, Export, Session, User, WebAuthnCredential};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// Users with more audit events than this get their archive generated in the background
+const MAX_INLINE_EVENTS: i64 = 1000;
+/// How long generated archives can be downloaded for, in hours
+const RETENTION: i64 = 24 * 7;
+/// Length of generated export IDs
+const ID_LEN: usize = 32;
+
+#[derive(Serialize)]
+struct Archive {
+    generated_at: DateTime<Utc>,
+    profile: User,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    identities: Vec<Identity>,
+    sessions: Vec<Session>,
+    passkeys: Vec<WebAuthnCredential>,
+    consents: Vec<Consent>,
+    audit_events: Vec<AuditEvent>,
+    clients: Vec<ConnectedClient>,
+}
+
+/// Account from a provider linked to the user
+#[derive(Serialize)]
+struct Identity {
+    provider: &'static str,
+    id: String,
+}
+
+/// Client the user logged in to or approved
+#[derive(Serialize)]
+struct ConnectedClient {
+    id: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logo_uri: Option<String>,
+}
+
+/// Whether the archive of a user is large enough to be generated in the background
+#[tracing::instrument(level = "debug")]
+pub async fn large(user_id: &str, pool: &PgPool) -> Result<bool> {
+    Ok(AuditEvent::count_by_user(user_id, pool).await? > MAX_INLINE_EVENTS)
+}
+
+/// Generates the archive of a user as JSON
+#[tracing::instrument(level = "debug")]
+pub async fn generate(user_id: &str, pool: &PgPool) -> Result<Option<String>> {
+    let profile = match User::select(user_id, pool).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let identities = [
+        ("google", &profile.google_id),
+        ("microsoft", &profile.microsoft_id),
+        ("facebook", &profile.facebook_id),
+        ("twitter", &profile.twitter_id),
+        ("github", &profile.github_id),
+        ("discord", &profile.discord_id),
+    ]
+    .iter()
+    .filter_map(|&(provider, id)| id.clone().map(|id| Identity { provider, id }))
+    .collect();
+
+    let sessions = Session::select_by_user(user_id, pool).await?;
+    let passkeys = WebAuthnCredential::select_by_user(user_id, pool).await?;
+    let consents = Consent::select_by_user(user_id, pool).await?;
+    let audit_events = AuditEvent::select_by_user(user_id, pool).await?;
+
+    let mut client_ids: Vec<&str> = consents
+        .iter()
+        .map(|c| c.client_id.as_str())
+        .chain(
+            sessions
+                .iter()
+                .flat_map(|s| s.clients.iter().map(String::as_str)),
+        )
+        .collect();
+    client_ids.sort();
+    client_ids.dedup();
+    let mut clients = Vec::with_capacity(client_ids.len());
+    for id in client_ids {
+        if let Some(client) = Client::select(id, pool).await? {
+            clients.push(ConnectedClient {
+                id: client.id,
+                name: client.name,
+                logo_uri: client.logo_uri,
+            });
+        }
+    }
+
+    let archive = Archive {
+        generated_at: Utc::now(),
+        email: profile.email.clone(),
+        profile,
+        identities,
+        sessions,
+        passkeys,
+        consents,
+        audit_events,
+        clients,
+    };
+    Ok(Some(serde_json::to_string(&archive)?))
+}
+
+/// Starts generating the archive of a user in the background
+#[tracing::instrument(level = "debug")]
+pub async fn start(user_id: &str, pool: &'static PgPool) -> Result<Export> {
+    Export::delete_expired(pool).await?;
+
+    let id: String = OsRng.sample_iter(&Alphanumeric).take(ID_LEN).collect();
+    let export = Export::insert(&id, user_id, Duration::hours(RETENTION), pool).await?;
+
+    let user_id = user_id.to_owned();
+    tokio::spawn(async move {
+        let data = match generate(&user_id, pool).await {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("couldn't generate export {}: {}", id, e);
+                None
+            }
+        };
+        if let Err(e) = Export::complete(&id, data.as_deref(), pool).await {
+            tracing::error!("couldn't store export {}: {}", id, e);
+        }
+    });
+    Ok(export)
+}
@@ -0,0 +1,33 @@
+use warp::{
+    http::{header, HeaderValue},
+    reply::Response,
+    Reply,
+};
+
+/// Escapes text so it can be safely inserted in HTML content and attributes
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replies with an HTML page, refusing to be framed so it can't be overlaid to trick users into clicking
+pub fn page(body: String) -> Response {
+    let mut response = warp::reply::html(body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("frame-ancestors 'none'"),
+    );
+    response
+}
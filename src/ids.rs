@@ -0,0 +1,156 @@
+//! Generation of user IDs, which are never chosen by users
+
+use crate::config::{AccountConfig, IdStrategy};
+use chrono::Utc;
+use rand::{rngs::OsRng, RngCore};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Crockford's base32 alphabet, used by ULIDs
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Start of snowflake timestamps, 2020-01-01T00:00:00Z in milliseconds
+const SNOWFLAKE_EPOCH: i64 = 1_577_836_800_000;
+const WORKER_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+/// Last snowflake timestamp and sequence, packed like the snowflakes themselves
+static SNOWFLAKE_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a new user ID using the configured strategy
+pub fn generate(config: &AccountConfig) -> String {
+    match config.id_strategy {
+        IdStrategy::Uuid => uuid(),
+        IdStrategy::Ulid => ulid(),
+        IdStrategy::Snowflake => snowflake(config.worker_id),
+    }
+}
+
+/// Random version 4 UUID
+fn uuid() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..],
+    )
+}
+
+/// Millisecond timestamp followed by 80 random bits, lexicographically sortable
+fn ulid() -> String {
+    let mut random = [0u8; 10];
+    OsRng.fill_bytes(&mut random);
+    let value = (Utc::now().timestamp_millis() as u128) << 80
+        | random.iter().fold(0u128, |acc, b| acc << 8 | *b as u128);
+
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD[(value >> (i * 5)) as usize & 0x1f] as char)
+        .collect()
+}
+
+/// Twitter style snowflake, made of a millisecond timestamp, the worker ID and a sequence number
+/// Worker IDs must be unique among the instances sharing a database
+fn snowflake(worker_id: u16) -> String {
+    let worker_id = u64::from(worker_id) & ((1 << WORKER_BITS) - 1);
+    let max_sequence = (1 << SEQUENCE_BITS) - 1;
+
+    loop {
+        let now = (Utc::now().timestamp_millis() - SNOWFLAKE_EPOCH) as u64;
+        let last = SNOWFLAKE_STATE.load(Ordering::Acquire);
+        let (last_time, last_sequence) = (last >> SEQUENCE_BITS, last & max_sequence);
+
+        // Never go back in time, and wait for the next millisecond once the sequence is exhausted
+        let (time, sequence) = if now > last_time {
+            (now, 0)
+        } else if last_sequence < max_sequence {
+            (last_time, last_sequence + 1)
+        } else {
+            std::thread::yield_now();
+            continue;
+        };
+
+        let state = time << SEQUENCE_BITS | sequence;
+        if SNOWFLAKE_STATE
+            .compare_exchange(last, state, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            let id = time << (WORKER_BITS + SEQUENCE_BITS) | worker_id << SEQUENCE_BITS | sequence;
+            return id.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn uuid_version_and_variant() {
+        for _ in 0..100 {
+            let id = uuid();
+            assert_eq!(id.len(), 36);
+            let groups: Vec<&str> = id.split('-').collect();
+            let lengths: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+            assert_eq!(lengths, [8, 4, 4, 4, 12]);
+            assert!(groups
+                .iter()
+                .all(|g| g.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))));
+            // Version 4, and the variant described by RFC 4122
+            assert!(groups[2].starts_with('4'));
+            assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+        }
+    }
+
+    #[test]
+    fn ulid_alphabet() {
+        for _ in 0..100 {
+            let id = ulid();
+            assert_eq!(id.len(), 26);
+            assert!(id.bytes().all(|b| CROCKFORD.contains(&b)));
+            // 26 characters hold 130 bits, the first one only carrying the top 3 of 128
+            assert!(id.as_bytes()[0] <= b'7');
+        }
+    }
+
+    #[test]
+    fn ulid_sorts_by_time() {
+        let before = ulid();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let after = ulid();
+        assert!(before[..10] < after[..10]);
+    }
+
+    #[test]
+    fn snowflake_monotonic() {
+        let ids: Vec<u64> = (0..10_000).map(|_| snowflake(1).parse().unwrap()).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+    }
+
+    #[test]
+    fn snowflake_worker_bits() {
+        let worker = |id: String| {
+            let id: u64 = id.parse().unwrap();
+            (id >> SEQUENCE_BITS) & ((1 << WORKER_BITS) - 1)
+        };
+        assert_eq!(worker(snowflake(0)), 0);
+        assert_eq!(worker(snowflake(5)), 5);
+        assert_eq!(worker(snowflake(1023)), 1023);
+        // Worker IDs wider than their bits are truncated rather than spilling into the timestamp
+        assert_eq!(worker(snowflake(1024 + 7)), 7);
+
+        let id: u64 = snowflake(1023).parse().unwrap();
+        let time = id >> (WORKER_BITS + SEQUENCE_BITS);
+        let now = (Utc::now().timestamp_millis() - SNOWFLAKE_EPOCH) as u64;
+        assert!(time <= now && now - time < 1000);
+    }
+}
@@ -0,0 +1,147 @@
+//! Bulk import of users from another system, keeping their password hashes and identities
+//!
+//! Run with `vaulth <config> import <file>`. Files ending in `.csv` are read as CSV with a header
+//! row, others as JSON Lines, with the fields of `Record`. Users log in with their previous
+//! password, whose hash is replaced by an Argon2id one the first time it is verified.
+//! The ID given to each imported user is printed along with their username.
+
+use crate::{
+    config::Config,
+    db::{audit_events, AuditEvent, ImportedUser, User},
+    emails, ids, password, profile, usernames,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::fs;
+
+/// Maximum length of password hashes, matching the database column
+const MAX_HASH_LEN: usize = 256;
+
+/// User as exported by the other system, empty CSV fields being absent
+#[derive(Debug, Deserialize)]
+struct Record {
+    username: String,
+    /// Hash in one of the formats `password::check` recognizes
+    password: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+    google_id: Option<String>,
+    github_id: Option<String>,
+    discord_id: Option<String>,
+}
+
+/// Imports every user of a file, skipping and logging the ones that can't be imported
+pub async fn run(path: &str, config: &'static Config, pool: &PgPool) -> Result<()> {
+    let contents = fs::read_to_string(path).await?;
+    let records: Vec<(usize, Result<Record, String>)> = if path.ends_with(".csv") {
+        csv::Reader::from_reader(contents.as_bytes())
+            .deserialize()
+            .enumerate()
+            // Account for the header row, and start counting at 1
+            .map(|(i, r)| (i + 2, r.map_err(|e| e.to_string())))
+            .collect()
+    } else {
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| (i + 1, serde_json::from_str(line).map_err(|e| e.to_string())))
+            .collect()
+    };
+
+    let (mut imported, mut skipped) = (0, 0);
+    for (line, record) in records {
+        let result = match record {
+            Ok(record) => import(&record, config, pool).await?,
+            Err(reason) => Err(reason),
+        };
+        match result {
+            Ok(user) => {
+                imported += 1;
+                println!("{}\t{}", user.id, user.username.unwrap_or_default());
+            }
+            Err(reason) => {
+                skipped += 1;
+                tracing::warn!(line, %reason, "skipped user");
+            }
+        }
+    }
+    tracing::info!(imported, skipped, "import finished");
+    Ok(())
+}
+
+/// Registers a user, unless their record is invalid or conflicts with an existing user
+async fn import(
+    record: &Record,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<Result<User, String>> {
+    let username = match usernames::check(&record.username, None, config, pool).await? {
+        Ok(username) => username,
+        Err(unavailable) => return Ok(Err(unavailable.reason().to_owned())),
+    };
+
+    if let Some(hash) = &record.password {
+        if hash.len() >= MAX_HASH_LEN || !password::recognized(hash) {
+            return Ok(Err("unrecognized password hash".to_owned()));
+        }
+    }
+
+    let email = record.email.as_deref().map(str::trim);
+    let email_verified = record.email_verified.unwrap_or_default();
+    if let Some(email) = email {
+        if !emails::valid(email) {
+            return Ok(Err("invalid email".to_owned()));
+        }
+        if email_verified && User::select_by_email(email, pool).await?.is_some() {
+            return Ok(Err("email taken".to_owned()));
+        }
+    }
+
+    let name = match record
+        .name
+        .as_deref()
+        .map(|n| profile::name(n, &config.profile))
+    {
+        Some(Ok(name)) => name,
+        Some(Err(reason)) => return Ok(Err(reason.to_owned())),
+        None => None,
+    };
+
+    let identities = [
+        ("google", &record.google_id),
+        ("github", &record.github_id),
+        ("discord", &record.discord_id),
+    ];
+    for (provider, id) in &identities {
+        if let Some(id) = id {
+            if User::select_by_provider(provider, id, pool)
+                .await?
+                .is_some()
+            {
+                return Ok(Err(format!("{} identity taken", provider)));
+            }
+        }
+    }
+
+    let user = User::import(
+        ImportedUser {
+            id: &ids::generate(&config.account),
+            username: &username.name,
+            username_skeleton: &username.skeleton,
+            email,
+            email_verified,
+            name: name.as_deref(),
+            password: record.password.as_deref(),
+            google_id: record.google_id.as_deref(),
+            github_id: record.github_id.as_deref(),
+            discord_id: record.discord_id.as_deref(),
+        },
+        pool,
+    )
+    .await?;
+    AuditEvent::insert(&user.id, audit_events::IMPORTED, None, None, pool).await?;
+    Ok(Ok(user))
+}
@@ -1,67 +1,130 @@
+//! Signed tokens, either read back by Vaulth or handed to clients
+//!
+//! Every kind of token has a purpose checked when it is decoded, so it can't be used in place of
+//! another one with a similar shape. Tokens clients verify, like access and logout tokens, are
+//! signed with the private key whose public part is published, others with a secret key only
+//! Vaulth knows, so a token clients can verify is never accepted in place of an internal one.
+
 use crate::config::TokenConfig;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Duration, Utc};
-use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt;
 use tokio::{fs, task};
 
+/// Kind of token, which decides how it is signed and what it can be decoded as
+pub trait Purpose {
+    /// Value of the `purpose` claim, unique to each kind of token
+    const PURPOSE: &'static str;
+    /// Whether clients verify the token, in which case it is signed with the private key
+    const PUBLIC: bool = false;
+}
+
 #[derive(Serialize, Deserialize)]
 struct Claims<T> {
     #[serde(with = "chrono_jwt")]
     exp: DateTime<Utc>,
     #[serde(with = "chrono_jwt")]
     iat: DateTime<Utc>,
+    purpose: String,
 
     #[serde(flatten)]
     data: T,
 }
 
+/// Checks that the secret key can be read, so a missing one fails at startup instead of on every login
+pub async fn check_secret_key(config: &TokenConfig) -> Result<()> {
+    let path = config.secret_key.display();
+    match fs::read(&config.secret_key).await {
+        Ok(key) if !key.is_empty() => Ok(()),
+        Ok(_) => Err(anyhow!("secret key {} is empty", path)),
+        Err(e) => Err(anyhow!(
+            "couldn't read secret key {} ({}), generate one with `openssl rand -out {} 64`",
+            path,
+            e,
+            path,
+        )),
+    }
+}
+
 /// Encodes and returns a JWT for the specified user
 #[tracing::instrument(level = "debug")]
 pub async fn encode<T>(data: T, config: &TokenConfig) -> Result<String>
 where
-    T: Send + Serialize + fmt::Debug + 'static,
+    T: Purpose + Send + Serialize + fmt::Debug + 'static,
+{
+    encode_for(data, Duration::minutes(config.duration), config).await
+}
+
+/// Encodes and returns a JWT valid for a custom duration
+#[tracing::instrument(level = "debug")]
+pub async fn encode_for<T>(data: T, duration: Duration, config: &TokenConfig) -> Result<String>
+where
+    T: Purpose + Send + Serialize + fmt::Debug + 'static,
 {
-    let duration = Duration::minutes(config.duration);
-    let key = fs::read(&config.private_key).await?;
+    let key = if T::PUBLIC {
+        &config.private_key
+    } else {
+        &config.secret_key
+    };
+    let key = fs::read(key).await?;
     Ok(task::spawn_blocking(move || encode_sync(data, duration, key)).await??)
 }
 fn encode_sync<T>(data: T, duration: Duration, key: Vec<u8>) -> Result<String>
 where
-    T: Serialize,
+    T: Purpose + Serialize,
 {
     let now = Utc::now();
+    let (algorithm, key) = if T::PUBLIC {
+        (Algorithm::ES384, EncodingKey::from_ec_pem(&key)?)
+    } else {
+        (Algorithm::HS256, EncodingKey::from_secret(&key))
+    };
     Ok(jsonwebtoken::encode(
-        &Header::new(Algorithm::ES384),
+        &Header::new(algorithm),
         &Claims {
             exp: now + duration,
             iat: now,
+            purpose: T::PURPOSE.to_owned(),
             data,
         },
-        &EncodingKey::from_ec_pem(&key)?,
+        &key,
     )?)
 }
 
-/// Decodes a JWT and returns the user it refers to if valid
+/// Decodes a JWT and returns its content if it is valid and was issued for the expected purpose
 #[tracing::instrument(level = "debug")]
 pub async fn decode<T>(token: String, config: &TokenConfig) -> Result<Option<T>>
 where
-    T: Send + DeserializeOwned + fmt::Debug + 'static,
+    T: Purpose + Send + DeserializeOwned + fmt::Debug + 'static,
 {
-    let key = fs::read(&config.public_key).await?;
+    let key = if T::PUBLIC {
+        &config.public_key
+    } else {
+        &config.secret_key
+    };
+    let key = fs::read(key).await?;
     Ok(task::spawn_blocking(move || decode_sync(token, key)).await??)
 }
 fn decode_sync<T>(token: String, key: Vec<u8>) -> Result<Option<T>>
 where
-    T: DeserializeOwned,
+    T: Purpose + DeserializeOwned,
 {
-    match jsonwebtoken::decode::<Claims<T>>(
-        &token,
-        &DecodingKey::from_secret(&key),
-        &Default::default(),
-    ) {
-        Ok(data) => Ok(Some(data.claims.data)),
+    let (validation, key) = if T::PUBLIC {
+        (
+            Validation::new(Algorithm::ES384),
+            DecodingKey::from_ec_pem(&key)?,
+        )
+    } else {
+        (
+            Validation::new(Algorithm::HS256),
+            DecodingKey::from_secret(&key),
+        )
+    };
+    match jsonwebtoken::decode::<Claims<T>>(&token, &key, &validation) {
+        Ok(data) if data.claims.purpose == T::PURPOSE => Ok(Some(data.claims.data)),
+        Ok(_) => Ok(None),
         Err(e) => match e.kind() {
             ErrorKind::InvalidKeyFormat | ErrorKind::Crypto(_) => Err(e.into()),
             _ => Ok(None),
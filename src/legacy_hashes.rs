@@ -0,0 +1,172 @@
+//! Verification of password hashes imported from other systems
+//!
+//! Supported formats are bcrypt (`$2a$`, `$2b$`, `$2y$`), SHA-512 crypt (`$6$`), and the modular
+//! formats of passlib for PBKDF2-SHA256 (`$pbkdf2-sha256$<rounds>$<salt>$<hash>`) and scrypt
+//! (`$scrypt$ln=<log n>,r=<r>,p=<p>$<salt>$<hash>`). These hashes never use the configured secret,
+//! and are replaced by Argon2id ones once their password is known.
+
+use anyhow::{anyhow, Result};
+use ring::{constant_time, pbkdf2};
+use scrypt::ScryptParams;
+use std::num::NonZeroU32;
+
+const BCRYPT_PREFIXES: &[&str] = &["$2a$", "$2b$", "$2y$"];
+const SHA512_CRYPT_PREFIX: &str = "$6$";
+const PBKDF2_SHA256_PREFIX: &str = "$pbkdf2-sha256$";
+const SCRYPT_PREFIX: &str = "$scrypt$";
+
+/// Whether a hash is in one of the supported legacy formats
+pub fn recognized(hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .chain(&[SHA512_CRYPT_PREFIX, PBKDF2_SHA256_PREFIX, SCRYPT_PREFIX])
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// Verifies a legacy hash, which is slow enough that it should be done on a blocking thread
+pub fn verify(hash: &str, password: &str) -> Result<bool> {
+    if BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+    {
+        Ok(bcrypt::verify(password, hash)?)
+    } else if hash.starts_with(SHA512_CRYPT_PREFIX) {
+        Ok(sha_crypt::sha512_check(password, hash).is_ok())
+    } else if let Some(hash) = hash.strip_prefix(PBKDF2_SHA256_PREFIX) {
+        verify_pbkdf2_sha256(hash, password)
+    } else if let Some(hash) = hash.strip_prefix(SCRYPT_PREFIX) {
+        verify_scrypt(hash, password)
+    } else {
+        Err(anyhow!("unrecognized password hash"))
+    }
+}
+
+fn verify_pbkdf2_sha256(hash: &str, password: &str) -> Result<bool> {
+    let (rounds, salt, expected) = match hash.split('$').collect::<Vec<_>>().as_slice() {
+        [rounds, salt, expected] => (*rounds, *salt, *expected),
+        _ => return Err(anyhow!("malformed PBKDF2 hash")),
+    };
+    let rounds: NonZeroU32 = rounds.parse()?;
+    let salt = decode(salt)?;
+    let expected = decode(expected)?;
+
+    Ok(pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        rounds,
+        &salt,
+        password.as_bytes(),
+        &expected,
+    )
+    .is_ok())
+}
+
+fn verify_scrypt(hash: &str, password: &str) -> Result<bool> {
+    let (params, salt, expected) = match hash.split('$').collect::<Vec<_>>().as_slice() {
+        [params, salt, expected] => (*params, *salt, *expected),
+        _ => return Err(anyhow!("malformed scrypt hash")),
+    };
+    let (mut log_n, mut r, mut p) = (None, None, None);
+    for param in params.split(',') {
+        let mut pair = param.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some("ln"), Some(value)) => log_n = Some(value.parse()?),
+            (Some("r"), Some(value)) => r = Some(value.parse()?),
+            (Some("p"), Some(value)) => p = Some(value.parse()?),
+            _ => return Err(anyhow!("malformed scrypt parameters")),
+        }
+    }
+    let params = match (log_n, r, p) {
+        (Some(log_n), Some(r), Some(p)) => {
+            ScryptParams::new(log_n, r, p).map_err(|_| anyhow!("invalid scrypt parameters"))?
+        }
+        _ => return Err(anyhow!("missing scrypt parameters")),
+    };
+    let salt = decode(salt)?;
+    let expected = decode(expected)?;
+
+    let mut derived = vec![0; expected.len()];
+    scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived)
+        .map_err(|_| anyhow!("invalid scrypt hash length"))?;
+    Ok(constant_time::verify_slices_are_equal(&derived, &expected).is_ok())
+}
+
+/// Decodes unpadded base64, passlib using `.` in place of `+`
+fn decode(encoded: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(
+        encoded.replace('.', "+"),
+        base64::STANDARD_NO_PAD,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(hash: &str, password: &str) {
+        assert!(recognized(hash));
+        assert!(verify(hash, password).unwrap(), "{} should match", hash);
+        assert!(!verify(hash, "wrong password").unwrap());
+    }
+
+    #[test]
+    fn bcrypt() {
+        // From the test vectors of OpenBSD's and Openwall's implementations
+        check(
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "U*U",
+        );
+        check(
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.7uG0VCzI2bS7j6ymqJi9CdcdxiRTWNy",
+            "",
+        );
+        check(
+            "$2b$05$XXXXXXXXXXXXXXXXXXXXXOAcXxm9kjPGEMsLznoKqmqw7tc8WCx4a",
+            "U*U*U",
+        );
+        check(
+            "$2y$05$CCCCCCCCCCCCCCCCCCCCC.VGOzA784oUp/Z0DY336zx7pLYAy0lwK",
+            "U*U*",
+        );
+    }
+
+    #[test]
+    fn sha512_crypt() {
+        // From the specification of SHA-crypt
+        check(
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1",
+            "Hello world!",
+        );
+        check(
+            "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.",
+            "Hello world!",
+        );
+    }
+
+    #[test]
+    fn pbkdf2_sha256() {
+        // Generated with Python's hashlib, then encoded like passlib does
+        check(
+            "$pbkdf2-sha256$1000$c2FsdHNhbHRzYWx0MTIzNA$w7/Z.TtKe2706ziYqntGWEpywycBhHRgtLIN.hknDGQ",
+            "correct horse",
+        );
+        assert!(verify("$pbkdf2-sha256$1000$c2FsdA", "correct horse").is_err());
+        assert!(verify("$pbkdf2-sha256$0$c2FsdA$c2FsdA", "correct horse").is_err());
+    }
+
+    #[test]
+    fn scrypt() {
+        // Generated with Python's hashlib, then encoded like passlib does
+        check(
+            "$scrypt$ln=10,r=8,p=1$c2FsdHNhbHRzYWx0MTIzNA$sBRFYJxJyR9gnQuMQio24Q6ZTaugiqeT1tSTvyCASoM",
+            "correct horse",
+        );
+        assert!(verify("$scrypt$ln=10,r=8$c2FsdA$c2FsdA", "correct horse").is_err());
+        assert!(verify("$scrypt$ln=10,r=8,p=1,x=1$c2FsdA$c2FsdA", "correct horse").is_err());
+    }
+
+    #[test]
+    fn unrecognized() {
+        assert!(!recognized("$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA"));
+        assert!(verify("$1$saltsalt$hash", "password").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! In-memory rate limiting, counting attempts per key over fixed windows
+//!
+//! Counts aren't shared between instances, so every instance allows the configured number of attempts.
+
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+
+/// Number of attempts allowed per key in every window
+#[derive(Debug)]
+pub struct RateLimiter {
+    max: u32,
+    window: Duration,
+    attempts: Mutex<HashMap<String, (DateTime<Utc>, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an attempt, returning whether it is allowed
+    pub fn attempt(&self, key: &str) -> bool {
+        let now = Utc::now();
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget windows that ended so keys seen once don't pile up
+        let window = self.window;
+        attempts.retain(|_, (start, _)| now - *start < window);
+
+        let (_, count) = attempts.entry(key.to_owned()).or_insert((now, 0));
+        *count += 1;
+        *count <= self.max
+    }
+}
+
+/// Limits on requests mailing something to an address, counted per address and per IP address
+#[derive(Debug)]
+pub struct MailLimits {
+    per_address: RateLimiter,
+    per_ip: RateLimiter,
+}
+
+impl MailLimits {
+    pub fn new(per_address: u32, per_ip: u32, window: Duration) -> Self {
+        Self {
+            per_address: RateLimiter::new(per_address, window),
+            per_ip: RateLimiter::new(per_ip, window),
+        }
+    }
+
+    /// Records a request, returning whether both the address and the IP address it came from are under their limit
+    pub fn attempt(&self, email: &str, ip: Option<&str>) -> bool {
+        let address = self.per_address.attempt(&email.to_lowercase());
+        let ip = ip.map_or(true, |ip| self.per_ip.attempt(ip));
+        address && ip
+    }
+}
+
+/// Address of the client, as reported by the trusted reverse proxies Vaulth runs behind
+///
+/// Proxies append the address they received the request from to `X-Forwarded-For`,
+/// so entries are only trusted from the end until one that wasn't added by a trusted proxy.
+/// Requests that don't come from a trusted proxy are attributed to the address they come from.
+pub fn client_ip(
+    forwarded_for: Option<&str>,
+    remote: Option<SocketAddr>,
+    trusted_proxies: &[IpAddr],
+) -> Option<String> {
+    let mut ip = remote?.ip();
+    for entry in forwarded_for.into_iter().flat_map(|f| f.rsplit(',')) {
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+        match entry.trim().parse() {
+            Ok(forwarded) => ip = forwarded,
+            Err(_) => break,
+        }
+    }
+    Some(ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const PROXY: &str = "10.0.0.1";
+    const OTHER_PROXY: &str = "10.0.0.2";
+
+    fn remote(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 443))
+    }
+
+    fn proxies() -> Vec<IpAddr> {
+        vec![PROXY.parse().unwrap(), OTHER_PROXY.parse().unwrap()]
+    }
+
+    #[test]
+    fn no_proxies() {
+        let ip = client_ip(Some("1.2.3.4"), remote("5.6.7.8"), &[]);
+        assert_eq!(ip.as_deref(), Some("5.6.7.8"));
+        let ip = client_ip(None, remote("5.6.7.8"), &[]);
+        assert_eq!(ip.as_deref(), Some("5.6.7.8"));
+        assert_eq!(client_ip(Some("1.2.3.4"), None, &proxies()), None);
+    }
+
+    #[test]
+    fn untrusted_remote() {
+        // Anyone can send the header, only proxies are believed
+        let ip = client_ip(Some("1.2.3.4"), remote("5.6.7.8"), &proxies());
+        assert_eq!(ip.as_deref(), Some("5.6.7.8"));
+    }
+
+    #[test]
+    fn spoofed_entries() {
+        // The client sent the leftmost entries itself, the proxy appended the last one
+        let ip = client_ip(
+            Some("9.9.9.9, 10.0.0.2, 1.2.3.4"),
+            remote(PROXY),
+            &proxies(),
+        );
+        assert_eq!(ip.as_deref(), Some("1.2.3.4"));
+    }
+
+    #[test]
+    fn proxy_chain() {
+        let ip = client_ip(
+            Some("9.9.9.9, 1.2.3.4, 10.0.0.1"),
+            remote(OTHER_PROXY),
+            &proxies(),
+        );
+        assert_eq!(ip.as_deref(), Some("1.2.3.4"));
+        // Every entry was added by a proxy
+        let ip = client_ip(Some(PROXY), remote(OTHER_PROXY), &proxies());
+        assert_eq!(ip.as_deref(), Some(PROXY));
+    }
+
+    #[test]
+    fn garbage_entry() {
+        // Nothing past an entry that doesn't parse can be trusted
+        let ip = client_ip(Some("1.2.3.4, unknown"), remote(PROXY), &proxies());
+        assert_eq!(ip.as_deref(), Some(PROXY));
+        let ip = client_ip(
+            Some("1.2.3.4, not an ip, 10.0.0.1"),
+            remote(OTHER_PROXY),
+            &proxies(),
+        );
+        assert_eq!(ip.as_deref(), Some(PROXY));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter::new(2, Duration::minutes(1));
+        assert!(limiter.attempt("a"));
+        assert!(limiter.attempt("a"));
+        assert!(!limiter.attempt("a"));
+        assert!(!limiter.attempt("a"));
+        assert!(limiter.attempt("b"));
+    }
+
+    #[test]
+    fn rate_limiter_window() {
+        let limiter = RateLimiter::new(1, Duration::milliseconds(10));
+        assert!(limiter.attempt("a"));
+        assert!(!limiter.attempt("a"));
+        thread::sleep(std::time::Duration::from_millis(20));
+        assert!(limiter.attempt("a"));
+    }
+
+    #[test]
+    fn mail_limits() {
+        let limits = MailLimits::new(1, 2, Duration::minutes(1));
+        assert!(limits.attempt("user@example.com", Some("1.2.3.4")));
+        // Addresses are counted regardless of case
+        assert!(!limits.attempt("User@Example.com", Some("5.6.7.8")));
+        assert!(limits.attempt("other@example.com", Some("1.2.3.4")));
+        // The IP address is over its limit, whatever address it mails
+        assert!(!limits.attempt("third@example.com", Some("1.2.3.4")));
+        // Without an IP address, only the address is counted
+        assert!(limits.attempt("fourth@example.com", None));
+        assert!(!limits.attempt("fourth@example.com", None));
+    }
+}
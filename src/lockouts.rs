@@ -0,0 +1,519 @@
+//! Protection against guessing credentials, through delays growing with every failed attempt
+//!
+//! Failed attempts are counted per account, second factor, IP address and client. Once a key goes past the
+//! attempts it is allowed, further ones are refused for a delay doubling with every failure,
+//! and accounts can be locked until an admin unlocks them. Users are mailed when someone
+//! gets their account locked, since it likely means someone else is guessing their password.
+//!
+//! Attempts are counted as failed before credentials are verified and taken back once they succeed,
+//! so guesses sent in parallel can't all be verified before the first ones are counted.
+
+use crate::{
+    config::{Config, LockoutConfig, LockoutStore},
+    db::{audit_events, AuditEvent, FailedAttempts, User},
+    mail::{self, Message},
+    templates::Locale,
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::{collections::HashMap, sync::Mutex, time};
+
+/// How often stale failed attempts are forgotten
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(10 * 60);
+/// Highest power of two delays are multiplied by, far above any sensible maximum delay
+const MAX_DOUBLINGS: u32 = 30;
+
+/// What failed attempts are counted against
+#[derive(Debug, Clone, Copy)]
+pub enum Key<'a> {
+    Account(&'a str),
+    /// Second factor of an account, counted apart so logging in with the password doesn't reset it
+    Mfa(&'a str),
+    Ip(&'a str),
+    Client(&'a str),
+}
+
+impl Key<'_> {
+    fn id(self) -> String {
+        match self {
+            Self::Account(id) => format!("account:{}", id),
+            Self::Mfa(id) => format!("mfa:{}", id),
+            Self::Ip(ip) => format!("ip:{}", ip),
+            Self::Client(id) => format!("client:{}", id),
+        }
+    }
+
+    /// Whether failures with the key can get an account locked, and its user mailed about it
+    fn account(self) -> bool {
+        matches!(self, Self::Account(_) | Self::Mfa(_))
+    }
+}
+
+/// Attempt counted as failed until it succeeds, along with the resulting counts of its account keys
+#[derive(Debug)]
+pub struct Recorded(Vec<FailedAttempts>);
+
+/// How long attempts are refused for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lock {
+    Until(DateTime<Utc>),
+    /// Until an admin unlocks the account
+    Permanent,
+}
+
+/// Where failed attempts are counted
+#[derive(Debug)]
+enum Store {
+    Memory(Mutex<HashMap<String, FailedAttempts>>),
+    Postgres(&'static PgPool),
+}
+
+/// Counts of failed attempts, in the configured store
+#[derive(Debug)]
+pub struct Lockouts {
+    store: Store,
+    config: &'static LockoutConfig,
+}
+
+impl Lockouts {
+    pub fn new(config: &'static LockoutConfig, pool: &'static PgPool) -> Self {
+        let store = match config.store {
+            LockoutStore::Memory => Store::Memory(Mutex::new(HashMap::new())),
+            LockoutStore::Postgres => Store::Postgres(pool),
+        };
+        Self { store, config }
+    }
+
+    /// Records an attempt with each of the keys before credentials are verified, unless one of them is locked
+    /// The attempt counts as failed until `succeed` forgets it, so concurrent attempts can't all get through
+    /// before the first ones fail. Refused attempts aren't counted.
+    #[tracing::instrument(level = "debug")]
+    pub async fn record(&self, keys: &[Key<'_>]) -> Result<std::result::Result<Recorded, Lock>> {
+        let ids: Vec<String> = keys.iter().map(|k| k.id()).collect();
+        let now = Utc::now();
+        let forget_before = now - Duration::minutes(self.config.reset_after);
+
+        let recorded = match &self.store {
+            Store::Memory(attempts) => {
+                let mut attempts = attempts.lock().unwrap_or_else(|e| e.into_inner());
+                let existing: Vec<FailedAttempts> = ids
+                    .iter()
+                    .filter_map(|id| attempts.get(id).cloned())
+                    .collect();
+                if let Some(lock) = self.refused(&existing, now) {
+                    return Ok(Err(lock));
+                }
+
+                ids.iter()
+                    .map(|id| {
+                        let entry = attempts.entry(id.clone()).or_insert(FailedAttempts {
+                            key: id.clone(),
+                            failures: 0,
+                            last_failure: now,
+                            locked: false,
+                        });
+                        if entry.last_failure < forget_before && !entry.locked {
+                            entry.failures = 0;
+                        }
+                        entry.failures += 1;
+                        entry.last_failure = now;
+                        entry.clone()
+                    })
+                    .collect()
+            }
+            Store::Postgres(pool) => {
+                if ids.is_empty() {
+                    return Ok(Ok(Recorded(Vec::new())));
+                }
+                let mut tx = pool.begin().await?;
+                let existing = FailedAttempts::select_for_update(&ids, now, &mut tx).await?;
+                if let Some(lock) = self.refused(&existing, now) {
+                    tx.rollback().await?;
+                    return Ok(Err(lock));
+                }
+
+                let mut recorded = Vec::with_capacity(ids.len());
+                for id in &ids {
+                    recorded.push(FailedAttempts::record(id, now, forget_before, &mut tx).await?);
+                }
+                tx.commit().await?;
+                recorded
+            }
+        };
+        Ok(Ok(Recorded(
+            recorded
+                .into_iter()
+                .zip(keys)
+                .filter(|(_, key)| key.account())
+                .map(|(attempts, _)| attempts)
+                .collect(),
+        )))
+    }
+
+    /// Keeps an attempt counted as failed, returning whether it got an account locked
+    #[tracing::instrument(level = "debug")]
+    pub async fn fail(&self, recorded: &Recorded) -> Result<bool> {
+        let mut locked = false;
+        for attempts in &recorded.0 {
+            let failures = attempts.failures as u32;
+            let permanent = self.config.permanent_after.map_or(false, |p| failures >= p);
+            if permanent && !attempts.locked {
+                self.lock_permanently(&attempts.key).await?;
+                locked = true;
+            }
+            // Only the attempt getting the account delayed first is reported, so users aren't mailed over and over
+            locked |= failures == self.config.free_attempts.max(1);
+        }
+        Ok(locked)
+    }
+
+    /// Forgets the failed attempts of accounts and clients after a successful one
+    /// IP addresses only get the attempt back, so guessing can't go on by logging in to another account now and then,
+    /// while clients and users behind a shared address succeeding over and over are never delayed.
+    #[tracing::instrument(level = "debug")]
+    pub async fn succeed(&self, keys: &[Key<'_>]) -> Result<()> {
+        let (ips, ids): (Vec<&Key>, Vec<&Key>) = keys.iter().partition(|k| matches!(k, Key::Ip(_)));
+        let ids: Vec<String> = ids.into_iter().map(|k| k.id()).collect();
+        let ips: Vec<String> = ips.into_iter().map(|k| k.id()).collect();
+        self.delete(&ids).await?;
+        self.take_back(&ips).await
+    }
+    /// Unlocks an account and its second factor, whether they were locked for a while or permanently
+    #[tracing::instrument(level = "debug")]
+    pub async fn unlock(&self, user_id: &str) -> Result<()> {
+        self.delete(&[Key::Account(user_id).id(), Key::Mfa(user_id).id()])
+            .await
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        match &self.store {
+            Store::Memory(attempts) => {
+                let mut attempts = attempts.lock().unwrap_or_else(|e| e.into_inner());
+                for id in ids {
+                    attempts.remove(id);
+                }
+            }
+            Store::Postgres(pool) => {
+                FailedAttempts::delete(ids, pool).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the failed attempts of unlocked keys that didn't fail for a while, so keys seen once don't pile up
+    #[tracing::instrument(level = "debug")]
+    pub async fn forget_stale(&self) -> Result<()> {
+        let forget_before = Utc::now() - Duration::minutes(self.config.reset_after);
+        match &self.store {
+            Store::Memory(attempts) => {
+                let mut attempts = attempts.lock().unwrap_or_else(|e| e.into_inner());
+                attempts.retain(|_, a| a.locked || a.last_failure >= forget_before);
+            }
+            Store::Postgres(pool) => {
+                FailedAttempts::delete_stale(forget_before, pool).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Uncounts an attempt of each key, forgetting those left without any
+    async fn take_back(&self, ids: &[String]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        match &self.store {
+            Store::Memory(attempts) => {
+                let mut attempts = attempts.lock().unwrap_or_else(|e| e.into_inner());
+                for id in ids {
+                    if let Some(entry) = attempts.get_mut(id) {
+                        entry.failures -= 1;
+                        if entry.failures <= 0 && !entry.locked {
+                            attempts.remove(id);
+                        }
+                    }
+                }
+            }
+            Store::Postgres(pool) => {
+                FailedAttempts::take_back(ids, pool).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn lock_permanently(&self, id: &str) -> Result<()> {
+        match &self.store {
+            Store::Memory(attempts) => {
+                let mut attempts = attempts.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(attempts) = attempts.get_mut(id) {
+                    attempts.locked = true;
+                }
+            }
+            Store::Postgres(pool) => {
+                FailedAttempts::lock(id, pool).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The lock refusing attempts with any of these keys for now, if any
+    fn refused(&self, attempts: &[FailedAttempts], now: DateTime<Utc>) -> Option<Lock> {
+        let mut latest = None;
+        for attempts in attempts {
+            match self.lock(attempts) {
+                Some(Lock::Permanent) => return Some(Lock::Permanent),
+                Some(Lock::Until(until)) if until > now => latest = latest.max(Some(until)),
+                _ => (),
+            }
+        }
+        latest.map(Lock::Until)
+    }
+
+    /// Until when a key is locked, which may be in the past
+    fn lock(&self, attempts: &FailedAttempts) -> Option<Lock> {
+        if attempts.locked {
+            return Some(Lock::Permanent);
+        }
+        let free = if attempts.key.starts_with("ip:") {
+            self.config.free_attempts_per_ip
+        } else {
+            self.config.free_attempts
+        };
+        if attempts.failures <= 0 {
+            return None;
+        }
+        let excess = (attempts.failures as u32).checked_sub(free)?;
+        let delay = self
+            .config
+            .base_delay
+            .saturating_mul(1 << excess.min(MAX_DOUBLINGS))
+            .min(self.config.max_delay);
+        Some(Lock::Until(
+            attempts.last_failure + Duration::seconds(delay),
+        ))
+    }
+}
+
+/// Forgets stale failed attempts in the background
+pub fn spawn(lockouts: &'static Lockouts) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = lockouts.forget_stale().await {
+                tracing::error!("couldn't forget stale failed attempts: {}", e);
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    });
+}
+
+/// Records that failed attempts got a user locked out, and mails them in the background
+/// if they have a verified address
+pub async fn locked_out(
+    user_id: &str,
+    client_id: &str,
+    user_agent: Option<&str>,
+    languages: &[String],
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<()> {
+    AuditEvent::insert(
+        user_id,
+        audit_events::LOCKED_OUT,
+        Some(client_id),
+        user_agent,
+        pool,
+    )
+    .await?;
+
+    let user_id = user_id.to_owned();
+    let languages = languages.to_vec();
+    tokio::spawn(async move {
+        if let Err(e) = send(&user_id, &languages, config, pool).await {
+            tracing::error!("couldn't send lockout notice: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug", skip(config))]
+async fn send(
+    user_id: &str,
+    languages: &[String],
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<()> {
+    let mail_config = match &config.mail {
+        Some(mail_config) => mail_config,
+        None => return Ok(()),
+    };
+    let user = match User::select(user_id, pool).await? {
+        Some(user) if user.email_verified => user,
+        _ => return Ok(()),
+    };
+    let (email, username) = match (&user.email, &user.username) {
+        (Some(email), Some(username)) => (email, username),
+        _ => return Ok(()),
+    };
+
+    let locale = Locale::negotiate(languages, config).await?;
+    mail::send(
+        &Message {
+            to: email,
+            subject: &locale.text("mail.lockout.subject", &[]),
+            body: &locale.text("mail.lockout.body", &[("username", username)]),
+        },
+        mail_config,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockouts(config: LockoutConfig) -> Lockouts {
+        Lockouts {
+            store: Store::Memory(Mutex::new(HashMap::new())),
+            config: Box::leak(Box::new(config)),
+        }
+    }
+
+    fn attempts(key: &str, failures: i32, last_failure: DateTime<Utc>) -> FailedAttempts {
+        FailedAttempts {
+            key: key.to_owned(),
+            failures,
+            last_failure,
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let lockouts = lockouts(LockoutConfig {
+            free_attempts: 3,
+            base_delay: 2,
+            max_delay: 60,
+            ..LockoutConfig::default()
+        });
+        let now = Utc::now();
+        let lock = |failures| lockouts.lock(&attempts("account:a", failures, now));
+        let until = |seconds| Some(Lock::Until(now + Duration::seconds(seconds)));
+
+        assert_eq!(lock(0), None);
+        assert_eq!(lock(2), None);
+        assert_eq!(lock(3), until(2));
+        assert_eq!(lock(4), until(4));
+        assert_eq!(lock(5), until(8));
+        assert_eq!(lock(7), until(32));
+        assert_eq!(lock(8), until(60));
+        assert_eq!(lock(1000), until(60));
+    }
+
+    #[test]
+    fn free_attempts_per_ip() {
+        let lockouts = lockouts(LockoutConfig {
+            free_attempts: 3,
+            free_attempts_per_ip: 10,
+            base_delay: 5,
+            ..LockoutConfig::default()
+        });
+        let now = Utc::now();
+        let until = Some(Lock::Until(now + Duration::seconds(5)));
+
+        assert_eq!(lockouts.lock(&attempts("ip:127.0.0.1", 9, now)), None);
+        assert_eq!(lockouts.lock(&attempts("ip:127.0.0.1", 10, now)), until);
+        assert_eq!(lockouts.lock(&attempts("account:a", 3, now)), until);
+        assert_eq!(lockouts.lock(&attempts("client:c", 3, now)), until);
+    }
+
+    #[tokio::test]
+    async fn attempts_count_before_verification() {
+        let lockouts = lockouts(LockoutConfig {
+            free_attempts: 2,
+            base_delay: 60,
+            ..LockoutConfig::default()
+        });
+        let keys = [Key::Account("a"), Key::Ip("127.0.0.1")];
+
+        // Attempts still being verified count as failed, so parallel guesses are refused too
+        let mut pending = Vec::new();
+        for _ in 0..2 {
+            pending.push(lockouts.record(&keys).await.unwrap().unwrap());
+        }
+        assert!(matches!(
+            lockouts.record(&keys).await.unwrap(),
+            Err(Lock::Until(_))
+        ));
+
+        // Only the first delayed attempt reports the account as locked
+        assert!(!lockouts.fail(&pending[0]).await.unwrap());
+        assert!(lockouts.fail(&pending[1]).await.unwrap());
+
+        // Refused attempts aren't counted, and successful ones are forgotten except for IP addresses
+        lockouts.succeed(&[Key::Account("a")]).await.unwrap();
+        let recorded = lockouts.record(&keys).await.unwrap().unwrap();
+        assert_eq!(recorded.0.len(), 1);
+        assert_eq!(recorded.0[0].failures, 1);
+        if let Store::Memory(attempts) = &lockouts.store {
+            let attempts = attempts.lock().unwrap();
+            assert_eq!(attempts["ip:127.0.0.1"].failures, 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn successes_never_lock() {
+        let lockouts = lockouts(LockoutConfig {
+            free_attempts: 2,
+            free_attempts_per_ip: 2,
+            base_delay: 60,
+            ..LockoutConfig::default()
+        });
+        let ip = Key::Ip("127.0.0.1");
+
+        // A failed attempt stays counted against the IP address through the successes of other accounts
+        let recorded = lockouts
+            .record(&[Key::Account("a"), ip])
+            .await
+            .unwrap()
+            .unwrap();
+        lockouts.fail(&recorded).await.unwrap();
+        for _ in 0..10 {
+            let keys = [Key::Account("b"), ip];
+            lockouts.record(&keys).await.unwrap().unwrap();
+            lockouts.succeed(&keys).await.unwrap();
+        }
+        if let Store::Memory(attempts) = &lockouts.store {
+            let attempts = attempts.lock().unwrap();
+            assert_eq!(attempts["ip:127.0.0.1"].failures, 1);
+        }
+        assert!(lockouts.record(&[ip]).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn permanent_lock() {
+        let lockouts = lockouts(LockoutConfig {
+            free_attempts: 10,
+            permanent_after: Some(3),
+            ..LockoutConfig::default()
+        });
+        let keys = [Key::Mfa("a")];
+
+        for i in 0..3 {
+            let recorded = lockouts.record(&keys).await.unwrap().unwrap();
+            assert_eq!(lockouts.fail(&recorded).await.unwrap(), i == 2);
+        }
+        assert_eq!(
+            lockouts.record(&keys).await.unwrap().unwrap_err(),
+            Lock::Permanent
+        );
+        // Other accounts and the password of the same one aren't affected
+        assert!(lockouts.record(&[Key::Mfa("b")]).await.unwrap().is_ok());
+        assert!(lockouts.record(&[Key::Account("a")]).await.unwrap().is_ok());
+
+        lockouts.unlock("a").await.unwrap();
+        assert!(lockouts.record(&keys).await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+//! OpenID Connect back-channel logout, notifying clients when sessions they took part in end
+//! or when accounts are deleted
+//!
+//! Notifications are queued in the database and delivered by a background task,
+//! which retries failed deliveries with an exponential backoff.
+//! Deletion notifications are logout tokens without a session ID, which log the user out everywhere,
+//! carrying an additional event telling the client to purge the data of the user.
+
+use crate::{
+    clients,
+    config::Config,
+    db::{Client, LogoutDelivery, Session, DELETION, LOGOUT},
+    jwt, HttpClient,
+};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Executor, PgPool, Postgres};
+use std::{net::IpAddr, time};
+use tokio::net;
+use url::{Host, Url};
+
+/// Event identifying a logout token
+const LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+/// Path of the event identifying a deletion token, relative to the root URI
+const DELETION_EVENT_PATH: &str = "/events/account-deleted";
+
+/// Deliveries are given up on after this many failed attempts
+const MAX_ATTEMPTS: i32 = 8;
+/// Delay before the first retry, doubled after every failed attempt
+const RETRY_DELAY: i64 = 30;
+/// How long a delivery stays claimed by a worker before another one can pick it up
+const LEASE: i64 = 5;
+/// Maximum number of deliveries handled at once
+const BATCH_SIZE: i64 = 32;
+/// How often the queue is checked for due deliveries
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// Timeout of a single delivery request
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// Duration for which logout tokens are valid, in minutes, only long enough for the request to go through
+const TOKEN_DURATION: i64 = 2;
+
+#[derive(Debug, Serialize)]
+struct LogoutTokenJwt {
+    iss: String,
+    aud: String,
+    sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sid: Option<String>,
+    jti: String,
+    events: serde_json::Value,
+}
+
+impl jwt::Purpose for LogoutTokenJwt {
+    const PURPOSE: &'static str = "logout";
+    const PUBLIC: bool = true;
+}
+
+/// Queues notifications for every client the session took part in
+#[tracing::instrument(level = "debug")]
+pub async fn notify(session: &Session, pool: &PgPool) -> Result<()> {
+    LogoutDelivery::queue(
+        &session.clients,
+        &session.user_id,
+        Some(&session.id),
+        LOGOUT,
+        pool,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Queues deletion notifications for the given clients
+#[tracing::instrument(level = "debug", skip(executor))]
+pub async fn notify_deletion<'e, E>(user_id: &str, client_ids: &[String], executor: E) -> Result<()>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    LogoutDelivery::queue(client_ids, user_id, None, DELETION, executor).await?;
+    Ok(())
+}
+
+/// Ends every session of a user, notifying the clients they took part in
+#[tracing::instrument(level = "debug")]
+pub async fn end_all(user_id: &str, pool: &PgPool) -> Result<()> {
+    for session in Session::delete_by_user(user_id, pool).await? {
+        notify(&session, pool).await?;
+    }
+    Ok(())
+}
+
+/// Starts the background task delivering queued notifications
+pub fn spawn(config: &'static Config, http_client: &'static HttpClient, pool: &'static PgPool) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = deliver_due(config, http_client, pool).await {
+                tracing::error!("couldn't deliver logout notifications: {}", e);
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[tracing::instrument(level = "debug", skip(http_client))]
+async fn deliver_due(
+    config: &'static Config,
+    http_client: &'static HttpClient,
+    pool: &'static PgPool,
+) -> Result<()> {
+    let deliveries = LogoutDelivery::claim_due(BATCH_SIZE, Duration::minutes(LEASE), pool).await?;
+
+    for delivery in deliveries {
+        match deliver(&delivery, config, http_client, pool).await {
+            Ok(()) => {
+                LogoutDelivery::mark_delivered(delivery.id, pool).await?;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "logout notification {} to {} failed: {}",
+                    delivery.id,
+                    delivery.client_id,
+                    e
+                );
+                let attempts = delivery.attempts + 1;
+                let next_attempt_at = if attempts < MAX_ATTEMPTS {
+                    Some(Utc::now() + Duration::seconds(RETRY_DELAY << (attempts - 1)))
+                } else {
+                    None
+                };
+                LogoutDelivery::mark_attempt_failed(
+                    delivery.id,
+                    &e.to_string(),
+                    next_attempt_at,
+                    pool,
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends a logout token to the back-channel logout URL of the client
+/// URLs of dynamically registered clients have to resolve to public addresses, and redirects aren't followed.
+async fn deliver(
+    delivery: &LogoutDelivery,
+    config: &'static Config,
+    http_client: &'static HttpClient,
+    pool: &PgPool,
+) -> Result<()> {
+    let client = Client::select(&delivery.client_id, pool)
+        .await?
+        .ok_or_else(|| anyhow!("client no longer exists"))?;
+    let url = client
+        .backchannel_logout_url
+        .ok_or_else(|| anyhow!("client no longer has a back-channel logout URL"))?;
+    if client.registration_token.is_some() {
+        resolves_to_public(&url).await?;
+    }
+
+    let token = LogoutTokenJwt {
+        iss: config.root_uri.clone(),
+        aud: delivery.client_id.clone(),
+        sub: delivery.user_id.clone(),
+        sid: delivery.session_id.clone(),
+        jti: OsRng.sample_iter(&Alphanumeric).take(32).collect(),
+        events: if delivery.event == DELETION {
+            json!({
+                LOGOUT_EVENT: {},
+                format!("{}{}", config.root_uri, DELETION_EVENT_PATH): {},
+            })
+        } else {
+            json!({ LOGOUT_EVENT: {} })
+        },
+    };
+    let token = jwt::encode_for(token, Duration::minutes(TOKEN_DURATION), &config.token).await?;
+
+    let response = http_client
+        .post(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .header("Cache-Control", "no-store")
+        .form(&[("logout_token", token)])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("client responded with {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Makes sure every address the host of a URL resolves to is public,
+/// so anonymous clients can't have Vaulth send requests to itself or its internal network
+async fn resolves_to_public(url: &str) -> Result<()> {
+    let url = Url::parse(url)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let ips: Vec<IpAddr> = match url.host() {
+        Some(Host::Domain(domain)) => net::lookup_host((domain, port))
+            .await?
+            .map(|addr| addr.ip())
+            .collect(),
+        Some(Host::Ipv4(ip)) => vec![ip.into()],
+        Some(Host::Ipv6(ip)) => vec![ip.into()],
+        None => Vec::new(),
+    };
+    match ips.iter().find(|ip| !clients::public_ip(**ip)) {
+        Some(ip) => Err(anyhow!("{} resolves to the non-public address {}", url, ip)),
+        None if ips.is_empty() => Err(anyhow!("{} doesn't resolve to any address", url)),
+        None => Ok(()),
+    }
+}
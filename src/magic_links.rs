@@ -0,0 +1,192 @@
+//! Passwordless login through single-use links mailed to users
+//!
+//! Links only work in the browser they were requested from: requesting one sets a cookie naming it,
+//! so a link forwarded to someone else, or intercepted on its way, doesn't log them in.
+//! Only the latest link requested from a browser works there.
+
+use crate::{
+    config::{Config, MagicLinkConfig, MailConfig},
+    db::{MagicLink, User},
+    jwt,
+    mail::{self, Message},
+    password,
+    providers::Params,
+    sessions,
+    templates::Locale,
+};
+use anyhow::Result;
+use chrono::Duration;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use url::form_urlencoded;
+
+/// Name of the cookie binding links to the browser they were requested from
+pub const COOKIE: &str = "vaulth_magic_link";
+
+/// Length of generated link IDs
+const ID_LEN: usize = 24;
+/// Length of the secret part of links
+const SECRET_LEN: usize = 32;
+
+/// Signed content of the binding cookie
+#[derive(Debug, Serialize, Deserialize)]
+struct BindingJwt {
+    magic_link: String,
+}
+
+impl jwt::Purpose for BindingJwt {
+    const PURPOSE: &'static str = "magic_link_binding";
+}
+
+/// Link waiting to be mailed
+#[derive(Debug)]
+struct Pending {
+    id: String,
+    email: String,
+    params: Params,
+    locale: Locale,
+    duration: Duration,
+}
+
+/// Why a link can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalid {
+    /// The link doesn't exist, expired or was already used
+    Unknown,
+    /// The link was requested from another browser
+    OtherBrowser,
+}
+
+/// Mails a login link to the owner of an address in the background, if there is one,
+/// returning the `Set-Cookie` header value binding it to the browser
+///
+/// A cookie is returned and the mail is sent in the background whether the address is known or not,
+/// so addresses can't be probed.
+pub async fn request(
+    email: String,
+    params: Params,
+    locale: Locale,
+    link_config: &'static MagicLinkConfig,
+    mail_config: &'static MailConfig,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<String> {
+    let id: String = OsRng.sample_iter(&Alphanumeric).take(ID_LEN).collect();
+    let duration = Duration::minutes(link_config.duration);
+    let binding = jwt::encode_for(
+        BindingJwt {
+            magic_link: id.clone(),
+        },
+        duration,
+        &config.token,
+    )
+    .await?;
+
+    let pending = Pending {
+        id,
+        email,
+        params,
+        locale,
+        duration,
+    };
+    tokio::spawn(async move {
+        if let Err(e) = send(&pending, mail_config, config, pool).await {
+            tracing::error!("couldn't send login link: {}", e);
+        }
+    });
+    Ok(sessions::cookie_header(
+        COOKIE,
+        &binding,
+        duration.num_seconds(),
+        config,
+    ))
+}
+
+#[tracing::instrument(level = "debug", skip(mail_config, config))]
+async fn send(
+    pending: &Pending,
+    mail_config: &MailConfig,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<()> {
+    // Only verified addresses are looked up
+    let user = match User::select_by_email(&pending.email, pool).await? {
+        Some(user) if user.active() => user,
+        _ => return Ok(()),
+    };
+    MagicLink::delete_expired(pool).await?;
+
+    let secret: String = OsRng.sample_iter(&Alphanumeric).take(SECRET_LEN).collect();
+    let hash = password::hash(&secret, &config.hash).await?;
+    MagicLink::insert(
+        &pending.id,
+        &user.id,
+        &hash,
+        &serde_json::to_string(&pending.params)?,
+        pending.duration,
+        pool,
+    )
+    .await?;
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", &format!("{}.{}", pending.id, secret))
+        .finish();
+    let link = format!("{}/magic-link?{}", config.root_uri, query);
+
+    mail::send(
+        &Message {
+            to: &pending.email,
+            subject: &pending.locale.text("mail.magic_link.subject", &[]),
+            body: &pending
+                .locale
+                .text("mail.magic_link.body", &[("link", &link)]),
+        },
+        mail_config,
+    )
+    .await
+}
+
+/// Uses up a link followed from the browser holding the binding cookie,
+/// returning it along with the authorization request the user started
+#[tracing::instrument(level = "debug", skip(token, cookie))]
+pub async fn redeem(
+    token: &str,
+    cookie: Option<String>,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<Result<(MagicLink, Params), Invalid>> {
+    let mut parts = token.splitn(2, '.');
+    let (id, secret) = match (parts.next(), parts.next()) {
+        (Some(id), Some(secret)) => (id, secret),
+        _ => return Ok(Err(Invalid::Unknown)),
+    };
+    let link = match MagicLink::select_valid(id, pool).await? {
+        Some(link) => link,
+        None => return Ok(Err(Invalid::Unknown)),
+    };
+    if !password::verify(link.secret.clone(), secret, &config.hash).await? {
+        return Ok(Err(Invalid::Unknown));
+    }
+
+    // The link is left usable so its actual owner can still follow it
+    let binding: Option<BindingJwt> = match cookie {
+        Some(cookie) => jwt::decode(cookie, &config.token).await?,
+        None => None,
+    };
+    if binding.map_or(true, |b| b.magic_link != link.id) {
+        return Ok(Err(Invalid::OtherBrowser));
+    }
+
+    let link = match MagicLink::consume(&link.id, pool).await? {
+        Some(link) => link,
+        None => return Ok(Err(Invalid::Unknown)),
+    };
+    let params = serde_json::from_str(&link.params)?;
+    Ok(Ok((link, params)))
+}
+
+/// Returns a `Set-Cookie` header value removing the binding cookie, once its link was used
+pub fn clear_cookie(config: &Config) -> String {
+    sessions::cookie_header(COOKIE, "", 0, config)
+}
@@ -0,0 +1,354 @@
+//! Delivery of outgoing mail through the configured transport
+
+use crate::config::{MailConfig, MailTransport, SmtpTls};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use std::{path::Path, process::Stdio, sync::Arc};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    process::Command,
+};
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+/// Length of the random part of message IDs
+const MESSAGE_ID_LEN: usize = 24;
+
+/// Plain text message to a single recipient
+#[derive(Debug)]
+pub struct Message<'a> {
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub body: &'a str,
+}
+
+/// Addresses and contents of a formatted message
+struct Envelope<'a> {
+    from: &'a str,
+    to: &'a str,
+    data: &'a str,
+}
+
+/// Sends a message, only returning once the transport accepted it
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn send(message: &Message<'_>, config: &MailConfig) -> Result<()> {
+    let data = compose(message, config)?;
+    let envelope = Envelope {
+        from: address(&config.from),
+        to: message.to,
+        data: &data,
+    };
+
+    match &config.transport {
+        MailTransport::Smtp {
+            host,
+            port,
+            username,
+            password,
+            tls,
+        } => {
+            let credentials = username.as_deref().zip(password.as_deref());
+            smtp(&envelope, host, *port, credentials, *tls).await
+        }
+        MailTransport::File { path } => file(&envelope, path.as_deref()).await,
+        MailTransport::Command { program, args } => command(&envelope, program, args).await,
+    }
+}
+
+/// Formats a message as described by RFC 5322
+fn compose(message: &Message, config: &MailConfig) -> Result<String> {
+    // Header values come from users and could otherwise add headers of their own
+    if message
+        .to
+        .chars()
+        .chain(message.subject.chars())
+        .any(|c| c == '\r' || c == '\n')
+    {
+        bail!("line break in mail header");
+    }
+
+    let domain = address(&config.from).rsplit('@').next().unwrap_or_default();
+    let id: String = OsRng
+        .sample_iter(&Alphanumeric)
+        .take(MESSAGE_ID_LEN)
+        .collect();
+    let mut data = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        config.from,
+        message.to,
+        encode_header(message.subject),
+        Utc::now().to_rfc2822(),
+        id,
+        domain,
+    );
+    for line in message.body.lines() {
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    Ok(data)
+}
+
+/// Encodes a header value as described by RFC 2047 unless it is plain ASCII
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_owned()
+    } else {
+        format!("=?utf-8?B?{}?=", base64::encode(value))
+    }
+}
+
+/// Extracts the address out of a mailbox like `Name <address>`
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+async fn smtp(
+    envelope: &Envelope<'_>,
+    host: &str,
+    port: Option<u16>,
+    credentials: Option<(&str, &str)>,
+    tls: SmtpTls,
+) -> Result<()> {
+    let port = port.unwrap_or(match tls {
+        SmtpTls::None => 25,
+        SmtpTls::StartTls => 587,
+        SmtpTls::Implicit => 465,
+    });
+    let stream = TcpStream::connect((host, port)).await?;
+
+    match tls {
+        SmtpTls::None => {
+            let mut session = Smtp::new(stream);
+            session.reply(220).await?;
+            session.deliver(envelope, credentials).await
+        }
+        SmtpTls::StartTls => {
+            let mut session = Smtp::new(stream);
+            session.reply(220).await?;
+            session.ehlo(envelope).await?;
+            session.command("STARTTLS", 220).await?;
+
+            let stream = connector()
+                .connect(DNSNameRef::try_from_ascii_str(host)?, session.into_inner())
+                .await?;
+            Smtp::new(stream).deliver(envelope, credentials).await
+        }
+        SmtpTls::Implicit => {
+            let stream = connector()
+                .connect(DNSNameRef::try_from_ascii_str(host)?, stream)
+                .await?;
+            let mut session = Smtp::new(stream);
+            session.reply(220).await?;
+            session.deliver(envelope, credentials).await
+        }
+    }
+}
+
+fn connector() -> TlsConnector {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Minimal SMTP client session, as described by RFC 5321
+struct Smtp<S> {
+    stream: BufReader<S>,
+}
+
+impl<S> Smtp<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    /// Authenticates if needed then transfers the message, the server greeting must have been read
+    async fn deliver(
+        &mut self,
+        envelope: &Envelope<'_>,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<()> {
+        self.ehlo(envelope).await?;
+        if let Some((username, password)) = credentials {
+            let token = base64::encode(format!("\0{}\0{}", username, password));
+            self.command(&format!("AUTH PLAIN {}", token), 235).await?;
+        }
+
+        self.command(&format!("MAIL FROM:<{}>", envelope.from), 250)
+            .await?;
+        self.command(&format!("RCPT TO:<{}>", envelope.to), 250)
+            .await?;
+        self.command("DATA", 354).await?;
+        self.command(&format!("{}.", stuff_dots(envelope.data)), 250)
+            .await?;
+        self.command("QUIT", 221).await
+    }
+
+    async fn ehlo(&mut self, envelope: &Envelope<'_>) -> Result<()> {
+        let domain = envelope.from.rsplit('@').next().unwrap_or_default();
+        self.command(&format!("EHLO {}", domain), 250).await
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        self.reply(expected).await
+    }
+
+    /// Reads a possibly multiline reply, failing unless it has the expected code
+    async fn reply(&mut self, expected: u16) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                bail!("SMTP connection closed");
+            }
+            let code: u16 = line
+                .get(..3)
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(|| anyhow!("invalid SMTP reply: {}", line.trim_end()))?;
+
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            if code != expected {
+                bail!("unexpected SMTP reply: {}", line.trim_end());
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Escapes lines starting with a dot by doubling it, so they don't end the SMTP data early
+fn stuff_dots(data: &str) -> String {
+    let data = data.replace("\r\n.", "\r\n..");
+    if data.starts_with('.') {
+        format!(".{}", data)
+    } else {
+        data
+    }
+}
+
+/// Appends the message to a file, or prints it if no file is configured
+async fn file(envelope: &Envelope<'_>, path: Option<&Path>) -> Result<()> {
+    match path {
+        Some(path) => {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(envelope.data.as_bytes()).await?;
+            file.write_all(b"\r\n").await?;
+            file.flush().await?;
+        }
+        None => println!("{}", envelope.data),
+    }
+    Ok(())
+}
+
+/// Writes the message to the standard input of a command
+async fn command(envelope: &Envelope<'_>, program: &str, args: &[String]) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("couldn't write to mail command"))?;
+    stdin.write_all(envelope.data.as_bytes()).await?;
+    drop(stdin);
+
+    let status = child.await?;
+    if !status.success() {
+        bail!("mail command failed with {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MailConfig {
+        MailConfig {
+            from: "Vaulth <noreply@example.com>".to_owned(),
+            verification_duration: 60,
+            transport: MailTransport::File { path: None },
+        }
+    }
+
+    fn message<'a>(to: &'a str, subject: &'a str, body: &'a str) -> Message<'a> {
+        Message { to, subject, body }
+    }
+
+    #[test]
+    fn header_injection() {
+        let config = config();
+        let injected = [
+            message("user@example.com\r\nBcc: victim@example.com", "Hi", "Body"),
+            message("user@example.com\nBcc: victim@example.com", "Hi", "Body"),
+            message("user@example.com", "Hi\r\nBcc: victim@example.com", "Body"),
+            message("user@example.com", "Hi\rBcc: victim@example.com", "Body"),
+        ];
+        for message in &injected {
+            assert!(compose(message, &config).is_err(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn compose_headers() {
+        let data = compose(&message("user@example.com", "Hi", "Body"), &config()).unwrap();
+        let (headers, body) = data.split_at(data.find("\r\n\r\n").unwrap());
+        assert!(headers.starts_with("From: Vaulth <noreply@example.com>\r\n"));
+        assert!(headers.contains("\r\nTo: user@example.com\r\n"));
+        assert!(headers.contains("\r\nSubject: Hi\r\n"));
+        assert!(headers.contains("@example.com>\r\n"));
+        assert_eq!(body, "\r\n\r\nBody\r\n");
+
+        let data = compose(&message("user@example.com", "Héllo", "Body"), &config()).unwrap();
+        assert!(data.contains("\r\nSubject: =?utf-8?B?SMOpbGxv?=\r\n"));
+    }
+
+    #[test]
+    fn dot_stuffing() {
+        let data = compose(
+            &message("user@example.com", "Hi", "First\n.\n.hidden\nLast"),
+            &config(),
+        )
+        .unwrap();
+        let stuffed = stuff_dots(&data);
+        assert!(stuffed.ends_with("\r\nFirst\r\n..\r\n..hidden\r\nLast\r\n"));
+        // A lone dot would otherwise end the data before the rest of the body
+        assert!(!stuffed.contains("\r\n.\r\n"));
+
+        assert_eq!(stuff_dots(".start\r\n"), "..start\r\n");
+        assert_eq!(stuff_dots("no dots\r\n"), "no dots\r\n");
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(
+            address("Vaulth <noreply@example.com>"),
+            "noreply@example.com"
+        );
+        assert_eq!(address(" noreply@example.com "), "noreply@example.com");
+    }
+}
@@ -1,18 +1,44 @@
 // lol
 #![type_length_limit = "2077914"]
 
+mod accounts;
+mod acr;
+mod clients;
 mod config;
 mod db;
+mod emails;
 mod errors;
+mod exports;
+mod html;
+mod ids;
+mod import;
 mod jwt;
+mod legacy_hashes;
+mod limits;
+mod lockouts;
+mod logout;
+mod magic_links;
+mod mail;
+mod mfa;
 mod password;
+mod password_policy;
+mod password_resets;
+mod pkce;
+mod profile;
 mod providers;
+mod redirect;
 mod routes;
+mod sessions;
+mod templates;
+mod totp;
+mod usernames;
+mod webauthn;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use config::Config;
+use lockouts::Lockouts;
 use providers::oauth::SharedResources;
-use reqwest::Client as HttpClient;
+use reqwest::{redirect::Policy, Client as HttpClient, ClientBuilder as HttpClientBuilder};
 use sqlx::PgPool;
 use std::env;
 use tracing_subscriber::EnvFilter;
@@ -37,14 +63,35 @@ async fn main() -> Result<()> {
         .with_env_filter(EnvFilter::from_env(LOG_ENV_VAR))
         .init();
 
+    jwt::check_secret_key(&config.token).await?;
+    if let Some(totp_config) = &config.totp {
+        totp::check_key(totp_config)?;
+    }
     let pool = pool(config).await?;
+    usernames::refresh_skeletons(pool).await?;
+    usernames::backfill(config, pool).await?;
+
+    // Commands run once instead of serving requests
+    let mut args = env::args().skip(2);
+    match (args.next().as_deref(), args.next()) {
+        (None, _) => (),
+        (Some("import"), Some(path)) => return import::run(&path, config, pool).await,
+        _ => return Err(anyhow!("usage: vaulth [config] [import <file>]")),
+    }
+
+    clients::seed(config, pool).await?;
     let client = client(&config).await?;
+    logout::spawn(config, logout_client(config).await?, pool);
+    accounts::spawn(pool);
+    let lockouts: &'static Lockouts = Box::leak(Box::new(Lockouts::new(&config.lockout, pool)));
+    lockouts::spawn(lockouts);
 
     let shared = SharedResources {
         config: None,
         global_config: config,
         http_client: client,
         pool,
+        lockouts,
     };
 
     let routes = (providers::discord::handler(SharedResources {
@@ -55,13 +102,32 @@ async fn main() -> Result<()> {
         config: config.github.as_ref(),
         ..shared
     })?)
-    .or(routes::token::handler(config, pool))
-    .or(routes::users::handler(config, pool))
+    .or(providers::google::handler(SharedResources {
+        config: config.google.as_ref(),
+        ..shared
+    })?)
+    .or(routes::authorize::handler(shared))
+    .or(routes::admin::handler(config, pool, lockouts))
+    .or(routes::clients::handler(config, pool))
+    .or(routes::consent::handler(config, pool))
+    .or(routes::magic_links::handler(shared))
+    .or(routes::mfa::handler(shared))
+    .or(routes::emails::handler(config, pool))
+    .or(routes::exports::handler(config, pool))
+    .or(routes::passkeys::handler(config, pool, lockouts))
+    .or(routes::passwords::handler(config, pool))
+    .or(routes::register::handler(config, pool))
+    .or(routes::sessions::handler(config, pool))
+    .or(routes::signup::handler(shared))
+    .or(routes::token::handler(config, pool, lockouts))
+    .or(routes::totp::handler(config, pool, lockouts))
+    .or(routes::users::handler(config, pool, lockouts))
     .or(routes::key::handler(&config.token));
 
     serve(
         routes
             .recover(errors::handle_redirects)
+            .recover(move |err| errors::handle_pages(err, config))
             .recover(errors::handle_json)
             .with(warp::trace::request()),
         &config,
@@ -89,22 +155,24 @@ async fn pool(config: &Config) -> Result<&'static PgPool> {
 
 #[tracing::instrument(level = "debug")]
 async fn client(config: &Config) -> Result<&'static HttpClient> {
-    let client = HttpClient::builder()
-        .user_agent(
-            config
-                .user_agent
-                .as_ref()
-                .map(AsRef::as_ref)
-                .unwrap_or(concat!(
-                    env!("CARGO_PKG_NAME"),
-                    "/",
-                    env!("CARGO_PKG_VERSION")
-                )),
-        )
-        .build()?;
+    let client = client_builder(config).build()?;
     Ok(&*Box::leak(Box::new(client)))
 }
 
+/// Client sending back-channel logout tokens, which doesn't follow redirects
+/// so clients can't use them to reach hosts their URL was checked not to point to
+#[tracing::instrument(level = "debug")]
+async fn logout_client(config: &Config) -> Result<&'static HttpClient> {
+    let client = client_builder(config).redirect(Policy::none()).build()?;
+    Ok(&*Box::leak(Box::new(client)))
+}
+
+fn client_builder(config: &Config) -> HttpClientBuilder {
+    HttpClient::builder().user_agent(config.user_agent.as_ref().map(AsRef::as_ref).unwrap_or(
+        concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+    ))
+}
+
 async fn serve(
     filter: impl Filter<Extract = (impl Reply,)> + Send + Sync + Clone + 'static,
     config: &Config,
@@ -0,0 +1,44 @@
+//! Second factors users are asked for after authenticating with a first one
+
+use crate::{
+    config::Config,
+    db::{User, WebAuthnCredential},
+    providers::{Authentication, AMR_HARDWARE_KEY},
+};
+use anyhow::Result;
+use sqlx::PgPool;
+
+/// Second factors a user set up
+#[derive(Debug)]
+pub struct Factors {
+    /// Whether the user set up an authenticator app
+    pub totp: bool,
+    /// Passkeys of the user, unless they already used one to authenticate
+    pub passkeys: Vec<WebAuthnCredential>,
+}
+
+impl Factors {
+    /// Lists the factors a user can complete an authentication with
+    #[tracing::instrument(level = "debug", skip(user, config))]
+    pub async fn of(
+        user: &User,
+        auth: &Authentication,
+        config: &Config,
+        pool: &PgPool,
+    ) -> Result<Self> {
+        let passkeys = if config.webauthn.is_some() && !auth.used(AMR_HARDWARE_KEY) {
+            WebAuthnCredential::select_by_user(&user.id, pool).await?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            totp: user.totp_enabled(),
+            passkeys,
+        })
+    }
+
+    /// Whether the user has to complete their authentication with a second factor
+    pub fn any(&self) -> bool {
+        self.totp || !self.passkeys.is_empty()
+    }
+}
@@ -1,40 +1,43 @@
-use crate::config::HashConfig;
+use crate::{config::HashConfig, legacy_hashes};
 use anyhow::{anyhow, Result};
 use argon2::{Config, ThreadMode, Variant, Version};
 use rand::{rngs::OsRng, Rng};
 use tokio::task;
 
-/// Hashes a password
-#[tracing::instrument(level = "debug")]
-pub async fn hash(password: &str, config: &'static HashConfig) -> Result<String> {
-    let password = password.as_bytes().to_vec();
+/// Length of salts unless configured otherwise
+const DEFAULT_SALT_LEN: usize = 16;
 
-    let mut argon_config = Config {
-        variant: Variant::Argon2i,
-        version: Version::Version13,
-        ..Default::default()
-    };
-    if let Some(hash_len) = config.hash_len {
-        argon_config.hash_length = hash_len;
-    }
-    if let Some(lanes) = config.lanes {
-        argon_config.lanes = lanes;
-        if lanes > 1 {
-            argon_config.thread_mode = ThreadMode::Parallel;
-        }
-    }
-    if let Some(mem_cost) = config.mem_cost {
-        argon_config.mem_cost = mem_cost;
-    }
-    if let Some(time_cost) = config.time_cost {
-        argon_config.time_cost = time_cost;
+/// Outcome of checking a password against a hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// Valid, but hashed with a previous secret or settings, so it should be hashed again
+    Outdated,
+}
+
+impl Verification {
+    pub fn valid(self) -> bool {
+        self != Self::Invalid
     }
+}
+
+/// Whether a hash can be verified, either made by Vaulth or imported from another system
+pub fn recognized(hash: &str) -> bool {
+    hash.starts_with("$argon2") || legacy_hashes::recognized(hash)
+}
+
+/// Hashes a password with Argon2id
+#[tracing::instrument(level = "debug", skip(password, config))]
+pub async fn hash(password: &str, config: &'static HashConfig) -> Result<String> {
+    let password = password.as_bytes().to_vec();
+    let argon_config = argon_config(config);
 
     Ok(task::spawn_blocking(move || {
         hash_sync(
             password,
             argon_config,
-            config.salt_len.unwrap_or(16),
+            config.salt_len.unwrap_or(DEFAULT_SALT_LEN),
             config.secret.as_ref().map(String::as_bytes).unwrap_or(&[]),
         )
     })
@@ -61,13 +64,118 @@ fn hash_sync<'a>(
     Ok(hash)
 }
 
+/// Takes as long as verifying a password would, for when there is no hash to verify it against,
+/// so callers can't tell whether there was one
+#[tracing::instrument(level = "debug", skip(password, config))]
+pub async fn verify_nothing(password: &str, config: &'static HashConfig) -> Result<()> {
+    hash(password, config).await?;
+    Ok(())
+}
+
 /// Verifies a password hash
-#[tracing::instrument(level = "debug")]
-pub async fn verify(hash: String, password: &str, secret: &str) -> Result<bool> {
+#[tracing::instrument(level = "debug", skip(hash, password, config))]
+pub async fn verify(hash: String, password: &str, config: &HashConfig) -> Result<bool> {
+    Ok(check(hash, password, config).await?.valid())
+}
+
+/// Verifies a password hash, telling whether it should be replaced by a new one
+/// Hashes made with one of the previous secrets still verify, so secrets can be rotated
+/// without locking everyone out, and so do hashes imported from other systems.
+#[tracing::instrument(level = "debug", skip(hash, password, config))]
+pub async fn check(hash: String, password: &str, config: &HashConfig) -> Result<Verification> {
+    if legacy_hashes::recognized(&hash) {
+        let password = password.to_owned();
+        let valid = task::spawn_blocking(move || legacy_hashes::verify(&hash, &password)).await??;
+        return Ok(if valid {
+            Verification::Outdated
+        } else {
+            Verification::Invalid
+        });
+    }
+
     let password = password.as_bytes().to_vec();
-    let secret = secret.as_bytes().to_vec();
-    Ok(task::spawn_blocking(move || verify_sync(hash, password, secret)).await??)
+    let current = config
+        .secret
+        .as_deref()
+        .unwrap_or_default()
+        .as_bytes()
+        .to_vec();
+    let previous: Vec<Vec<u8>> = config
+        .previous_secrets
+        .iter()
+        .map(|s| s.as_bytes().to_vec())
+        .collect();
+    let outdated = outdated(&hash, config);
+
+    let verification = task::spawn_blocking(move || -> Result<Verification> {
+        if verify_sync(&hash, &password, &current)? {
+            return Ok(Verification::Valid);
+        }
+        for secret in &previous {
+            if verify_sync(&hash, &password, secret)? {
+                return Ok(Verification::Outdated);
+            }
+        }
+        Ok(Verification::Invalid)
+    })
+    .await??;
+
+    Ok(match verification {
+        Verification::Valid if outdated => Verification::Outdated,
+        verification => verification,
+    })
+}
+fn verify_sync(hash: &str, password: &[u8], secret: &[u8]) -> Result<bool> {
+    Ok(argon2::verify_encoded_ext(hash, password, secret, &[])?)
 }
-fn verify_sync(hash: String, password: Vec<u8>, secret: Vec<u8>) -> Result<bool> {
-    Ok(argon2::verify_encoded_ext(&hash, &password, &secret, &[])?)
+
+/// Argon2 settings passwords are currently hashed with
+fn argon_config(config: &HashConfig) -> Config<'static> {
+    let mut argon_config = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        ..Default::default()
+    };
+    if let Some(hash_len) = config.hash_len {
+        argon_config.hash_length = hash_len;
+    }
+    if let Some(lanes) = config.lanes {
+        argon_config.lanes = lanes;
+        if lanes > 1 {
+            argon_config.thread_mode = ThreadMode::Parallel;
+        }
+    }
+    if let Some(mem_cost) = config.mem_cost {
+        argon_config.mem_cost = mem_cost;
+    }
+    if let Some(time_cost) = config.time_cost {
+        argon_config.time_cost = time_cost;
+    }
+    argon_config
+}
+
+/// Whether a hash was made with another variant or other settings than the current ones
+/// Hashes look like `$argon2id$v=19$m=4096,t=3,p=1$<salt>$<hash>`, in unpadded base64.
+fn outdated(hash: &str, config: &HashConfig) -> bool {
+    let current = argon_config(config);
+    let parts: Vec<&str> = hash.split('$').collect();
+    let (variant, version, params, salt, hash) = match parts.as_slice() {
+        ["", variant, version, params, salt, hash] => (*variant, *version, *params, *salt, *hash),
+        _ => return true,
+    };
+
+    let expected_params = format!(
+        "m={},t={},p={}",
+        current.mem_cost, current.time_cost, current.lanes
+    );
+    variant != current.variant.as_lowercase_str()
+        || version != format!("v={}", current.version.as_u32())
+        || params != expected_params
+        || encoded_len(salt) != config.salt_len.unwrap_or(DEFAULT_SALT_LEN)
+        || encoded_len(hash) != current.hash_length as usize
+}
+
+/// Length of the data encoded in unpadded base64
+fn encoded_len(encoded: &str) -> usize {
+    encoded.len() * 3 / 4
 }
@@ -0,0 +1,256 @@
+//! Rules new passwords must follow
+//!
+//! Passwords are checked for their length, against a local list of breached passwords, then for
+//! their strength as estimated by zxcvbn, which also gives hints to pick a better one.
+
+use crate::{config::PasswordConfig, html};
+use anyhow::{anyhow, Result};
+use ring::digest;
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::Path,
+};
+use tokio::task;
+
+/// Maximum length of passwords, in bytes, whatever the configured length in characters
+const MAX_LEN: usize = 1024;
+
+/// Why a password can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    TooShort,
+    TooLong,
+    Breached,
+    TooWeak,
+}
+
+impl Rejected {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::TooShort => "password too short",
+            Self::TooLong => "password too long",
+            Self::Breached => "password breached",
+            Self::TooWeak => "password too weak",
+        }
+    }
+
+    /// Key of the message shown on hosted pages
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::TooShort => "password.too_short",
+            Self::TooLong => "password.too_long",
+            Self::Breached => "password.breached",
+            Self::TooWeak => "password.too_weak",
+        }
+    }
+}
+
+/// Why a password was rejected, along with hints to pick a better one
+#[derive(Debug, Serialize)]
+pub struct Feedback {
+    #[serde(rename = "error", serialize_with = "serialize_reason")]
+    pub rejected: Rejected,
+    /// Strength of the password from 0 to 4, once estimated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<String>,
+}
+
+impl Feedback {
+    fn new(rejected: Rejected) -> Self {
+        Self {
+            rejected,
+            score: None,
+            warning: None,
+            suggestions: Vec::new(),
+        }
+    }
+
+    /// Warning and suggestions, to show along with the error
+    pub fn hints(&self) -> Vec<String> {
+        self.warning
+            .iter()
+            .chain(&self.suggestions)
+            .cloned()
+            .collect()
+    }
+}
+
+/// List of hints shown below errors on hosted pages
+pub fn hints_html(hints: &[String]) -> String {
+    if hints.is_empty() {
+        return String::new();
+    }
+    let items: String = hints
+        .iter()
+        .map(|hint| format!("<li>{}</li>", html::escape(hint)))
+        .collect();
+    format!(r#"<ul class="hints">{}</ul>"#, items)
+}
+
+fn serialize_reason<S: serde::Serializer>(
+    rejected: &Rejected,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(rejected.reason())
+}
+
+/// Checks a new password against the configured policy, returning its strength if it is accepted
+/// User inputs are the username and other details of the user, which make passwords easier to guess.
+#[tracing::instrument(level = "debug", skip(password))]
+pub async fn validate(
+    password: &str,
+    user_inputs: &[&str],
+    config: &PasswordConfig,
+) -> Result<Result<u8, Feedback>> {
+    let length = password.chars().count();
+    if password.len() > MAX_LEN || length > config.max_length {
+        return Ok(Err(Feedback::new(Rejected::TooLong)));
+    }
+    if length < config.min_length {
+        return Ok(Err(Feedback::new(Rejected::TooShort)));
+    }
+
+    if let Some(path) = &config.breached_passwords {
+        let path = path.clone();
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        let hash: String = hash.as_ref().iter().map(|b| format!("{:02X}", b)).collect();
+        if task::spawn_blocking(move || breached(&path, &hash)).await?? {
+            return Ok(Err(Feedback::new(Rejected::Breached)));
+        }
+    }
+
+    let entropy = zxcvbn::zxcvbn(password, user_inputs)
+        .map_err(|e| anyhow!("couldn't estimate password strength: {:?}", e))?;
+    if entropy.score() < config.min_score {
+        let (warning, suggestions) = match entropy.feedback() {
+            Some(feedback) => (
+                feedback.warning().map(|w| w.to_string()),
+                feedback
+                    .suggestions()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+        return Ok(Err(Feedback {
+            rejected: Rejected::TooWeak,
+            score: Some(entropy.score()),
+            warning,
+            suggestions,
+        }));
+    }
+    Ok(Ok(entropy.score()))
+}
+
+/// Whether the uppercase SHA-1 hash of a password is in the breached passwords file
+///
+/// Lines are sorted by hash, which may be followed by `:` and a count like in the downloads of
+/// Have I Been Pwned, so the file is binary searched in place rather than loaded in memory.
+fn breached(path: &Path, hash: &str) -> Result<bool> {
+    let mut file = BufReader::new(File::open(path)?);
+    // Lines starting before `low` are known to be lower than the hash, those starting at or after
+    // `high` to be higher
+    let (mut low, mut high) = (0, file.get_ref().metadata()?.len());
+    let mut line = Vec::new();
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+
+        // Find the first line starting at or after the middle
+        let mut start = mid;
+        if mid > 0 {
+            file.seek(SeekFrom::Start(mid - 1))?;
+            line.clear();
+            start = mid - 1 + file.read_until(b'\n', &mut line)? as u64;
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+        }
+        if start >= high {
+            high = mid;
+            continue;
+        }
+        line.clear();
+        let end = start + file.read_until(b'\n', &mut line)? as u64;
+
+        let entry = line
+            .split(|b| *b == b':' || b.is_ascii_whitespace())
+            .next()
+            .unwrap_or_default();
+        match compare(entry, hash.as_bytes()) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = end,
+            Ordering::Greater => high = mid,
+        }
+    }
+    Ok(false)
+}
+
+/// Compares hexadecimal hashes regardless of case
+fn compare(a: &[u8], b: &[u8]) -> Ordering {
+    a.iter()
+        .map(u8::to_ascii_uppercase)
+        .cmp(b.iter().map(u8::to_ascii_uppercase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn sha1(password: &str) -> String {
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        hash.as_ref().iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    /// Writes a file of breached passwords formatted like the downloads of Have I Been Pwned
+    fn file(name: &str, passwords: &[String]) -> PathBuf {
+        let mut hashes: Vec<String> = passwords.iter().map(|p| sha1(p)).collect();
+        hashes.sort();
+        let content: String = hashes
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| format!("{}:{}\r\n", hash, i + 1))
+            .collect();
+        let path = env::temp_dir().join(format!("vaulth-{}-{}.txt", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn breached_file() {
+        let passwords: Vec<String> = (0..100).map(|i| format!("password{}", i)).collect();
+        let path = file("breached", &passwords);
+        for password in &passwords {
+            assert!(breached(&path, &sha1(password)).unwrap(), "{}", password);
+            assert!(breached(&path, &sha1(password).to_lowercase()).unwrap());
+        }
+        for i in 0..100 {
+            assert!(!breached(&path, &sha1(&format!("unbreached{}", i))).unwrap());
+        }
+        assert!(!breached(&path, &"0".repeat(40)).unwrap());
+        assert!(!breached(&path, &"F".repeat(40)).unwrap());
+        // Prefixes of a hash aren't matches
+        assert!(!breached(&path, &sha1("password0")[..39]).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn small_files() {
+        let path = file("empty", &[]);
+        assert!(!breached(&path, &sha1("password")).unwrap());
+        fs::remove_file(path).unwrap();
+
+        let path = file("single", &["password".to_owned()]);
+        assert!(breached(&path, &sha1("password")).unwrap());
+        assert!(!breached(&path, &sha1("other")).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! Password resets through single-use links mailed to users who forgot their password
+//!
+//! Links carry the ID of the reset and a secret, only a hash of which is stored,
+//! so a leaked database can't be used to take over accounts.
+
+use crate::{
+    config::{Config, MailConfig},
+    db::{audit_events, AuditEvent, PasswordReset, User},
+    logout,
+    mail::{self, Message},
+    password,
+    templates::Locale,
+};
+use anyhow::Result;
+use chrono::Duration;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use sqlx::PgPool;
+use url::form_urlencoded;
+
+/// Length of generated reset IDs
+const ID_LEN: usize = 24;
+/// Length of the secret part of reset links
+const SECRET_LEN: usize = 32;
+
+/// Mails a reset link to the owner of an address in the background, if there is one
+///
+/// Requests for unknown addresses are silently ignored, and the mail being sent in the background
+/// keeps response times the same, so addresses can't be probed.
+pub fn request(
+    email: String,
+    locale: Locale,
+    mail_config: &'static MailConfig,
+    config: &'static Config,
+    pool: &'static PgPool,
+) {
+    tokio::spawn(async move {
+        if let Err(e) = send(&email, &locale, mail_config, config, pool).await {
+            tracing::error!("couldn't send password reset: {}", e);
+        }
+    });
+}
+
+#[tracing::instrument(level = "debug", skip(mail_config, config))]
+async fn send(
+    email: &str,
+    locale: &Locale,
+    mail_config: &MailConfig,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<()> {
+    // Only verified addresses are looked up, and users without a password log in with a provider
+    let user = match User::select_by_email(email, pool).await? {
+        Some(user) if user.password.is_some() && user.active() => user,
+        _ => return Ok(()),
+    };
+
+    let id: String = OsRng.sample_iter(&Alphanumeric).take(ID_LEN).collect();
+    let secret: String = OsRng.sample_iter(&Alphanumeric).take(SECRET_LEN).collect();
+    let hash = password::hash(&secret, &config.hash).await?;
+    PasswordReset::insert(
+        &id,
+        &user.id,
+        &hash,
+        Duration::minutes(config.password.reset_duration),
+        pool,
+    )
+    .await?;
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", &format!("{}.{}", id, secret))
+        .append_pair("ui_locales", &locale.language)
+        .finish();
+    let link = format!("{}/password/reset?{}", config.root_uri, query);
+
+    mail::send(
+        &Message {
+            to: email,
+            subject: &locale.text("mail.reset.subject", &[]),
+            body: &locale.text("mail.reset.body", &[("link", &link)]),
+        },
+        mail_config,
+    )
+    .await
+}
+
+/// Looks up the reset a link was sent for, if it can still be used
+#[tracing::instrument(level = "debug", skip(token))]
+pub async fn check(
+    token: &str,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<Option<PasswordReset>> {
+    let mut parts = token.splitn(2, '.');
+    let (id, secret) = match (parts.next(), parts.next()) {
+        (Some(id), Some(secret)) => (id, secret),
+        _ => return Ok(None),
+    };
+    let reset = match PasswordReset::select_valid(id, pool).await? {
+        Some(reset) => reset,
+        None => return Ok(None),
+    };
+
+    if password::verify(reset.secret.clone(), secret, &config.hash).await? {
+        Ok(Some(reset))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Sets the new password of the user and ends all their sessions, which whoever knew
+/// the old password could have started, returning `false` if the link was used in the meantime
+#[tracing::instrument(level = "debug", skip(password, config))]
+pub async fn complete(
+    reset: &PasswordReset,
+    password: &str,
+    user_agent: Option<&str>,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<bool> {
+    let hash = password::hash(password, &config.hash).await?;
+    if PasswordReset::consume(&reset.id, pool).await?.is_none() {
+        return Ok(false);
+    }
+
+    User::update_password(&reset.user_id, &hash, pool).await?;
+    PasswordReset::delete_unused(&reset.user_id, pool).await?;
+    logout::end_all(&reset.user_id, pool).await?;
+    AuditEvent::insert(
+        &reset.user_id,
+        audit_events::PASSWORD_RESET,
+        None,
+        user_agent,
+        pool,
+    )
+    .await?;
+    Ok(true)
+}
@@ -0,0 +1,72 @@
+//! Proof Key for Code Exchange (RFC 7636), binding codes to the client instance that requested them
+//!
+//! Public clients have no secret to authenticate with when redeeming a code, so anyone intercepting
+//! it could exchange it for a token. They send the hash of a random verifier along with the
+//! authorization request instead, and the verifier itself along with the code.
+
+use ring::{constant_time, digest};
+
+/// Only method supported, `plain` giving away the verifier along with the authorization request
+pub const METHOD: &str = "S256";
+
+/// Checks whether a verifier or challenge is 43 to 128 unreserved characters,
+/// a challenge always being the 43 characters of an encoded SHA-256 hash
+fn valid(value: &str) -> bool {
+    (43..=128).contains(&value.len())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~'))
+}
+
+/// Checks whether a challenge sent with an authorization request is well formed
+pub fn valid_challenge(challenge: &str) -> bool {
+    challenge.len() == 43 && valid(challenge)
+}
+
+/// Checks whether a verifier sent along with a code hashes to the challenge the code was issued for
+pub fn verify(verifier: &str, challenge: &str) -> bool {
+    if !valid(verifier) {
+        return false;
+    }
+    let hash = digest::digest(&digest::SHA256, verifier.as_bytes());
+    let hash = base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD);
+    constant_time::verify_slices_are_equal(hash.as_bytes(), challenge.as_bytes()).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn rfc7636_vector() {
+        assert!(valid_challenge(CHALLENGE));
+        assert!(verify(VERIFIER, CHALLENGE));
+    }
+
+    #[test]
+    fn wrong_verifiers() {
+        assert!(!verify(CHALLENGE, CHALLENGE));
+        assert!(!verify(&VERIFIER[1..], CHALLENGE));
+        assert!(!verify("", CHALLENGE));
+        // The plain method would send the verifier as the challenge
+        assert!(!verify(VERIFIER, VERIFIER));
+        // Too short to carry enough entropy, even if it matches
+        assert!(!verify(
+            "abc",
+            "ungWv48Bz-pBQUDeXa4iI7ADYaOWF3qctBD_YfIAFa0"
+        ));
+    }
+
+    #[test]
+    fn malformed_challenges() {
+        assert!(!valid_challenge(""));
+        assert!(!valid_challenge(&CHALLENGE[1..]));
+        assert!(!valid_challenge(&format!("{}A", CHALLENGE)));
+        assert!(!valid_challenge(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw/+cM"
+        ));
+    }
+}
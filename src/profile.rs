@@ -0,0 +1,142 @@
+//! Validation of the profile fields users can edit
+
+use crate::config::ProfileConfig;
+use unicode_normalization::UnicodeNormalization;
+
+/// Scope clients need to read the profile of users
+pub const SCOPE: &str = "profile";
+
+/// Maximum length of names, matching the database column
+const NAME_MAX_LEN: usize = 64;
+/// Maximum length of about sections
+const ABOUT_MAX_LEN: usize = 1024;
+
+struct Field {
+    max_len: usize,
+    multiline: bool,
+    too_long: &'static str,
+    invalid: &'static str,
+    blocked: &'static str,
+}
+
+const NAME: Field = Field {
+    max_len: NAME_MAX_LEN,
+    multiline: false,
+    too_long: "name too long",
+    invalid: "invalid characters in name",
+    blocked: "inappropriate name",
+};
+
+const ABOUT: Field = Field {
+    max_len: ABOUT_MAX_LEN,
+    multiline: true,
+    too_long: "about too long",
+    invalid: "invalid characters in about",
+    blocked: "inappropriate about",
+};
+
+/// Normalizes a name, blank names being removed
+pub fn name(value: &str, config: &ProfileConfig) -> Result<Option<String>, &'static str> {
+    normalize(value, &NAME, config)
+}
+
+/// Normalizes an about section, blank ones being removed
+pub fn about(value: &str, config: &ProfileConfig) -> Result<Option<String>, &'static str> {
+    normalize(value, &ABOUT, config)
+}
+
+fn normalize(
+    value: &str,
+    field: &Field,
+    config: &ProfileConfig,
+) -> Result<Option<String>, &'static str> {
+    let value: String = value.replace("\r\n", "\n").trim().nfc().collect();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    if value.chars().count() > field.max_len {
+        return Err(field.too_long);
+    }
+    if value
+        .chars()
+        .any(|c| c.is_control() && !(field.multiline && c == '\n'))
+    {
+        return Err(field.invalid);
+    }
+    if blocked(&value, config) {
+        return Err(field.blocked);
+    }
+    Ok(Some(value))
+}
+
+/// Checks a value against the configured blocked words,
+/// ignoring case and compatibility variants of characters
+pub fn blocked(value: &str, config: &ProfileConfig) -> bool {
+    if config.blocked_words.is_empty() {
+        return false;
+    }
+
+    let folded: String = value.nfkc().flat_map(char::to_lowercase).collect();
+    let words: Vec<&str> = folded
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    config
+        .blocked_words
+        .iter()
+        .map(|b| b.to_lowercase())
+        .any(|b| words.contains(&b.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(blocked_words: &[&str]) -> ProfileConfig {
+        ProfileConfig {
+            blocked_words: blocked_words.iter().map(|w| (*w).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn names() {
+        let config = config(&[]);
+        assert_eq!(name("  Ada  ", &config), Ok(Some("Ada".to_owned())));
+        assert_eq!(name(" \n ", &config), Ok(None));
+        // Decomposed accents are composed, so lengths and blocked words compare as users see them
+        assert_eq!(name("Zoe\u{301}", &config), Ok(Some("Zoé".to_owned())));
+        assert_eq!(name(&"é".repeat(64), &config), Ok(Some("é".repeat(64))));
+        assert_eq!(name(&"é".repeat(65), &config), Err("name too long"));
+        assert_eq!(
+            name("Ada\nLovelace", &config),
+            Err("invalid characters in name")
+        );
+        assert_eq!(name("Ada\u{7}", &config), Err("invalid characters in name"));
+    }
+
+    #[test]
+    fn abouts() {
+        let config = config(&[]);
+        assert_eq!(
+            about("First line\r\nSecond line\n", &config),
+            Ok(Some("First line\nSecond line".to_owned()))
+        );
+        assert_eq!(
+            about("Tab\tted", &config),
+            Err("invalid characters in about")
+        );
+        assert_eq!(about(&"a".repeat(1025), &config), Err("about too long"));
+    }
+
+    #[test]
+    fn blocked_words() {
+        let config = config(&["Badword"]);
+        assert!(blocked("a badword here", &config));
+        assert!(blocked("BADWORD!", &config));
+        // Compatibility variants like fullwidth letters are folded
+        assert!(blocked("ｂａｄｗｏｒｄ", &config));
+        assert!(!blocked("badwords", &config));
+        assert_eq!(name("Badword", &config), Err("inappropriate name"));
+    }
+}
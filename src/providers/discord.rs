@@ -1,4 +1,7 @@
-use crate::providers::oauth::{self, ProviderInfo, SharedResources};
+use crate::providers::{
+    oauth::{self, ProviderInfo, SharedResources},
+    Identity,
+};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply};
@@ -11,12 +14,12 @@ fn redirect_uri(root: &str) -> String {
 
 fn uri_fn(client_id: &str, root: &str, state: &str) -> String {
     format!(
-        "https://discord.com/api/oauth2/authorize?response_type=code&scope=identify&prompt=none&client_id={}&redirect_uri={}&state={}",
+        "https://discord.com/api/oauth2/authorize?response_type=code&scope=identify%20email&prompt=none&client_id={}&redirect_uri={}&state={}",
         client_id, redirect_uri(&root), state,
     )
 }
 
-async fn id_fn(code: String, _: String, shared: SharedResources) -> Result<String> {
+async fn id_fn(code: String, _: String, shared: SharedResources) -> Result<Identity> {
     #[derive(Serialize)]
     struct TokenRequest<'a> {
         client_id: &'static str,
@@ -35,6 +38,9 @@ async fn id_fn(code: String, _: String, shared: SharedResources) -> Result<Strin
     #[derive(Deserialize)]
     struct UserResponse {
         id: String,
+        email: Option<String>,
+        #[serde(default)]
+        verified: bool,
     }
 
     let config = shared.config.context("unsupported provider")?;
@@ -48,7 +54,7 @@ async fn id_fn(code: String, _: String, shared: SharedResources) -> Result<Strin
             grant_type: "authorization_code",
             code: &code,
             redirect_uri: &redirect_uri(&shared.global_config.root_uri),
-            scope: "identity",
+            scope: "identify email",
         })
         .send()
         .await?
@@ -56,17 +62,20 @@ async fn id_fn(code: String, _: String, shared: SharedResources) -> Result<Strin
         .await?
         .access_token;
 
-    let id = shared
+    let user = shared
         .http_client
         .get("https://discord.com/api/v6/users/@me")
         .bearer_auth(token)
         .send()
         .await?
         .json::<UserResponse>()
-        .await?
-        .id;
+        .await?;
 
-    Ok(id)
+    let verified = user.verified;
+    Ok(Identity {
+        id: user.id,
+        email: user.email.map(|e| (e, verified)),
+    })
 }
 
 pub fn handler(
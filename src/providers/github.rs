@@ -1,4 +1,7 @@
-use crate::providers::oauth::{self, ProviderInfo, SharedResources};
+use crate::providers::{
+    oauth::{self, ProviderInfo, SharedResources},
+    Identity,
+};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply};
@@ -11,14 +14,14 @@ fn redirect_uri(root: &str) -> String {
 
 fn uri_fn(client_id: &str, root: &str, state: &str) -> String {
     format!(
-        "https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}&state={}",
+        "https://github.com/login/oauth/authorize?scope=user:email&client_id={}&redirect_uri={}&state={}",
         client_id,
         redirect_uri(root),
         state,
     )
 }
 
-async fn id_fn(code: String, state: String, shared: SharedResources) -> Result<String> {
+async fn id_fn(code: String, state: String, shared: SharedResources) -> Result<Identity> {
     #[derive(Serialize)]
     struct TokenRequest<'a> {
         client_id: &'static str,
@@ -38,6 +41,13 @@ async fn id_fn(code: String, state: String, shared: SharedResources) -> Result<S
         id: i32,
     }
 
+    #[derive(Deserialize)]
+    struct EmailResponse {
+        email: String,
+        primary: bool,
+        verified: bool,
+    }
+
     let config = shared.config.context("unsupported provider")?;
 
     let token = shared
@@ -59,7 +69,7 @@ async fn id_fn(code: String, state: String, shared: SharedResources) -> Result<S
     let id = shared
         .http_client
         .get("https://api.github.com/user")
-        .bearer_auth(token)
+        .bearer_auth(&token)
         .header("Accept", "application/vnd.github.v3+json")
         .send()
         .await?
@@ -68,7 +78,21 @@ async fn id_fn(code: String, state: String, shared: SharedResources) -> Result<S
         .id
         .to_string();
 
-    Ok(id)
+    // The public email of the profile may not be verified, use the primary one instead
+    let email = shared
+        .http_client
+        .get("https://api.github.com/user/emails")
+        .bearer_auth(&token)
+        .header("Accept", "application/vnd.github.v3+json")
+        .send()
+        .await?
+        .json::<Vec<EmailResponse>>()
+        .await?
+        .into_iter()
+        .find(|e| e.primary)
+        .map(|e| (e.email, e.verified));
+
+    Ok(Identity { id, email })
 }
 
 pub fn handler(
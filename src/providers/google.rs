@@ -1 +1,91 @@
+use crate::providers::{
+    oauth::{self, ProviderInfo, SharedResources},
+    Identity,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Rejection, Reply};
 
+const NAME: &str = "google";
+
+fn redirect_uri(root: &str) -> String {
+    format!("{}/{}-r", root, NAME)
+}
+
+fn uri_fn(client_id: &str, root: &str, state: &str) -> String {
+    format!(
+        "https://accounts.google.com/o/oauth2/v2/auth?response_type=code&scope=openid%20email&client_id={}&redirect_uri={}&state={}",
+        client_id, redirect_uri(&root), state,
+    )
+}
+
+async fn id_fn(code: String, _: String, shared: SharedResources) -> Result<Identity> {
+    #[derive(Serialize)]
+    struct TokenRequest<'a> {
+        client_id: &'static str,
+        client_secret: &'static str,
+        grant_type: &'static str,
+        code: &'a str,
+        redirect_uri: &'a str,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+    }
+
+    #[derive(Deserialize)]
+    struct UserResponse {
+        sub: String,
+        email: Option<String>,
+        #[serde(default)]
+        email_verified: bool,
+    }
+
+    let config = shared.config.context("unsupported provider")?;
+
+    let token = shared
+        .http_client
+        .post("https://oauth2.googleapis.com/token")
+        .form(&TokenRequest {
+            client_id: &config.client_id,
+            client_secret: &config.client_secret,
+            grant_type: "authorization_code",
+            code: &code,
+            redirect_uri: &redirect_uri(&shared.global_config.root_uri),
+        })
+        .send()
+        .await?
+        .json::<TokenResponse>()
+        .await?
+        .access_token;
+
+    let user = shared
+        .http_client
+        .get("https://openidconnect.googleapis.com/v1/userinfo")
+        .bearer_auth(token)
+        .send()
+        .await?
+        .json::<UserResponse>()
+        .await?;
+
+    let verified = user.email_verified;
+    Ok(Identity {
+        id: user.sub,
+        email: user.email.map(|e| (e, verified)),
+    })
+}
+
+pub fn handler(
+    shared: SharedResources,
+) -> Result<impl Filter<Extract = (impl Reply,), Error = Rejection> + Send + Sync + Clone + 'static>
+{
+    oauth::handler(
+        ProviderInfo {
+            name: NAME,
+            uri_fn,
+            id_fn,
+        },
+        shared,
+    )
+}
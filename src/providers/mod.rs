@@ -7,7 +7,25 @@ pub mod twitter;
 
 pub mod oauth;
 
+use crate::jwt;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+/// Name of the pseudo provider used for users logging in with their Vaulth password
+pub const LOCAL: &str = "local";
+/// Name of the pseudo provider used for users logging in with a link mailed to them
+pub const EMAIL: &str = "email";
+/// Name of the pseudo provider used for users logging in with a passkey
+pub const WEBAUTHN: &str = "webauthn";
+
+/// Authentication methods reported to clients in the `amr` claim, as described by RFC 8176
+pub const AMR_PASSWORD: &str = "pwd";
+pub const AMR_EMAIL: &str = "email";
+pub const AMR_FEDERATED: &str = "fed";
+pub const AMR_OTP: &str = "otp";
+pub const AMR_HARDWARE_KEY: &str = "hwk";
+pub const AMR_MFA: &str = "mfa";
 
 /// Query parameters coming from the client
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,16 +34,217 @@ pub struct Params {
     pub redirect_uri: String,
     pub state: Option<String>,
     pub token: Option<String>,
+    /// Space separated list of requested scopes
+    pub scope: Option<String>,
+    /// How the response is sent back to the client, either `query`, `fragment` or `form_post`
+    pub response_mode: Option<String>,
+    /// Either `none` to fail unless the user is already logged in, or `login` to force a new login
+    pub prompt: Option<String>,
+    /// Maximum time since the user last actively authenticated, in seconds
+    pub max_age: Option<i64>,
+    /// Space separated list of languages preferred by the user for the hosted pages
+    pub ui_locales: Option<String>,
+    /// Space separated list of authentication levels the client accepts, the lowest one being required
+    pub acr_values: Option<String>,
+    /// Hash of the PKCE verifier the client sends along with the code, required for public clients
+    pub code_challenge: Option<String>,
+    /// How the PKCE challenge was derived from the verifier, only `S256` is supported
+    pub code_challenge_method: Option<String>,
+}
+
+impl jwt::Purpose for Params {
+    const PURPOSE: &'static str = "authorization";
+}
+
+impl Params {
+    /// Encodes the parameters back into a query string, to hand them off to a provider handler
+    pub fn to_query(&self) -> String {
+        let max_age = self.max_age.map(|m| m.to_string());
+        let optional = [
+            ("state", self.state.as_deref()),
+            ("token", self.token.as_deref()),
+            ("scope", self.scope.as_deref()),
+            ("response_mode", self.response_mode.as_deref()),
+            ("prompt", self.prompt.as_deref()),
+            ("max_age", max_age.as_deref()),
+            ("ui_locales", self.ui_locales.as_deref()),
+            ("acr_values", self.acr_values.as_deref()),
+            ("code_challenge", self.code_challenge.as_deref()),
+            (
+                "code_challenge_method",
+                self.code_challenge_method.as_deref(),
+            ),
+        ];
+        form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .extend_pairs(
+                optional
+                    .iter()
+                    .filter_map(|(name, value)| value.map(|v| (name, v))),
+            )
+            .finish()
+    }
+}
+
+/// User as known by a provider
+#[derive(Debug)]
+pub struct Identity {
+    pub id: String,
+    /// Email address of the user, along with whether the provider verified it
+    pub email: Option<(String, bool)>,
+}
+
+/// User authenticated by Vaulth, on their way back to the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Authentication {
+    pub provider_name: String,
+    pub provider_id: String,
+    pub user_id: Option<String>,
+    /// Last time the user actively authenticated, as a Unix timestamp
+    pub auth_time: i64,
+    /// Vaulth session the user is logged in with, if any
+    pub session_id: Option<String>,
+    /// Methods the user authenticated with
+    #[serde(default)]
+    pub amr: Vec<String>,
+}
+
+impl Authentication {
+    /// Authentication that just happened with a provider, before the user is logged in to Vaulth
+    pub fn new(provider_name: &str, provider_id: &str, user_id: Option<&str>) -> Self {
+        let method = match provider_name {
+            LOCAL => AMR_PASSWORD,
+            EMAIL => AMR_EMAIL,
+            WEBAUTHN => AMR_HARDWARE_KEY,
+            _ => AMR_FEDERATED,
+        };
+        Self {
+            provider_name: provider_name.to_owned(),
+            provider_id: provider_id.to_owned(),
+            user_id: user_id.map(str::to_owned),
+            auth_time: Utc::now().timestamp(),
+            session_id: None,
+            amr: vec![method.to_owned()],
+        }
+    }
+
+    /// Records that the user also authenticated with a second factor
+    pub fn add_factor(&mut self, method: &str) {
+        for method in &[method, AMR_MFA] {
+            if !self.used(method) {
+                self.amr.push((*method).to_owned());
+            }
+        }
+    }
+
+    /// Whether the user authenticated with more than one factor
+    pub fn multi_factor(&self) -> bool {
+        self.used(AMR_MFA)
+    }
+
+    /// Whether the user authenticated with a method
+    pub fn used(&self, method: &str) -> bool {
+        self.amr.iter().any(|m| m == method)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CodeJwt {
+    /// Random ID of the code, forgotten once the code is redeemed so it can't be redeemed again
+    pub jti: String,
     pub provider_name: String,
     pub provider_id: String,
     pub client_id: String,
+    /// Space separated list of granted scopes
+    pub scope: String,
+    pub auth_time: i64,
+    #[serde(default)]
+    pub amr: Vec<String>,
+    /// PKCE challenge sent with the authorization request, if any
+    pub code_challenge: Option<String>,
+}
+
+impl jwt::Purpose for CodeJwt {
+    const PURPOSE: &'static str = "code";
+}
+
+/// Pending authorization waiting for the user to approve the requested scopes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsentJwt {
+    pub params: Params,
+    pub auth: Authentication,
+}
+
+impl jwt::Purpose for ConsentJwt {
+    const PURPOSE: &'static str = "consent";
+}
+
+/// Pending login waiting for the user to enter their second factor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaJwt {
+    pub params: Params,
+    pub pending_auth: Authentication,
+}
+
+impl jwt::Purpose for MfaJwt {
+    const PURPOSE: &'static str = "mfa";
+}
+
+/// Pending signup of a user who isn't registered yet,
+/// along with the identity they authenticated with unless they are signing up with a password
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignupJwt {
+    pub params: Params,
+    pub auth: Option<Authentication>,
+    /// Verified email address returned by the provider, if any
+    pub email: Option<String>,
+}
+
+impl jwt::Purpose for SignupJwt {
+    const PURPOSE: &'static str = "signup";
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenJwt {
     pub sub: String,
+    /// Client the token was issued to
+    pub client_id: String,
+    /// Space separated list of granted scopes
+    pub scope: String,
+    pub auth_time: i64,
+    /// Level the user authenticated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    /// Methods the user authenticated with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<String>,
+    /// Only included when the `email` scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+impl jwt::Purpose for TokenJwt {
+    const PURPOSE: &'static str = "access";
+    const PUBLIC: bool = true;
+}
+
+impl TokenJwt {
+    /// Whether the client was granted a scope
+    pub fn granted(&self, scope: &str) -> bool {
+        self.scope.split(' ').any(|s| s == scope)
+    }
+}
+
+/// Splits a space separated scope string, ignoring duplicates
+pub fn split_scope(scope: &str) -> Vec<String> {
+    let mut scopes: Vec<String> = Vec::new();
+    for s in scope.split(' ').filter(|s| !s.is_empty()) {
+        if !scopes.iter().any(|e| e == s) {
+            scopes.push(s.to_owned());
+        }
+    }
+    scopes
 }
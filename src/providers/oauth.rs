@@ -1,16 +1,35 @@
 use crate::{
+    acr,
     config::{Config, OAuth2Config},
-    db::User,
-    errors::TryExt,
+    db::{AuthorizationCode, Client, Consent, Session, User},
+    emails,
+    errors::{OAuthError, TryExt},
     jwt,
-    providers::{CodeJwt, Params},
-    HttpClient,
+    lockouts::Lockouts,
+    mfa::Factors,
+    pkce,
+    providers::{self, Authentication, CodeJwt, ConsentJwt, Identity, MfaJwt, Params, SignupJwt},
+    redirect::{self, ResponseMode},
+    routes, sessions, templates, HttpClient,
 };
+use chrono::{Duration, Utc};
 use derivative::Derivative;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use serde::Deserialize;
 use sqlx::PgPool;
 use std::future::Future;
-use warp::{http::uri::Uri, Filter, Rejection, Reply};
+use warp::{
+    http::{header, uri::Uri, HeaderValue, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// Duration for which users can enter their second factor after authenticating, in minutes
+const MFA_DURATION: i64 = 10;
+/// Duration for which clients can redeem a code, in minutes
+const CODE_DURATION: i64 = 10;
+/// Length of the random IDs making codes single-use
+const CODE_ID_LEN: usize = 32;
 
 /// Information about a standard OAuth2 provider
 #[derive(Derivative)]
@@ -21,7 +40,7 @@ pub struct ProviderInfo<IdFnRet> {
     /// Function used to start building the auth URI
     #[derivative(Debug = "ignore")]
     pub uri_fn: fn(&str, &str, &str) -> String,
-    /// Function used to obtain an ID from a provider
+    /// Function used to obtain the identity of the user from a provider
     #[derivative(Debug = "ignore")]
     pub id_fn: fn(String, String, SharedResources) -> IdFnRet,
 }
@@ -47,6 +66,8 @@ pub struct SharedResources {
     pub http_client: &'static HttpClient,
     #[derivative(Debug = "ignore")]
     pub pool: &'static PgPool,
+    #[derivative(Debug = "ignore")]
+    pub lockouts: &'static Lockouts,
 }
 
 /// Generates a filter handling everything for a single provider
@@ -57,42 +78,49 @@ pub fn handler<IdFnRet>(
     impl Filter<Extract = (impl Reply,), Error = Rejection> + Send + Sync + Clone + 'static,
 >
 where
-    IdFnRet: Future<Output = anyhow::Result<String>> + Send + 'static,
+    IdFnRet: Future<Output = anyhow::Result<Identity>> + Send + 'static,
 {
     tracing::debug!("generating {} handlers", provider.name);
 
     let first_handler = warp::path::path(provider.name)
         .and(warp::path::end())
         .and(warp::query::query())
-        .and_then(move |query: Params| first_handler(query, provider, shared));
+        .and(warp::cookie::optional(sessions::COOKIE))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |query: Params, session: Option<String>, accept_language: Option<String>| {
+                first_handler(query, session, accept_language, provider, shared)
+            },
+        );
 
     let second_handler = warp::path::path(format!("{}-r", provider.name))
         .and(warp::path::end())
         .and(warp::query::query())
-        .and_then(move |query: RedirectParams| second_handler(query, provider, shared));
+        .and(warp::header::optional("User-Agent"))
+        .and_then(move |query: RedirectParams, user_agent: Option<String>| {
+            second_handler(query, user_agent, provider, shared)
+        });
 
     Ok(first_handler.or(second_handler))
 }
 
 /// This is where the user is redirected by the client
-/// The handler translates and stores important info, then redirects the user to the provider
-#[tracing::instrument]
+/// The handler translates and stores important info, then redirects the user to the provider,
+/// unless the user is already logged in to Vaulth in which case it skips straight to the client
+#[tracing::instrument(skip(session))]
 async fn first_handler<IdFnRet>(
-    query: Params,
+    mut query: Params,
+    session: Option<String>,
+    accept_language: Option<String>,
     provider: ProviderInfo<IdFnRet>,
     shared: SharedResources,
-) -> Result<impl Reply, Rejection> {
-    // Verify the infos are valid
-    let client = shared
-        .global_config
-        .clients
-        .get(&query.client_id)
-        .or_redirect("invalid client_id", &query)?;
-    client
-        .redirect_urls
-        .iter()
-        .find(|u| query.redirect_uri.starts_with(*u))
-        .or_redirect("invalid redirect_uri", &query)?;
+) -> Result<Response, Rejection> {
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+    let client = validate(&mut query, &languages, shared.pool).await?;
+
+    if let Some(response) = resume(&query, session, &client, shared).await? {
+        return Ok(response);
+    }
 
     // Encode the client id and redirect url in the state that will be sent to the provider
     // Required to know where to forward info from the provider
@@ -105,15 +133,144 @@ async fn first_handler<IdFnRet>(
     let uri = (provider.uri_fn)(
         &shared
             .config
-            .or_redirect("unsupported provider", &query)?
+            .or_redirect(OAuthError::InvalidRequest, "unsupported provider", &query)?
             .client_id,
         &shared.global_config.root_uri,
         &state,
     );
 
-    Ok(warp::redirect::temporary(
-        finish_auth_uri(&uri, &state).or_ise()?,
-    ))
+    Ok(warp::redirect::temporary(finish_auth_uri(&uri, &state).or_ise()?).into_response())
+}
+
+/// Makes sure the client exists and is allowed to receive the requested response,
+/// filling in the default scopes if none were requested
+pub async fn validate(
+    query: &mut Params,
+    languages: &[String],
+    pool: &'static PgPool,
+) -> Result<Client, Rejection> {
+    // Verify the infos are valid
+    // The user can't be redirected to the client until its redirect_uri is known to be legitimate
+    let client = Client::select_enabled(&query.client_id, pool)
+        .await
+        .or_ise()?
+        .or_page("invalid_client", StatusCode::BAD_REQUEST, languages)?;
+    if !redirect::matches(&client.redirect_urls, &query.redirect_uri) {
+        None.or_page("invalid_redirect_uri", StatusCode::BAD_REQUEST, languages)?;
+    }
+
+    ResponseMode::parse(query.response_mode.as_deref()).or_redirect(
+        OAuthError::InvalidRequest,
+        "unsupported response_mode",
+        query,
+    )?;
+
+    // Public clients have no secret, so codes are bound to the instance that requested them instead
+    match (
+        &query.code_challenge,
+        query.code_challenge_method.as_deref(),
+    ) {
+        (Some(challenge), Some(pkce::METHOD)) => {
+            if !pkce::valid_challenge(challenge) {
+                None.or_redirect(OAuthError::InvalidRequest, "invalid code_challenge", query)?;
+            }
+        }
+        (Some(_), _) | (None, Some(_)) => {
+            None.or_redirect(
+                OAuthError::InvalidRequest,
+                "unsupported code_challenge_method",
+                query,
+            )?;
+        }
+        (None, None) => {
+            if client.secret.is_none() {
+                None.or_redirect(
+                    OAuthError::InvalidRequest,
+                    "code_challenge required for public clients",
+                    query,
+                )?;
+            }
+        }
+    }
+
+    // Make sure the client is allowed every requested scope, defaulting to all of them
+    let scopes = match &query.scope {
+        Some(scope) => providers::split_scope(scope),
+        None => client.scopes.clone(),
+    };
+    if scopes.iter().any(|s| !client.scopes.contains(s)) {
+        None.or_redirect(
+            OAuthError::InvalidScope,
+            "scope not allowed for client",
+            query,
+        )?;
+    }
+    // Whatever was stored in the database, only first-party clients from the config file can act as admins
+    if scopes.iter().any(|s| s == routes::ADMIN_SCOPE) && !(client.seeded && client.trusted) {
+        None.or_redirect(
+            OAuthError::InvalidScope,
+            "scope not allowed for client",
+            query,
+        )?;
+    }
+    query.scope = Some(scopes.join(" "));
+
+    Ok(client)
+}
+
+/// Skips straight back to the client if the user is already logged in to Vaulth,
+/// unless the client asks for a fresh login or requires a second factor they didn't enter yet
+pub async fn resume(
+    query: &Params,
+    session: Option<String>,
+    client: &Client,
+    shared: SharedResources,
+) -> Result<Option<Response>, Rejection> {
+    // Reuse the existing session unless the client asks for a fresh login
+    let prompt = query.prompt.as_deref();
+    if !matches!(prompt, None | Some("none") | Some("login")) {
+        None.or_redirect(OAuthError::InvalidRequest, "unsupported prompt", query)?;
+    }
+    if prompt != Some("login") {
+        let session = sessions::current(session, shared.global_config, shared.pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", query)?;
+        let max_age = query.max_age;
+        let session = session.filter(|s| {
+            max_age.map_or(true, |max_age| {
+                (Utc::now() - s.auth_time).num_seconds() <= max_age
+            })
+        });
+
+        if let Some(session) = session {
+            let auth = Authentication {
+                provider_name: session.provider_name,
+                provider_id: session.provider_id,
+                user_id: Some(session.user_id),
+                auth_time: session.auth_time.timestamp(),
+                session_id: Some(session.id),
+                amr: session.amr,
+            };
+            if acr::satisfies(&auth.amr, acr::required(query, client)) {
+                return authorize(query, auth, client, shared).await.map(Some);
+            }
+
+            // The session isn't strong enough for this client, so the user has to step up
+            if prompt == Some("none") {
+                None.or_redirect(
+                    OAuthError::InteractionRequired,
+                    "second factor required",
+                    query,
+                )?;
+            }
+            return log_in(query, auth, client, None, shared).await.map(Some);
+        }
+    }
+    if prompt == Some("none") {
+        None.or_redirect(OAuthError::LoginRequired, "user isn't logged in", query)?;
+    }
+
+    Ok(None)
 }
 
 /// Redirect query parameters from a standard OAuth2 provider
@@ -131,11 +288,12 @@ enum RedirectParams {
 #[tracing::instrument]
 async fn second_handler<IdFnRet>(
     query: RedirectParams,
+    user_agent: Option<String>,
     provider: ProviderInfo<IdFnRet>,
     shared: SharedResources,
-) -> Result<impl Reply, Rejection>
+) -> Result<Response, Rejection>
 where
-    IdFnRet: Future<Output = anyhow::Result<String>> + Send + 'static,
+    IdFnRet: Future<Output = anyhow::Result<Identity>> + Send + 'static,
 {
     // Try to extract the initial query params from the state returned by the provider
     let (code, state) = match query {
@@ -146,12 +304,11 @@ where
                 .await
                 .or_ise()?
                 .or_ise()?;
-            let uri = Uri::from_maybe_shared(match &params.state {
-                Some(s) => format!("{}?error={}&state={}", params.redirect_uri, error, s),
-                None => format!("{}?error={}", params.redirect_uri, error),
-            })
-            .or_ise()?;
-            return Ok(warp::redirect::temporary(uri));
+            let code = match error.as_str() {
+                "access_denied" => OAuthError::AccessDenied,
+                _ => OAuthError::ServerError,
+            };
+            return None.or_redirect(code, format!("provider error: {}", error), &params);
         }
     };
     // It's ok to not forward the error here cause it can only be cause by malicious requests
@@ -161,33 +318,244 @@ where
         .or_ise()?;
 
     // Defer to the provider-specific code to grab an ID using the code
-    let provider_id = (provider.id_fn)(code, state, shared)
-        .await
-        .or_redirect("couldn't obtain id from provider", &params)?;
+    let identity = (provider.id_fn)(code, state, shared).await.or_redirect(
+        OAuthError::ServerError,
+        "couldn't obtain id from provider",
+        &params,
+    )?;
+    let provider_id = identity.id;
+    // Only addresses verified by the provider are trusted
+    let email = identity
+        .email
+        .filter(|(_, verified)| *verified)
+        .map(|(email, _)| email);
 
     // Try to find a Vaulth user matching that provider ID
     let user_id = User::select_by_provider(provider.name, &provider_id, shared.pool)
         .await
-        .or_redirect("internal server error", &params)?;
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+
+    let client = Client::select_enabled(&params.client_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", &params)?;
+    let mut auth = Authentication::new(provider.name, &provider_id, None);
+
+    // Users who aren't registered yet have to sign up first
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => return signup(params, Some(auth), email, shared.global_config).await,
+    };
+    if let Some(email) = &email {
+        emails::import(&user_id, email, shared.pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+    }
+
+    auth.user_id = Some(user_id);
+    log_in(&params, auth, &client, user_agent.as_deref(), shared).await
+}
+
+/// Logs a user who just authenticated in to Vaulth, so following authorizations can skip the login page,
+/// then sends them back to the client
+/// Users who set up a second factor are asked for it first, disabled users and those who can't reach
+/// the level required by the client are sent back with an error.
+/// Users stepping up an existing session keep it, along with the stronger authentication.
+pub async fn log_in(
+    params: &Params,
+    mut auth: Authentication,
+    client: &Client,
+    user_agent: Option<&str>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let user_id = auth.user_id.clone().or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    let user = User::select(&user_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    if !user.active() {
+        None.or_redirect(OAuthError::AccessDenied, "account disabled", params)?;
+    }
+
+    if !auth.multi_factor() {
+        let factors = Factors::of(&user, &auth, config, shared.pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+        if factors.any() {
+            let request = MfaJwt {
+                params: params.clone(),
+                pending_auth: auth,
+            };
+            let request = jwt::encode_for(request, Duration::minutes(MFA_DURATION), &config.token)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            let uri =
+                Uri::from_maybe_shared(format!("{}/mfa?request={}", config.root_uri, request))
+                    .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            return Ok(warp::redirect::temporary(uri).into_response());
+        }
+    }
+    if !acr::satisfies(&auth.amr, acr::required(params, client)) {
+        None.or_redirect(
+            OAuthError::UnmetAuthenticationRequirements,
+            "second factor required",
+            params,
+        )?;
+    }
+
+    let session = match &auth.session_id {
+        Some(session_id) => Session::update_amr(session_id, &user_id, &auth.amr, shared.pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", params)?,
+        None => None,
+    };
+    let cookie = match session {
+        Some(_) => None,
+        None => {
+            let (session, cookie) =
+                sessions::create(&user_id, &auth, user_agent, config, shared.pool)
+                    .await
+                    .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            auth.session_id = Some(session.id);
+            Some(cookie)
+        }
+    };
+
+    let mut response = authorize(params, auth, client, shared).await?;
+    if let Some(cookie) = cookie {
+        let cookie = HeaderValue::from_str(&cookie).or_redirect(
+            OAuthError::ServerError,
+            "internal server error",
+            params,
+        )?;
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+/// Sends the user to the signup page, along with the identity they authenticated with if any
+pub async fn signup(
+    params: Params,
+    auth: Option<Authentication>,
+    email: Option<String>,
+    config: &'static Config,
+) -> Result<Response, Rejection> {
+    let request = SignupJwt {
+        params: params.clone(),
+        auth,
+        email,
+    };
+    let request = jwt::encode(request, &config.token).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        &params,
+    )?;
+    let uri = Uri::from_maybe_shared(format!("{}/signup?request={}", config.root_uri, request))
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+    Ok(warp::redirect::temporary(uri).into_response())
+}
+
+/// Sends an authenticated user back to the client,
+/// after asking them to approve the requested scopes for third-party clients
+pub async fn authorize(
+    params: &Params,
+    auth: Authentication,
+    client: &Client,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    if !client.trusted {
+        let scopes = providers::split_scope(params.scope.as_deref().unwrap_or_default());
+        let consent = match &auth.user_id {
+            Some(user_id) => Consent::select(user_id, &params.client_id, shared.pool)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", params)?,
+            None => None,
+        };
+
+        if !consent.map_or(false, |c| c.covers(&scopes)) {
+            if params.prompt.as_deref() == Some("none") {
+                None.or_redirect(
+                    OAuthError::ConsentRequired,
+                    "user hasn't approved the requested scopes",
+                    params,
+                )?;
+            }
+
+            let request = ConsentJwt {
+                params: params.clone(),
+                auth,
+            };
+            let request = jwt::encode(request, &shared.global_config.token)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            let uri = Uri::from_maybe_shared(format!(
+                "{}/consent?request={}",
+                shared.global_config.root_uri, request
+            ))
+            .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            return Ok(warp::redirect::temporary(uri).into_response());
+        }
+    }
+
+    issue_code(params, &auth, shared.global_config, shared.pool).await
+}
+
+/// Generates a code the client can exchange for a Vaulth token,
+/// and sends the user back to the client with it
+pub async fn issue_code(
+    params: &Params,
+    auth: &Authentication,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    // Remember the client so it can be notified when the session ends
+    if let Some(session_id) = &auth.session_id {
+        Session::add_client(session_id, &params.client_id, pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    }
+
+    // Only the ID is stored, the code itself carrying everything the token is made of
+    let id: String = OsRng.sample_iter(&Alphanumeric).take(CODE_ID_LEN).collect();
+    let duration = Duration::minutes(CODE_DURATION);
+    AuthorizationCode::delete_expired(pool).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+    AuthorizationCode::insert(&id, duration, pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
 
-    // Generate a code the client can exchange for a Vaulth token
     let code = CodeJwt {
-        provider_name: provider.name.to_owned(),
-        provider_id,
+        jti: id,
+        provider_name: auth.provider_name.clone(),
+        provider_id: auth.provider_id.clone(),
         client_id: params.client_id.clone(),
+        scope: params.scope.clone().unwrap_or_default(),
+        auth_time: auth.auth_time,
+        amr: auth.amr.clone(),
+        code_challenge: params.code_challenge.clone(),
     };
-    let code = jwt::encode(code, &shared.global_config.token)
+    let code = jwt::encode_for(code, duration, &config.token)
         .await
-        .or_redirect("internal server error", &params)?;
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
 
-    // Redirect the user back to the client
-    let uri = Uri::from_maybe_shared(success_redirect_uri_from_state(
-        &params,
-        &code,
-        user_id.as_ref().map(AsRef::as_ref),
-    ))
-    .or_redirect("internal server error", &params)?;
-    Ok(warp::redirect::temporary(uri))
+    let mut pairs = vec![("code", code.as_str())];
+    if let Some(user_id) = &auth.user_id {
+        pairs.push(("user", user_id.as_str()));
+    }
+    redirect::respond(params, &pairs).or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )
 }
 
 /// Adds the state and finishes a standard OAuth2 authentication URI (used for providers)
@@ -195,15 +563,3 @@ fn finish_auth_uri(uri: &str, state: &str) -> anyhow::Result<Uri> {
     let uri = format!("{}&state={}", uri, state);
     Ok(Uri::from_maybe_shared(uri)?)
 }
-
-/// Creates a redirect URI for success (used for the client)
-fn success_redirect_uri_from_state(params: &Params, code: &str, user: Option<&str>) -> String {
-    let mut uri = format!("{}?code={}", params.redirect_uri, code);
-    if let Some(state) = &params.state {
-        uri = format!("{}&state={}", uri, state);
-    }
-    if let Some(user) = user {
-        uri = format!("{}&user={}", uri, user);
-    }
-    uri
-}
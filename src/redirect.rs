@@ -0,0 +1,312 @@
+//! Matching of the redirect URIs requested by clients against the registered ones
+//!
+//! Registered URIs are matched exactly unless they opt in to one of the following patterns
+//! - `http://127.0.0.1:*/callback` allows any port on a loopback host, for native apps
+//! - `https://example.com/app/*` allows any path starting with `/app/` on that exact origin
+//!
+//! Only HTTPS URIs are accepted, along with HTTP ones on loopback hosts and, for native apps,
+//! custom schemes in reverse domain name notation like `com.example.app:/callback`.
+
+use crate::{html, providers::Params};
+use url::{form_urlencoded, Url};
+use warp::{http::Uri, reply::Response, Reply};
+
+const ANY_PORT: &str = ":*";
+const ANY_PATH: &str = "*";
+
+#[derive(Debug)]
+enum Pattern {
+    Exact(Url),
+    LoopbackAnyPort(Url),
+    PathPrefix(Url),
+}
+
+impl Pattern {
+    fn parse(registered: &str) -> Option<Self> {
+        if let Some(index) = registered.find(ANY_PORT) {
+            let url = Url::parse(&format!(
+                "{}{}",
+                &registered[..index],
+                &registered[index + ANY_PORT.len()..]
+            ))
+            .ok()?;
+            if !loopback(&url) || url.port().is_some() || !valid(&url) {
+                return None;
+            }
+            return Some(Self::LoopbackAnyPort(url));
+        }
+
+        if registered.ends_with(ANY_PATH) {
+            let url = Url::parse(&registered[..registered.len() - ANY_PATH.len()]).ok()?;
+            if !url.path().ends_with('/') || url.query().is_some() || !valid(&url) {
+                return None;
+            }
+            return Some(Self::PathPrefix(url));
+        }
+
+        let url = Url::parse(registered).ok()?;
+        if !valid(&url) {
+            return None;
+        }
+        Some(Self::Exact(url))
+    }
+
+    fn matches(&self, requested: &Url) -> bool {
+        match self {
+            Self::Exact(url) => url == requested,
+            Self::LoopbackAnyPort(url) => {
+                url.scheme() == requested.scheme()
+                    && url.host() == requested.host()
+                    && url.path() == requested.path()
+                    && url.query() == requested.query()
+            }
+            Self::PathPrefix(url) => {
+                let path = requested.path();
+                url.scheme() == requested.scheme()
+                    && url.host() == requested.host()
+                    && url.port_or_known_default() == requested.port_or_known_default()
+                    && path.starts_with(url.path())
+                    && !has_encoded_separators(path)
+            }
+        }
+    }
+}
+
+fn loopback(url: &Url) -> bool {
+    matches!(
+        url.host_str(),
+        Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+    )
+}
+
+/// Schemes which run code or read files in the browser instead of reaching the client
+const DENIED_SCHEMES: &[&str] = &["javascript", "data", "vbscript", "file"];
+
+/// Only HTTPS reaches a client safely, HTTP being allowed on loopback hosts for native apps,
+/// which can also claim custom schemes named after a domain they own (RFC 8252 section 7.1)
+fn allowed_scheme(url: &Url) -> bool {
+    match url.scheme() {
+        "https" => true,
+        "http" => loopback(url),
+        scheme if DENIED_SCHEMES.contains(&scheme) => false,
+        scheme => scheme.contains('.'),
+    }
+}
+
+/// Redirect URIs can't carry credentials or fragments
+fn valid(url: &Url) -> bool {
+    allowed_scheme(url)
+        && !url.cannot_be_a_base()
+        && url.username().is_empty()
+        && url.password().is_none()
+        && url.fragment().is_none()
+}
+
+/// Encoded dots and slashes could be used to escape a path prefix once decoded by the client
+fn has_encoded_separators(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    path.contains("%2e") || path.contains("%2f") || path.contains("%5c")
+}
+
+/// Checks whether a registered redirect URI or pattern is well formed
+pub fn valid_registration(registered: &str) -> bool {
+    Pattern::parse(registered).is_some()
+}
+
+/// Checks whether a redirect URI requested by a client matches one of its registered ones
+pub fn matches<S: AsRef<str>>(registered: &[S], requested: &str) -> bool {
+    let requested = match Url::parse(requested) {
+        Ok(url) if valid(&url) => url,
+        _ => return false,
+    };
+    registered
+        .iter()
+        .filter_map(|r| Pattern::parse(r.as_ref()))
+        .any(|p| p.matches(&requested))
+}
+
+/// How the parameters of an authorization response are sent back to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMode {
+    Query,
+    Fragment,
+    FormPost,
+}
+
+impl ResponseMode {
+    /// Parses the `response_mode` parameter, defaulting to the query
+    pub fn parse(mode: Option<&str>) -> Option<Self> {
+        match mode {
+            None | Some("query") => Some(Self::Query),
+            Some("fragment") => Some(Self::Fragment),
+            Some("form_post") => Some(Self::FormPost),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Sends parameters back to the client at its redirect URI, along with its state
+pub fn respond(params: &Params, pairs: &[(&str, &str)]) -> anyhow::Result<Response> {
+    let mode = ResponseMode::parse(params.response_mode.as_deref()).unwrap_or(ResponseMode::Query);
+    let mut url = Url::parse(&params.redirect_uri)?;
+    let pairs = pairs
+        .iter()
+        .copied()
+        .chain(params.state.as_deref().map(|s| ("state", s)));
+
+    match mode {
+        ResponseMode::Query => {
+            url.query_pairs_mut().extend_pairs(pairs);
+        }
+        ResponseMode::Fragment => {
+            let fragment = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(pairs)
+                .finish();
+            url.set_fragment(Some(&fragment));
+        }
+        ResponseMode::FormPost => {
+            let inputs: String = pairs
+                .map(|(name, value)| {
+                    format!(
+                        r#"<input type="hidden" name="{}" value="{}">"#,
+                        html::escape(name),
+                        html::escape(value),
+                    )
+                })
+                .collect();
+            return Ok(html::page(format!(
+                r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Submit</title></head>
+<body onload="document.forms[0].submit()">
+<form method="post" action="{action}">
+{inputs}
+<noscript><button type="submit">Continue</button></noscript>
+</form>
+</body>
+</html>"#,
+                action = html::escape(url.as_str()),
+                inputs = inputs,
+            )));
+        }
+    }
+
+    let uri = Uri::from_maybe_shared(url.into_string())?;
+    Ok(warp::redirect::temporary(uri).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let registered = ["https://example.com/callback"];
+        assert!(matches(&registered, "https://example.com/callback"));
+        assert!(!matches(&registered, "https://example.com/callback/"));
+        assert!(!matches(&registered, "https://example.com/callback?next=/"));
+        assert!(!matches(&registered, "https://example.com:8443/callback"));
+        assert!(!matches(&registered, "http://example.com/callback"));
+        assert!(!matches(
+            &registered,
+            "https://example.com.evil.com/callback"
+        ));
+        assert!(!matches(&registered, "https://user@example.com/callback"));
+        assert!(!matches(&registered, "https://example.com/callback#token"));
+    }
+
+    #[test]
+    fn loopback_any_port() {
+        let registered = ["http://127.0.0.1:*/callback"];
+        assert!(matches(&registered, "http://127.0.0.1:51234/callback"));
+        assert!(matches(&registered, "http://127.0.0.1/callback"));
+        assert!(!matches(&registered, "http://127.0.0.1:51234/other"));
+        assert!(!matches(&registered, "http://localhost:51234/callback"));
+        assert!(!matches(&registered, "https://127.0.0.1:51234/callback"));
+
+        assert!(!valid_registration("https://example.com:*/callback"));
+        assert!(!valid_registration("http://127.0.0.1:8080:*/callback"));
+    }
+
+    #[test]
+    fn path_prefix() {
+        let registered = ["https://example.com/app/*"];
+        assert!(matches(&registered, "https://example.com/app/"));
+        assert!(matches(&registered, "https://example.com/app/callback"));
+        assert!(matches(&registered, "https://example.com/app/a/b?c=d"));
+        assert!(!matches(&registered, "https://example.com/application"));
+        assert!(!matches(&registered, "https://example.com/app/..%2fadmin"));
+        assert!(!matches(
+            &registered,
+            "https://example.com/app/%2E%2E/admin"
+        ));
+        assert!(!matches(
+            &registered,
+            "https://example.com:444/app/callback"
+        ));
+        assert!(!matches(
+            &registered,
+            "https://sub.example.com/app/callback"
+        ));
+
+        assert!(!valid_registration("https://example.com/app*"));
+        assert!(!valid_registration("https://example.com/app/?a=b*"));
+    }
+
+    #[test]
+    fn schemes() {
+        assert!(valid_registration("https://example.com/callback"));
+        assert!(valid_registration("http://localhost/callback"));
+        assert!(valid_registration("http://[::1]:8080/callback"));
+        assert!(valid_registration("com.example.app:/callback"));
+        assert!(valid_registration("com.example.app://callback/*"));
+
+        assert!(!valid_registration("http://example.com/callback"));
+        assert!(!valid_registration("http://example.com/app/*"));
+        assert!(!valid_registration("javascript://x/%0aalert(1)"));
+        assert!(!valid_registration("JavaScript://x/%0aalert(1)"));
+        assert!(!valid_registration(
+            "data://x/text/html,<script>alert(1)</script>"
+        ));
+        assert!(!valid_registration("vbscript://x/msgbox(1)"));
+        assert!(!valid_registration("file:///etc/passwd"));
+        assert!(!valid_registration("myapp://callback"));
+    }
+
+    #[test]
+    fn denied_schemes_never_match() {
+        // Registrations predating the scheme checks are still in the database
+        let registered = [
+            "javascript://x/%0aalert(1)",
+            "http://example.com/callback",
+            "myapp://callback",
+        ];
+        assert!(!matches(&registered, "javascript://x/%0aalert(1)"));
+        assert!(!matches(&registered, "http://example.com/callback"));
+        assert!(!matches(&registered, "myapp://callback"));
+
+        let registered = ["com.example.app:/callback"];
+        assert!(matches(&registered, "com.example.app:/callback"));
+    }
+
+    #[test]
+    fn invalid_registrations_never_match() {
+        let registered = ["not a url", "https://example.com/callback#fragment"];
+        assert!(!matches(&registered, "https://example.com/callback"));
+        assert!(!matches(&registered, "not a url"));
+    }
+
+    #[test]
+    fn form_post_cannot_be_framed() {
+        let params: Params = serde_json::from_value(serde_json::json!({
+            "client_id": "client",
+            "redirect_uri": "https://example.com/callback",
+            "response_mode": "form_post",
+        }))
+        .unwrap();
+        let response = respond(&params, &[("code", "abc")]).unwrap();
+        let headers = response.headers();
+        assert_eq!(headers["X-Frame-Options"], "DENY");
+        assert_eq!(headers["Content-Security-Policy"], "frame-ancestors 'none'");
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{
+    accounts,
+    config::Config,
+    db::{audit_events, AuditEvent, User},
+    errors::TryExt,
+    lockouts::Lockouts,
+    logout,
+    providers::TokenJwt,
+    routes::{
+        self,
+        users::{self, ProfileBody},
+    },
+};
+use sqlx::PgPool;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let disable = warp::path!("admin" / "users" / String / "disable")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| set_disabled(id, true, pool));
+    let enable = warp::path!("admin" / "users" / String / "enable")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| set_disabled(id, false, pool));
+    let update = warp::path!("admin" / "users" / String)
+        .and(warp::patch())
+        .and(routes::admin(config))
+        .and(warp::body::json())
+        .and_then(move |id: String, _: TokenJwt, body: ProfileBody| {
+            users::update(id, body, true, config, pool)
+        });
+    let delete = warp::path!("admin" / "users" / String)
+        .and(warp::delete())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| delete(id, config, pool));
+    let restore = warp::path!("admin" / "users" / String / "restore")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| restore(id, pool));
+    let unlock = warp::path!("admin" / "users" / String / "unlock")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| unlock(id, lockouts, pool));
+    (disable)
+        .or(enable)
+        .or(update)
+        .or(delete)
+        .or(restore)
+        .or(unlock)
+}
+
+/// Disables or re-enables a user, logging disabled users out everywhere
+#[tracing::instrument(level = "debug")]
+async fn set_disabled(
+    id: String,
+    disabled: bool,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let user = User::update_disabled(&id, disabled, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    if disabled {
+        logout::end_all(&id, pool).await.or_ise()?;
+    }
+    let event = if disabled {
+        audit_events::DISABLED
+    } else {
+        audit_events::ENABLED
+    };
+    AuditEvent::insert(&id, event, None, None, pool)
+        .await
+        .or_ise()?;
+    Ok(warp::reply::json(&user))
+}
+
+/// Deletes a user, or schedules their deletion if a grace period is configured
+#[tracing::instrument(level = "debug")]
+async fn delete(
+    id: String,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    accounts::delete(&id, config, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Cancels the scheduled deletion of a user
+#[tracing::instrument(level = "debug")]
+async fn restore(id: String, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    let user = accounts::restore(&id, pool).await.or_ise()?.or_nf()?;
+    Ok(warp::reply::json(&user))
+}
+
+/// Lets a user locked out after too many failed attempts log in again
+#[tracing::instrument(level = "debug")]
+async fn unlock(
+    id: String,
+    lockouts: &'static Lockouts,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let user = User::select(&id, pool).await.or_ise()?.or_nf()?;
+    lockouts.unlock(&user.id).await.or_ise()?;
+    AuditEvent::insert(&user.id, audit_events::UNLOCKED, None, None, pool)
+        .await
+        .or_ise()?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,403 @@
+//! Hosted login page letting users pick how to authenticate
+
+use crate::{
+    db::{Client, User},
+    errors::{OAuthError, TryExt},
+    html, jwt,
+    lockouts::{self, Key},
+    password::{self, Verification},
+    providers::{
+        self,
+        oauth::{self, SharedResources},
+        Authentication, Params, SignupJwt, AMR_HARDWARE_KEY,
+    },
+    routes, sessions,
+    templates::{self, Locale},
+    usernames, webauthn,
+};
+use serde::Deserialize;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    request: String,
+    user: String,
+    password: String,
+}
+
+/// Passkey along with the challenge it signed
+#[derive(Debug, Deserialize)]
+struct PasskeyForm {
+    request: String,
+    challenge: String,
+    credential: String,
+}
+
+pub fn handler(
+    shared: SharedResources,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let page = warp::path!("authorize")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::cookie::optional(sessions::COOKIE))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |query: Params, session: Option<String>, accept_language: Option<String>| {
+                page(query, session, accept_language, shared)
+            },
+        );
+    let login = warp::path!("authorize")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(routes::client_ip(shared.global_config))
+        .and(warp::header::optional("User-Agent"))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: LoginForm,
+                  ip: Option<String>,
+                  user_agent: Option<String>,
+                  accept_language: Option<String>| {
+                login(form, ip, user_agent, accept_language, shared)
+            },
+        );
+    let passkey = warp::path!("passkey")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::header::optional("User-Agent"))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: PasskeyForm,
+                  user_agent: Option<String>,
+                  accept_language: Option<String>| {
+                passkey(form, user_agent, accept_language, shared)
+            },
+        );
+    (page).or(login).or(passkey)
+}
+
+/// Shows every way the user can log in, unless they already are
+#[tracing::instrument(level = "debug", skip(session))]
+async fn page(
+    mut query: Params,
+    session: Option<String>,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+    let client = oauth::validate(&mut query, &languages, shared.pool).await?;
+    if let Some(response) = oauth::resume(&query, session, &client, shared).await? {
+        return Ok(response);
+    }
+
+    render(&query, &client, None, &languages, shared).await
+}
+
+/// Logs the user in with their Vaulth username and password
+/// Failed attempts are counted per account and IP address, which get locked out after too many.
+#[tracing::instrument(level = "debug", skip(form))]
+async fn login(
+    form: LoginForm,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let (params, client, languages) = decode(form.request, accept_language, shared).await?;
+
+    // Longer usernames can't exist, nor fit in the key their attempts would be counted with
+    if form.user.chars().count() > usernames::MAX_LEN {
+        return rejected(
+            &params,
+            &client,
+            "login.invalid_credentials",
+            StatusCode::UNAUTHORIZED,
+            &languages,
+            shared,
+        )
+        .await;
+    }
+
+    let user = User::select_by_username(&form.user, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+    let user_id = user.as_ref().map(|u| u.id.clone());
+    // Unknown usernames are counted too, so probing for them is as slow as guessing passwords,
+    // marked so they can't be mistaken for the ID of another account
+    let account = match &user_id {
+        Some(user_id) => user_id.clone(),
+        None => format!("@{}", form.user),
+    };
+    let keys: Vec<Key> = std::iter::once(Key::Account(&account))
+        .chain(ip.as_deref().map(Key::Ip))
+        .collect();
+    let recorded = shared.lockouts.record(&keys).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        &params,
+    )?;
+    let recorded = match recorded {
+        Ok(recorded) => recorded,
+        Err(_) => {
+            return rejected(
+                &params,
+                &client,
+                "login.locked",
+                StatusCode::TOO_MANY_REQUESTS,
+                &languages,
+                shared,
+            )
+            .await
+        }
+    };
+
+    let hash = user.as_ref().and_then(|u| u.password.clone());
+    let verification = match hash {
+        Some(hash) => password::check(hash, &form.password, &config.hash)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", &params)?,
+        None => {
+            password::verify_nothing(&form.password, &config.hash)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+            Verification::Invalid
+        }
+    };
+    let user = match user {
+        Some(user) if verification.valid() => user,
+        _ => {
+            let locked_out = shared.lockouts.fail(&recorded).await.or_redirect(
+                OAuthError::ServerError,
+                "internal server error",
+                &params,
+            )?;
+            if let (true, Some(user_id)) = (locked_out, &user_id) {
+                lockouts::locked_out(
+                    user_id,
+                    &client.id,
+                    user_agent.as_deref(),
+                    &languages,
+                    config,
+                    shared.pool,
+                )
+                .await
+                .or_redirect(
+                    OAuthError::ServerError,
+                    "internal server error",
+                    &params,
+                )?;
+            }
+            return rejected(
+                &params,
+                &client,
+                "login.invalid_credentials",
+                StatusCode::UNAUTHORIZED,
+                &languages,
+                shared,
+            )
+            .await;
+        }
+    };
+    shared.lockouts.succeed(&keys).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        &params,
+    )?;
+
+    // Upgrade hashes made with previous settings now that the password is known
+    if verification == Verification::Outdated {
+        let hash = password::hash(&form.password, &config.hash)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+        User::update_password(&user.id, &hash, shared.pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+    }
+
+    let auth = Authentication::new(providers::LOCAL, &user.id, Some(&user.id));
+    oauth::log_in(&params, auth, &client, user_agent.as_deref(), shared).await
+}
+
+/// Logs the user in with a passkey, found through the credential ID the authenticator returned
+#[tracing::instrument(level = "debug", skip(form))]
+async fn passkey(
+    form: PasskeyForm,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let (params, client, languages) = decode(form.request, accept_language, shared).await?;
+
+    let challenge = webauthn::redeem_challenge(form.challenge, config, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?;
+    let assertion = match challenge {
+        Some(challenge) => {
+            webauthn::authenticate(&form.credential, &challenge, None, config, shared.pool)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", &params)?
+        }
+        None => None,
+    };
+    let assertion = match assertion {
+        Some(assertion) => assertion,
+        None => {
+            return rejected(
+                &params,
+                &client,
+                "login.invalid_passkey",
+                StatusCode::UNAUTHORIZED,
+                &languages,
+                shared,
+            )
+            .await;
+        }
+    };
+
+    let user_id = &assertion.credential.user_id;
+    let mut auth = Authentication::new(providers::WEBAUTHN, user_id, Some(user_id));
+    if assertion.user_verified {
+        // The authenticator checked a PIN or biometrics on top of proving possession of the key
+        auth.add_factor(AMR_HARDWARE_KEY);
+    }
+    oauth::log_in(&params, auth, &client, user_agent.as_deref(), shared).await
+}
+
+/// Decodes the signed parameters of a login form, along with the client and languages it is shown with
+async fn decode(
+    request: String,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<(Params, Client, Vec<String>), Rejection> {
+    let params: Option<Params> = jwt::decode(request, &shared.global_config.token)
+        .await
+        .or_ise()?;
+    let params = params.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let languages = templates::languages(params.ui_locales.as_deref(), accept_language.as_deref());
+    let client = Client::select_enabled(&params.client_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", &params)?;
+    Ok((params, client, languages))
+}
+
+/// Shows the login page again with an error
+async fn rejected(
+    params: &Params,
+    client: &Client,
+    error: &str,
+    status: StatusCode,
+    languages: &[String],
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let mut response = render(params, client, Some(error), languages, shared).await?;
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+/// Renders the login page, with the branding of the client
+async fn render(
+    params: &Params,
+    client: &Client,
+    error: Option<&str>,
+    languages: &[String],
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let locale = Locale::negotiate(languages, config).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    // The parameters are signed so the password form can't be used to tamper with them
+    let request = jwt::encode(params.clone(), &config.token)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    let signup = SignupJwt {
+        params: params.clone(),
+        auth: None,
+        email: None,
+    };
+    let signup = jwt::encode(signup, &config.token).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    let query = params.to_query();
+    let providers: String = [
+        ("github", "GitHub", config.github.is_some()),
+        ("discord", "Discord", config.discord.is_some()),
+        ("google", "Google", config.google.is_some()),
+    ]
+    .iter()
+    .filter(|(_, _, enabled)| *enabled)
+    .map(|(name, label, _)| {
+        format!(
+            r#"<a class="provider" href="{}?{}">{}</a>"#,
+            name,
+            html::escape(&query),
+            locale.text("login.continue_with", &[("provider", label)]),
+        )
+    })
+    .collect();
+    // Magic links can't be sent without mail
+    let magic_link = if config.magic_links.is_some() && config.mail.is_some() {
+        format!(
+            r#"<form method="post" action="magic-link"><input type="hidden" name="request" value="{}"><input type="email" name="email" placeholder="{}" autocomplete="email" required><button type="submit">{}</button></form>"#,
+            html::escape(&request),
+            locale.text("login.email", &[]),
+            locale.text("login.send_magic_link", &[]),
+        )
+    } else {
+        String::new()
+    };
+    let passkey = match &config.webauthn {
+        Some(webauthn_config) => {
+            let (challenge, token) = webauthn::challenge(config, shared.pool).await.or_redirect(
+                OAuthError::ServerError,
+                "internal server error",
+                params,
+            )?;
+            let options = webauthn::request_options(&challenge, &[], webauthn_config, config)
+                .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            webauthn::form(
+                "passkey",
+                &request,
+                &token,
+                &options,
+                &locale.text("login.passkey", &[]),
+            )
+        }
+        None => String::new(),
+    };
+    let error = match error {
+        Some(error) => format!(r#"<p class="error">{}</p>"#, locale.text(error, &[])),
+        None => String::new(),
+    };
+
+    templates::render(
+        "login",
+        &[
+            ("client", &client.name),
+            ("logo", &templates::logo(client)),
+            ("error", &error),
+            ("providers", &providers),
+            ("passkey", &passkey),
+            ("magic_link", &magic_link),
+            ("request", &request),
+            ("signup", &signup),
+        ],
+        &locale,
+        Some(client),
+        config,
+    )
+    .await
+    .or_redirect(OAuthError::ServerError, "internal server error", params)
+}
@@ -0,0 +1,314 @@
+use crate::{
+    acr, clients,
+    config::Config,
+    db::{Client, NewClient},
+    errors::{JsonError, TryExt},
+    password,
+    providers::TokenJwt,
+    redirect, routes,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use url::Url;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ClientType {
+    Public,
+    Confidential,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateClientBody {
+    id: Option<String>,
+    name: String,
+    logo_uri: Option<String>,
+    #[serde(rename = "type")]
+    client_type: ClientType,
+    redirect_urls: Vec<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    trusted: bool,
+    #[serde(default)]
+    post_logout_redirect_urls: Vec<String>,
+    backchannel_logout_url: Option<String>,
+    primary_color: Option<String>,
+    background_color: Option<String>,
+    theme: Option<String>,
+    #[serde(default)]
+    acr_values: Vec<String>,
+}
+
+/// Client along with its secret, which is only ever returned once
+#[derive(Serialize)]
+struct ClientWithSecret {
+    #[serde(flatten)]
+    client: Client,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let list = warp::path!("admin" / "clients")
+        .and(warp::get())
+        .and(routes::admin(config))
+        .and_then(move |_: TokenJwt| list(pool));
+    let create = warp::path!("admin" / "clients")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and(warp::body::json())
+        .and_then(move |_: TokenJwt, body: CreateClientBody| create(body, config, pool));
+    let get = warp::path!("admin" / "clients" / String)
+        .and(warp::get())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| get(id, pool));
+    let rotate = warp::path!("admin" / "clients" / String / "secret")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| rotate(id, config, pool));
+    let disable = warp::path!("admin" / "clients" / String / "disable")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| set_disabled(id, true, pool));
+    let enable = warp::path!("admin" / "clients" / String / "enable")
+        .and(warp::post())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| set_disabled(id, false, pool));
+    let delete = warp::path!("admin" / "clients" / String)
+        .and(warp::delete())
+        .and(routes::admin(config))
+        .and_then(move |id: String, _: TokenJwt| delete(id, pool));
+    (list)
+        .or(create)
+        .or(get)
+        .or(rotate)
+        .or(disable)
+        .or(enable)
+        .or(delete)
+}
+
+#[tracing::instrument(level = "debug")]
+async fn list(pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    let clients = Client::select_all(pool).await.or_ise()?;
+    Ok(warp::reply::json(&clients))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn create(
+    body: CreateClientBody,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    if body.id.iter().any(|id| !clients::valid_id(id)) {
+        None.or_json(
+            JsonError {
+                error: "invalid id",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    if body.name.chars().count() > clients::MAX_LEN {
+        None.or_json(
+            JsonError {
+                error: "invalid name",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    if !body
+        .redirect_urls
+        .iter()
+        .chain(&body.post_logout_redirect_urls)
+        .all(|u| redirect::valid_registration(u))
+    {
+        None.or_json(
+            JsonError {
+                error: "invalid redirect_urls",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    if body
+        .backchannel_logout_url
+        .as_ref()
+        .map_or(false, |u| Url::parse(u).is_err())
+    {
+        None.or_json(
+            JsonError {
+                error: "invalid backchannel_logout_url",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    // Logos are shown on hosted pages, to users who haven't logged in yet
+    if body.logo_uri.iter().any(|u| !clients::valid_public_url(u)) {
+        None.or_json(
+            JsonError {
+                error: "invalid logo_uri",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    if !body
+        .primary_color
+        .iter()
+        .chain(&body.background_color)
+        .all(|c| clients::valid_color(c))
+    {
+        None.or_json(
+            JsonError {
+                error: "invalid color",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    if body
+        .theme
+        .as_ref()
+        .map_or(false, |t| !clients::valid_theme(t))
+    {
+        None.or_json(
+            JsonError {
+                error: "invalid theme",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    if !body.acr_values.iter().all(|v| acr::valid(v)) {
+        None.or_json(
+            JsonError {
+                error: "invalid acr_values",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    let id = body.id.unwrap_or_else(clients::generate_id);
+    if Client::select(&id, pool).await.or_ise()?.is_some() {
+        None.or_json(
+            JsonError {
+                error: "client already exists",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+
+    let secret = match body.client_type {
+        ClientType::Confidential => Some(clients::generate_secret()),
+        ClientType::Public => None,
+    };
+    let hash = match &secret {
+        Some(secret) => Some(password::hash(secret, &config.hash).await.or_ise()?),
+        None => None,
+    };
+
+    let client = Client::insert(
+        NewClient {
+            id: &id,
+            name: &body.name,
+            logo_uri: body.logo_uri.as_deref(),
+            secret: hash.as_deref(),
+            redirect_urls: &body.redirect_urls,
+            scopes: &body.scopes,
+            trusted: body.trusted,
+            registration_token: None,
+            post_logout_redirect_urls: &body.post_logout_redirect_urls,
+            backchannel_logout_url: body.backchannel_logout_url.as_deref(),
+            primary_color: body.primary_color.as_deref(),
+            background_color: body.background_color.as_deref(),
+            theme: body.theme.as_deref(),
+            acr_values: &body.acr_values,
+        },
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ClientWithSecret {
+            client,
+            client_secret: secret,
+        }),
+        StatusCode::CREATED,
+    ))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn get(id: String, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    let client = Client::select(&id, pool).await.or_ise()?.or_nf()?;
+    Ok(warp::reply::json(&client))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn rotate(
+    id: String,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let client = modifiable(&id, pool).await?;
+    if client.secret.is_none() {
+        None.or_json(
+            JsonError {
+                error: "public clients don't have a secret",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    let secret = clients::generate_secret();
+    let hash = password::hash(&secret, &config.hash).await.or_ise()?;
+    let client = Client::update_secret(&id, &hash, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    Ok(warp::reply::json(&ClientWithSecret {
+        client,
+        client_secret: Some(secret),
+    }))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn set_disabled(
+    id: String,
+    disabled: bool,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    modifiable(&id, pool).await?;
+    let client = Client::update_disabled(&id, disabled, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    Ok(warp::reply::json(&client))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn delete(id: String, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    modifiable(&id, pool).await?;
+    Client::delete(&id, pool).await.or_ise()?.or_nf()?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Selects a client, making sure it doesn't come from the config file
+async fn modifiable(id: &str, pool: &'static PgPool) -> Result<Client, Rejection> {
+    let client = Client::select(id, pool).await.or_ise()?.or_nf()?;
+    if client.seeded {
+        None.or_json(
+            JsonError {
+                error: "client is managed by the config file",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+    Ok(client)
+}
@@ -0,0 +1,172 @@
+use crate::{
+    accounts,
+    config::Config,
+    db::{audit_events, AuditEvent, Client, Consent},
+    errors::{OAuthError, TryExt},
+    html, jwt,
+    providers::{self, oauth, ConsentJwt, TokenJwt},
+    routes,
+    templates::{self, Locale},
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+struct ConsentQuery {
+    request: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConsentForm {
+    request: String,
+    decision: String,
+    remember: Option<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let page = warp::path!("consent")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |query: ConsentQuery, accept_language: Option<String>| {
+                page(query, accept_language, config, pool)
+            },
+        );
+    let decide = warp::path!("consent")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |form: ConsentForm, accept_language: Option<String>| {
+            decide(form, accept_language, config, pool)
+        });
+    let list = warp::path!("me" / "consents")
+        .and(warp::get())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |token: TokenJwt| list(token, pool));
+    let revoke = warp::path!("me" / "consents" / String)
+        .and(warp::delete())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |client_id: String, token: TokenJwt| revoke(client_id, token, pool));
+    (page).or(decide).or(list).or(revoke)
+}
+
+/// Shows the user which scopes the client is requesting
+#[tracing::instrument(level = "debug")]
+async fn page(
+    query: ConsentQuery,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let request: Option<ConsentJwt> = jwt::decode(query.request.clone(), &config.token)
+        .await
+        .or_ise()?;
+    let request = request.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let params = &request.params;
+    let client = Client::select_enabled(&params.client_id, pool)
+        .await
+        .or_ise()?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", params)?;
+
+    let languages = templates::languages(params.ui_locales.as_deref(), accept_language.as_deref());
+    let locale = Locale::negotiate(&languages, config).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+    let scopes: String = providers::split_scope(params.scope.as_deref().unwrap_or_default())
+        .iter()
+        .map(|s| format!("<li>{}</li>", html::escape(s)))
+        .collect();
+    templates::render(
+        "consent",
+        &[
+            ("client", &client.name),
+            ("logo", &templates::logo(&client)),
+            ("scopes", &scopes),
+            ("request", &query.request),
+        ],
+        &locale,
+        Some(&client),
+        config,
+    )
+    .await
+    .or_redirect(OAuthError::ServerError, "internal server error", params)
+}
+
+/// Forwards the decision of the user to the client
+#[tracing::instrument(level = "debug")]
+async fn decide(
+    form: ConsentForm,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let request: Option<ConsentJwt> = jwt::decode(form.request, &config.token).await.or_ise()?;
+    let request = request.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let params = &request.params;
+
+    if form.decision != "approve" {
+        None.or_redirect(OAuthError::AccessDenied, "consent denied by user", params)?;
+    }
+
+    // Users that aren't registered yet can't have their decision remembered
+    if let (Some(user_id), Some(_)) = (&request.auth.user_id, &form.remember) {
+        let scopes = providers::split_scope(params.scope.as_deref().unwrap_or_default());
+        Consent::upsert(user_id, &params.client_id, &scopes, pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+        AuditEvent::insert(
+            user_id,
+            audit_events::CONSENT_GRANTED,
+            Some(&params.client_id),
+            None,
+            pool,
+        )
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    }
+
+    oauth::issue_code(params, &request.auth, config, pool).await
+}
+
+#[tracing::instrument(level = "debug")]
+async fn list(token: TokenJwt, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    let consents = Consent::select_by_user(&token.sub, pool).await.or_ise()?;
+    Ok(warp::reply::json(&consents))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn revoke(
+    client_id: String,
+    token: TokenJwt,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    Consent::delete(&token.sub, &client_id, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    AuditEvent::insert(
+        &token.sub,
+        audit_events::CONSENT_REVOKED,
+        Some(&client_id),
+        None,
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(StatusCode::NO_CONTENT)
+}
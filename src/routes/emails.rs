@@ -0,0 +1,207 @@
+use crate::{
+    accounts,
+    config::{Config, MailConfig},
+    db::{audit_events, AuditEvent, User},
+    emails::{self, VerificationJwt},
+    errors::{JsonError, TryExt},
+    jwt,
+    providers::TokenJwt,
+    routes::{self, users::Me},
+    templates::{self, Locale},
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+struct EmailBody {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyQuery {
+    token: String,
+    ui_locales: Option<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let update = warp::path!("me" / "email")
+        .and(warp::put())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::body::json())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |token: TokenJwt, body: EmailBody, accept_language: Option<String>| {
+                update(token, body, accept_language, config, pool)
+            },
+        );
+    let remove = warp::path!("me" / "email")
+        .and(warp::delete())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |token: TokenJwt| remove(token, pool));
+    let resend = warp::path!("me" / "email" / "verification")
+        .and(warp::post())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |token: TokenJwt, accept_language: Option<String>| {
+            resend(token, accept_language, config, pool)
+        });
+    let verify = warp::path!("verify-email")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |query: VerifyQuery, accept_language: Option<String>| {
+            verify(query, accept_language, config, pool)
+        });
+    (update).or(remove).or(resend).or(verify)
+}
+
+/// Changes the email address of the user, which stays unverified until they follow the mailed link
+#[tracing::instrument(level = "debug")]
+async fn update(
+    token: TokenJwt,
+    body: EmailBody,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let mail_config = enabled(config)?;
+    let email = body.email.trim();
+    if !emails::valid(email) {
+        None.or_json(
+            JsonError {
+                error: "invalid email",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    if user.email_verified
+        && user
+            .email
+            .as_deref()
+            .map_or(false, |e| emails::same(e, email))
+    {
+        return Ok(warp::reply::json(&Me::new(
+            user,
+            token.granted(emails::SCOPE),
+        )));
+    }
+    if let Some(holder) = User::select_by_email(email, pool).await.or_ise()? {
+        if holder.id != user.id {
+            None.or_json(
+                JsonError {
+                    error: "email taken",
+                },
+                StatusCode::CONFLICT,
+            )?;
+        }
+    }
+
+    let user = User::update_email(&user.id, Some(email), false, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    AuditEvent::insert(&user.id, audit_events::EMAIL_CHANGED, None, None, pool)
+        .await
+        .or_ise()?;
+
+    let locale = Locale::negotiate(
+        &templates::languages(None, accept_language.as_deref()),
+        config,
+    )
+    .await
+    .or_ise()?;
+    emails::send_verification(&user.id, email, &locale, mail_config, config)
+        .await
+        .or_ise()?;
+    Ok(warp::reply::json(&Me::new(
+        user,
+        token.granted(emails::SCOPE),
+    )))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn remove(token: TokenJwt, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    User::update_email(&token.sub, None, false, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    AuditEvent::insert(&token.sub, audit_events::EMAIL_CHANGED, None, None, pool)
+        .await
+        .or_ise()?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mails another verification link, in case the previous one was lost or expired
+#[tracing::instrument(level = "debug")]
+async fn resend(
+    token: TokenJwt,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let mail_config = enabled(config)?;
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    let email = user.email.as_deref().or_nf()?;
+    if user.email_verified {
+        None.or_json(
+            JsonError {
+                error: "email already verified",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+
+    let locale = Locale::negotiate(
+        &templates::languages(None, accept_language.as_deref()),
+        config,
+    )
+    .await
+    .or_ise()?;
+    emails::send_verification(&user.id, email, &locale, mail_config, config)
+        .await
+        .or_ise()?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Marks the address of the user as verified when they follow the mailed link
+#[tracing::instrument(level = "debug", skip(query))]
+async fn verify(
+    query: VerifyQuery,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+    let request: Option<VerificationJwt> =
+        jwt::decode(query.token, &config.token).await.or_ise()?;
+    let request = request.or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+
+    // Someone else may have verified the address in the meantime
+    if let Some(holder) = User::select_by_email(&request.verify_email, pool)
+        .await
+        .or_ise()?
+    {
+        if holder.id != request.sub {
+            None.or_page("email_taken", StatusCode::CONFLICT, &languages)?;
+        }
+    }
+    User::verify_email(&request.sub, &request.verify_email, pool)
+        .await
+        .or_ise()?
+        .or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+
+    let locale = Locale::negotiate(&languages, config).await.or_ise()?;
+    templates::render("email_verified", &[], &locale, None, config)
+        .await
+        .or_ise()
+}
+
+fn enabled(config: &'static Config) -> Result<&'static MailConfig, Rejection> {
+    config.mail.as_ref().or_nf()
+}
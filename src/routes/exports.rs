@@ -0,0 +1,120 @@
+use crate::{
+    accounts,
+    config::Config,
+    db::{audit_events, AuditEvent, Export},
+    errors::TryExt,
+    exports,
+    providers::TokenJwt,
+    routes,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+const FILENAME: &str = "attachment; filename=\"vaulth-export.json\"";
+
+/// Export being generated in the background, along with where to download it
+#[derive(Serialize)]
+struct PendingExport {
+    #[serde(flatten)]
+    export: Export,
+    url: String,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let export = warp::path!("me" / "export")
+        .and(warp::get())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::header::optional("User-Agent"))
+        .and_then(move |token: TokenJwt, user_agent: Option<String>| {
+            export(token, user_agent, config, pool)
+        });
+    let download = warp::path!("me" / "exports" / String)
+        .and(warp::get())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |id: String, token: TokenJwt| download(id, token, config, pool));
+    (export).or(download)
+}
+
+/// Sends the archive of the user right away if it is small enough,
+/// otherwise starts generating it and tells the user where to download it
+#[tracing::instrument(level = "debug")]
+async fn export(
+    token: TokenJwt,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    AuditEvent::insert(
+        &token.sub,
+        audit_events::EXPORT_REQUESTED,
+        None,
+        user_agent.as_deref(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+
+    if !exports::large(&token.sub, pool).await.or_ise()? {
+        let data = exports::generate(&token.sub, pool)
+            .await
+            .or_ise()?
+            .or_nf()?;
+        return Ok(archive(data));
+    }
+
+    let export = exports::start(&token.sub, pool).await.or_ise()?;
+    let url = format!("{}/me/exports/{}", config.root_uri, export.id);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&PendingExport { export, url }),
+        StatusCode::ACCEPTED,
+    )
+    .into_response())
+}
+
+/// Sends an archive generated in the background, or its status if it isn't ready
+#[tracing::instrument(level = "debug")]
+async fn download(
+    id: String,
+    token: TokenJwt,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let mut export = Export::select_for_user(&id, &token.sub, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+
+    match export.data.take() {
+        Some(data) => Ok(archive(data)),
+        None => {
+            let status = if export.pending() {
+                StatusCode::ACCEPTED
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            let url = format!("{}/me/exports/{}", config.root_uri, export.id);
+            Ok(
+                warp::reply::with_status(warp::reply::json(&PendingExport { export, url }), status)
+                    .into_response(),
+            )
+        }
+    }
+}
+
+fn archive(data: String) -> Response {
+    let mut response =
+        warp::reply::with_header(data, header::CONTENT_TYPE, "application/json").into_response();
+    response.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_static(FILENAME),
+    );
+    response
+}
@@ -0,0 +1,195 @@
+//! Passwordless login through links mailed from the hosted login page
+
+use crate::{
+    config::{Config, MagicLinkConfig, MailConfig},
+    db::Client,
+    emails,
+    errors::{OAuthError, TryExt},
+    jwt,
+    limits::MailLimits,
+    magic_links::{self, Invalid},
+    providers::{
+        self,
+        oauth::{self, SharedResources},
+        Authentication, Params,
+    },
+    routes,
+    templates::{self, Locale},
+};
+use chrono::Duration;
+use serde::Deserialize;
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// Window over which link requests are counted, in minutes
+const LIMIT_WINDOW: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+struct LinkForm {
+    request: String,
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinkQuery {
+    token: String,
+}
+
+pub fn handler(
+    shared: SharedResources,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let limits: &'static MailLimits =
+        Box::leak(Box::new(match &shared.global_config.magic_links {
+            Some(link_config) => MailLimits::new(
+                link_config.limit_per_address,
+                link_config.limit_per_ip,
+                Duration::minutes(LIMIT_WINDOW),
+            ),
+            None => MailLimits::new(0, 0, Duration::minutes(LIMIT_WINDOW)),
+        }));
+
+    let send = warp::path!("magic-link")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(routes::client_ip(shared.global_config))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: LinkForm, ip: Option<String>, accept_language: Option<String>| {
+                send(form, ip, accept_language, limits, shared)
+            },
+        );
+    let login = warp::path!("magic-link")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::cookie::optional(magic_links::COOKIE))
+        .and(warp::header::optional("User-Agent"))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |query: LinkQuery,
+                  cookie: Option<String>,
+                  user_agent: Option<String>,
+                  accept_language: Option<String>| {
+                login(query, cookie, user_agent, accept_language, shared)
+            },
+        );
+    (send).or(login)
+}
+
+/// Mails a login link, telling the user it was sent whether the address is known or not
+#[tracing::instrument(level = "debug", skip(limits))]
+async fn send(
+    form: LinkForm,
+    ip: Option<String>,
+    accept_language: Option<String>,
+    limits: &'static MailLimits,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let (link_config, mail_config) = enabled(config)?;
+    let params: Option<Params> = jwt::decode(form.request, &config.token).await.or_ise()?;
+    let params = params.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let languages = templates::languages(params.ui_locales.as_deref(), accept_language.as_deref());
+    let client = Client::select_enabled(&params.client_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", &params)?;
+
+    let email = form.email.trim();
+    if !emails::valid(email) {
+        None.or_page("invalid_email", StatusCode::BAD_REQUEST, &languages)?;
+    }
+    if !limits.attempt(email, ip.as_deref()) {
+        None.or_page("rate_limited", StatusCode::TOO_MANY_REQUESTS, &languages)?;
+    }
+
+    let locale = Locale::negotiate(&languages, config).await.or_ise()?;
+    let mut response = templates::render(
+        "magic_link_sent",
+        &[
+            ("client", &client.name),
+            ("logo", &templates::logo(&client)),
+            ("query", &params.to_query()),
+        ],
+        &locale,
+        Some(&client),
+        config,
+    )
+    .await
+    .or_ise()?;
+    let cookie = magic_links::request(
+        email.to_owned(),
+        params,
+        locale,
+        link_config,
+        mail_config,
+        config,
+        shared.pool,
+    )
+    .await
+    .or_ise()?;
+    response
+        .headers_mut()
+        .append(header::SET_COOKIE, HeaderValue::from_str(&cookie).or_ise()?);
+    Ok(response)
+}
+
+/// Logs the user in with a link followed from the browser it was requested from,
+/// then resumes the authorization they started
+#[tracing::instrument(level = "debug", skip(query, cookie))]
+async fn login(
+    query: LinkQuery,
+    cookie: Option<String>,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let languages = templates::languages(None, accept_language.as_deref());
+    let (link, params) = match magic_links::redeem(&query.token, cookie, config, shared.pool)
+        .await
+        .or_ise()?
+    {
+        Ok(redeemed) => redeemed,
+        Err(Invalid::Unknown) => {
+            None.or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?
+        }
+        Err(Invalid::OtherBrowser) => None.or_page(
+            "magic_link_other_browser",
+            StatusCode::FORBIDDEN,
+            &languages,
+        )?,
+    };
+
+    let client = Client::select_enabled(&params.client_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", &params)?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", &params)?;
+
+    let auth = Authentication::new(providers::EMAIL, &link.user_id, Some(&link.user_id));
+    let mut response = oauth::log_in(&params, auth, &client, user_agent.as_deref(), shared).await?;
+    let cookie = HeaderValue::from_str(&magic_links::clear_cookie(config)).or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        &params,
+    )?;
+    response.headers_mut().append(header::SET_COOKIE, cookie);
+    Ok(response)
+}
+
+/// Magic links need mail to be configured as well
+fn enabled(
+    config: &'static Config,
+) -> Result<(&'static MagicLinkConfig, &'static MailConfig), Rejection> {
+    config
+        .magic_links
+        .as_ref()
+        .zip(config.mail.as_ref())
+        .or_nf()
+}
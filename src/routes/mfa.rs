@@ -0,0 +1,340 @@
+//! Hosted page asking users who set up a second factor for it after they authenticate,
+//! either a code from their authenticator app or a passkey
+
+use crate::{
+    db::{Client, User},
+    errors::{OAuthError, TryExt},
+    html, jwt,
+    lockouts::{self, Key},
+    mfa::Factors,
+    providers::{
+        oauth::{self, SharedResources},
+        MfaJwt, Params, AMR_HARDWARE_KEY, AMR_OTP,
+    },
+    routes,
+    templates::{self, Locale},
+    totp, webauthn,
+};
+use serde::Deserialize;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+struct MfaQuery {
+    request: String,
+}
+
+/// Either a code, or a passkey along with the challenge it signed
+#[derive(Debug, Deserialize)]
+struct MfaForm {
+    request: String,
+    code: Option<String>,
+    challenge: Option<String>,
+    credential: Option<String>,
+}
+pub fn handler(
+    shared: SharedResources,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let page = warp::path!("mfa")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |query: MfaQuery, accept_language: Option<String>| {
+            page(query, accept_language, shared)
+        });
+    let verify = warp::path!("mfa")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(routes::client_ip(shared.global_config))
+        .and(warp::header::optional("User-Agent"))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: MfaForm,
+                  ip: Option<String>,
+                  user_agent: Option<String>,
+                  accept_language: Option<String>| {
+                verify(form, ip, user_agent, accept_language, shared)
+            },
+        );
+    (page).or(verify)
+}
+
+/// Asks the user for one of the second factors they set up
+#[tracing::instrument(level = "debug")]
+async fn page(
+    query: MfaQuery,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let (request, client, languages) = decode(&query.request, accept_language, shared).await?;
+    render(&query.request, &request, &client, None, &languages, shared).await
+}
+
+/// Checks the second factor, then logs the user in and resumes the authorization they started
+/// Failed attempts are counted like those of the login page, since codes can be guessed too,
+/// but apart from passwords so knowing the password doesn't allow more guesses.
+#[tracing::instrument(level = "debug", skip(form))]
+async fn verify(
+    form: MfaForm,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let (mut request, client, languages) = decode(&form.request, accept_language, shared).await?;
+    let params = &request.params;
+    let user_id = request.pending_auth.user_id.as_deref().or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    let keys: Vec<Key> = Some(Key::Mfa(user_id))
+        .into_iter()
+        .chain(ip.as_deref().map(Key::Ip))
+        .collect();
+    let recorded = shared.lockouts.record(&keys).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+    let recorded = match recorded {
+        Ok(recorded) => recorded,
+        Err(_) => {
+            let mut response = render(
+                &form.request,
+                &request,
+                &client,
+                Some("mfa.locked"),
+                &languages,
+                shared,
+            )
+            .await?;
+            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            return Ok(response);
+        }
+    };
+
+    let method = match (
+        form.code.as_deref(),
+        form.challenge,
+        form.credential.as_deref(),
+    ) {
+        (Some(code), _, _) => {
+            if check_code(user_id, code, params, shared).await? {
+                Some(AMR_OTP)
+            } else {
+                None
+            }
+        }
+        // A passkey can't be both factors, unless the authenticator verified the user
+        (None, Some(challenge), Some(credential))
+            if !request.pending_auth.used(AMR_HARDWARE_KEY) =>
+        {
+            if check_passkey(user_id, challenge, credential, params, shared).await? {
+                Some(AMR_HARDWARE_KEY)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    let method = match method {
+        Some(method) => method,
+        None => {
+            let locked_out = shared.lockouts.fail(&recorded).await.or_redirect(
+                OAuthError::ServerError,
+                "internal server error",
+                params,
+            )?;
+            if locked_out {
+                lockouts::locked_out(
+                    user_id,
+                    &client.id,
+                    user_agent.as_deref(),
+                    &languages,
+                    shared.global_config,
+                    shared.pool,
+                )
+                .await
+                .or_redirect(
+                    OAuthError::ServerError,
+                    "internal server error",
+                    params,
+                )?;
+            }
+            let mut response = render(
+                &form.request,
+                &request,
+                &client,
+                Some("mfa.invalid"),
+                &languages,
+                shared,
+            )
+            .await?;
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            return Ok(response);
+        }
+    };
+
+    shared.lockouts.succeed(&keys).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    request.pending_auth.add_factor(method);
+    oauth::log_in(
+        &request.params,
+        request.pending_auth,
+        &client,
+        user_agent.as_deref(),
+        shared,
+    )
+    .await
+}
+
+/// Checks a code from the authenticator app of the user, or one of their recovery codes
+async fn check_code(
+    user_id: &str,
+    code: &str,
+    params: &Params,
+    shared: SharedResources,
+) -> Result<bool, Rejection> {
+    let user = User::select(user_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    totp::check(&user, code, shared.global_config, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)
+}
+
+/// Checks one of the passkeys of the user signed the challenge
+async fn check_passkey(
+    user_id: &str,
+    challenge: String,
+    credential: &str,
+    params: &Params,
+    shared: SharedResources,
+) -> Result<bool, Rejection> {
+    let config = shared.global_config;
+    let challenge = webauthn::redeem_challenge(challenge, config, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    let assertion = match challenge {
+        Some(challenge) => {
+            webauthn::authenticate(credential, &challenge, Some(user_id), config, shared.pool)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", params)?
+        }
+        None => None,
+    };
+    Ok(assertion.is_some())
+}
+
+/// Decodes a pending login, along with the client and languages it is shown with
+async fn decode(
+    request: &str,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<(MfaJwt, Client, Vec<String>), Rejection> {
+    let config = shared.global_config;
+    let request: Option<MfaJwt> = jwt::decode(request.to_owned(), &config.token)
+        .await
+        .or_ise()?;
+    let request = request.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let params = &request.params;
+    let languages = templates::languages(params.ui_locales.as_deref(), accept_language.as_deref());
+    let client = Client::select_enabled(&params.client_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", params)?;
+    Ok((request, client, languages))
+}
+
+/// Renders the second factor page, with the branding of the client
+/// The request is passed along as is, so users only have a few minutes to complete their login
+async fn render(
+    encoded: &str,
+    request: &MfaJwt,
+    client: &Client,
+    error: Option<&str>,
+    languages: &[String],
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let params = &request.params;
+    let locale = Locale::negotiate(languages, config).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    let user_id = request.pending_auth.user_id.as_deref().or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+    let user = User::select(user_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    let factors = Factors::of(&user, &request.pending_auth, config, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+
+    let code = if factors.totp {
+        format!(
+            r#"<form method="post" action="mfa"><input type="hidden" name="request" value="{}"><input type="text" name="code" placeholder="{}" autocomplete="one-time-code" autofocus required><button type="submit">{}</button></form>"#,
+            html::escape(encoded),
+            locale.text("mfa.code", &[]),
+            locale.text("mfa.submit", &[]),
+        )
+    } else {
+        String::new()
+    };
+    let passkey = match &config.webauthn {
+        Some(webauthn_config) if !factors.passkeys.is_empty() => {
+            let (challenge, token) = webauthn::challenge(config, shared.pool).await.or_redirect(
+                OAuthError::ServerError,
+                "internal server error",
+                params,
+            )?;
+            let options =
+                webauthn::request_options(&challenge, &factors.passkeys, webauthn_config, config)
+                    .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            webauthn::form(
+                "mfa",
+                encoded,
+                &token,
+                &options,
+                &locale.text("mfa.use_passkey", &[]),
+            )
+        }
+        _ => String::new(),
+    };
+    let error = match error {
+        Some(error) => format!(r#"<p class="error">{}</p>"#, locale.text(error, &[])),
+        None => String::new(),
+    };
+
+    templates::render(
+        "mfa",
+        &[
+            ("client", &client.name),
+            ("logo", &templates::logo(client)),
+            ("error", &error),
+            ("code", &code),
+            ("passkey", &passkey),
+        ],
+        &locale,
+        Some(client),
+        config,
+    )
+    .await
+    .or_redirect(OAuthError::ServerError, "internal server error", params)
+}
@@ -1,3 +1,171 @@
+pub mod admin;
+pub mod authorize;
+pub mod clients;
+pub mod consent;
+pub mod emails;
+pub mod exports;
 pub mod key;
+pub mod magic_links;
+pub mod mfa;
+pub mod passkeys;
+pub mod passwords;
+pub mod register;
+pub mod sessions;
+pub mod signup;
 pub mod token;
+pub mod totp;
 pub mod users;
+
+use crate::{
+    config::Config,
+    db::User,
+    errors::{JsonError, TryExt},
+    jwt, limits,
+    lockouts::{self, Key, Lockouts},
+    password,
+    providers::TokenJwt,
+};
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use warp::{http::StatusCode, Filter, Rejection};
+
+/// Scope needed to use the admin API, only granted to trusted clients from the config file
+pub const ADMIN_SCOPE: &str = "admin";
+
+/// Extracts and verifies the bearer token sent by the client, which must have been granted a scope
+pub fn authenticated(
+    scope: &'static str,
+    config: &'static Config,
+) -> impl Filter<Extract = (TokenJwt,), Error = Rejection> + Clone + 'static {
+    warp::header("Authorization").and_then(move |auth: String| authenticate(auth, scope, config))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn authenticate(
+    auth: String,
+    scope: &'static str,
+    config: &'static Config,
+) -> Result<TokenJwt, Rejection> {
+    if !auth.starts_with("Bearer ") {
+        None.or_json(
+            JsonError {
+                error: "invalid authorization header",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    let token: TokenJwt = jwt::decode(auth[7..].to_owned(), &config.token)
+        .await
+        .or_ise()?
+        .or_json(
+            JsonError {
+                error: "invalid token",
+            },
+            StatusCode::UNAUTHORIZED,
+        )?;
+    if !token.granted(scope) {
+        None.or_json(
+            JsonError {
+                error: "insufficient scope",
+            },
+            StatusCode::FORBIDDEN,
+        )?;
+    }
+    Ok(token)
+}
+
+/// Extracts and verifies the bearer token sent by the client, only letting admins through
+/// The token must also come from a trusted client of the config file, in case another one was granted the scope.
+pub fn admin(
+    config: &'static Config,
+) -> impl Filter<Extract = (TokenJwt,), Error = Rejection> + Clone + 'static {
+    authenticated(ADMIN_SCOPE, config).and_then(move |token: TokenJwt| async move {
+        let trusted = config
+            .clients
+            .get(&token.client_id)
+            .map_or(false, |c| c.trusted);
+        if !trusted || !config.admins.contains(&token.sub) {
+            None.or_json(
+                JsonError {
+                    error: "insufficient privileges",
+                },
+                StatusCode::FORBIDDEN,
+            )?;
+        }
+        Ok::<_, Rejection>(token)
+    })
+}
+
+/// Extracts the IP address of the client, used for rate limiting
+pub fn client_ip(
+    config: &'static Config,
+) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone + 'static {
+    warp::header::optional("X-Forwarded-For")
+        .and(warp::addr::remote())
+        .map(
+            move |forwarded_for: Option<String>, remote: Option<SocketAddr>| {
+                limits::client_ip(forwarded_for.as_deref(), remote, &config.trusted_proxies)
+            },
+        )
+}
+
+/// Makes sure the user recently proved their identity, either by logging in or by giving their password,
+/// before a change a stolen token shouldn't be enough for
+/// Failed attempts at giving the password are counted like those of the login page.
+pub async fn reauthenticate(
+    token: &TokenJwt,
+    password: Option<&str>,
+    ip: Option<&str>,
+    user_agent: Option<&str>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<(), Rejection> {
+    let window = Duration::minutes(config.account.reauthentication_window);
+    if Utc::now().timestamp() - token.auth_time <= window.num_seconds() {
+        return Ok(());
+    }
+
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    let valid = match (password, user.password) {
+        (Some(password), Some(hash)) => {
+            let keys: Vec<Key> = Some(Key::Account(&user.id))
+                .into_iter()
+                .chain(ip.map(Key::Ip))
+                .collect();
+            let recorded = match lockouts.record(&keys).await.or_ise()? {
+                Ok(recorded) => recorded,
+                Err(_) => None.or_json(
+                    JsonError {
+                        error: "too many failed attempts",
+                    },
+                    StatusCode::TOO_MANY_REQUESTS,
+                )?,
+            };
+
+            let valid = password::verify(hash, password, &config.hash)
+                .await
+                .or_ise()?;
+            if valid {
+                lockouts.succeed(&keys).await.or_ise()?;
+            } else if lockouts.fail(&recorded).await.or_ise()? {
+                lockouts::locked_out(&user.id, &token.client_id, user_agent, &[], config, pool)
+                    .await
+                    .or_ise()?;
+            }
+            valid
+        }
+        _ => false,
+    };
+    if !valid {
+        None.or_json(
+            JsonError {
+                error: "reauthentication required",
+            },
+            StatusCode::UNAUTHORIZED,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,259 @@
+//! Registration and removal of passkeys by users, through the API
+
+use crate::{
+    accounts,
+    config::{Config, WebAuthnConfig},
+    db::{audit_events, AuditEvent, User, WebAuthnCredential},
+    errors::{JsonError, TryExt},
+    lockouts::Lockouts,
+    providers::TokenJwt,
+    routes,
+    webauthn::{self, AttestationResponse, Credential},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+/// Maximum length of passkey names, matching the database column
+const MAX_NAME_LEN: usize = 64;
+/// Name of passkeys the user didn't name
+const DEFAULT_NAME: &str = "Passkey";
+
+/// Options to create a passkey with, along with the token to send back with it
+#[derive(Debug, Serialize)]
+struct Challenge {
+    request: String,
+    options: serde_json::Value,
+}
+
+/// Passkeys are a way to log in, so adding one needs the password unless the user just logged in
+#[derive(Debug, Deserialize)]
+struct RegisterBody {
+    request: String,
+    name: Option<String>,
+    credential: Credential<AttestationResponse>,
+    password: Option<String>,
+}
+
+/// Password confirming the removal of a passkey, unnecessary right after logging in
+#[derive(Debug, Default, Deserialize)]
+struct RemoveBody {
+    password: Option<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let list = warp::path!("me" / "passkeys")
+        .and(warp::get())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |token: TokenJwt| list(token, config, pool));
+    let challenge = warp::path!("me" / "passkeys" / "challenge")
+        .and(warp::post())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |token: TokenJwt| challenge(token, config, pool));
+    let register = warp::path!("me" / "passkeys")
+        .and(warp::post())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::body::json())
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("User-Agent"))
+        .and_then(
+            move |token: TokenJwt,
+                  body: RegisterBody,
+                  ip: Option<String>,
+                  user_agent: Option<String>| {
+                register(token, body, ip, user_agent, config, pool, lockouts)
+            },
+        );
+    let remove = warp::path!("me" / "passkeys" / String)
+        .and(warp::delete())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(
+            warp::body::json()
+                .or(warp::any().map(RemoveBody::default))
+                .unify(),
+        )
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("User-Agent"))
+        .and_then(
+            move |id: String,
+                  token: TokenJwt,
+                  body: RemoveBody,
+                  ip: Option<String>,
+                  user_agent: Option<String>| {
+                remove(id, token, body, ip, user_agent, config, pool, lockouts)
+            },
+        );
+    (list).or(challenge).or(register).or(remove)
+}
+
+#[tracing::instrument(level = "debug")]
+async fn list(
+    token: TokenJwt,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    enabled(config)?;
+    let passkeys = WebAuthnCredential::select_by_user(&token.sub, pool)
+        .await
+        .or_ise()?;
+    Ok(warp::reply::json(&passkeys))
+}
+
+/// Starts the registration of a passkey, returning the options to pass to `navigator.credentials.create()`
+#[tracing::instrument(level = "debug")]
+async fn challenge(
+    token: TokenJwt,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let webauthn_config = enabled(config)?;
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    let existing = WebAuthnCredential::select_by_user(&user.id, pool)
+        .await
+        .or_ise()?;
+
+    let (challenge, request) = webauthn::challenge(config, pool).await.or_ise()?;
+    let options = webauthn::creation_options(&user, &challenge, &existing, webauthn_config, config)
+        .or_ise()?;
+    Ok(warp::reply::json(&Challenge { request, options }))
+}
+
+/// Stores the passkey the authenticator created for the challenge, making sure the user recently proved their identity
+#[tracing::instrument(level = "debug", skip(body))]
+async fn register(
+    token: TokenJwt,
+    body: RegisterBody,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<impl Reply, Rejection> {
+    let webauthn_config = enabled(config)?;
+    routes::reauthenticate(
+        &token,
+        body.password.as_deref(),
+        ip.as_deref(),
+        user_agent.as_deref(),
+        config,
+        pool,
+        lockouts,
+    )
+    .await?;
+    let name = body
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .unwrap_or(DEFAULT_NAME);
+    if name.chars().count() > MAX_NAME_LEN {
+        None.or_json(
+            JsonError {
+                error: "invalid name",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    let challenge = webauthn::redeem_challenge(body.request, config, pool)
+        .await
+        .or_ise()?
+        .or_json(
+            JsonError {
+                error: "invalid request",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    let credential = webauthn::register(&body.credential, &challenge, webauthn_config, config)
+        .or_ise()?
+        .or_json(
+            JsonError {
+                error: "invalid credential",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    if WebAuthnCredential::select(&credential.id, pool)
+        .await
+        .or_ise()?
+        .is_some()
+    {
+        None.or_json(
+            JsonError {
+                error: "passkey already registered",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+
+    let passkey = WebAuthnCredential::insert(
+        &credential.id,
+        &token.sub,
+        name,
+        &credential.public_key,
+        credential.sign_count.into(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+    AuditEvent::insert(
+        &token.sub,
+        audit_events::PASSKEY_ADDED,
+        None,
+        user_agent.as_deref(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&passkey),
+        StatusCode::CREATED,
+    ))
+}
+
+/// Removes a passkey, making sure the user recently proved their identity
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(level = "debug", skip(body))]
+async fn remove(
+    id: String,
+    token: TokenJwt,
+    body: RemoveBody,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<impl Reply, Rejection> {
+    enabled(config)?;
+    routes::reauthenticate(
+        &token,
+        body.password.as_deref(),
+        ip.as_deref(),
+        user_agent.as_deref(),
+        config,
+        pool,
+        lockouts,
+    )
+    .await?;
+    WebAuthnCredential::delete_for_user(&id, &token.sub, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    AuditEvent::insert(
+        &token.sub,
+        audit_events::PASSKEY_REMOVED,
+        None,
+        user_agent.as_deref(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn enabled(config: &'static Config) -> Result<&'static WebAuthnConfig, Rejection> {
+    config.webauthn.as_ref().or_nf()
+}
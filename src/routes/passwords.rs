@@ -0,0 +1,358 @@
+//! Hosted pages resetting forgotten passwords, along with an API for clients with their own form
+
+use crate::{
+    config::{Config, MailConfig},
+    db::User,
+    emails,
+    errors::{JsonError, TryExt},
+    limits::MailLimits,
+    password_policy, password_resets, routes,
+    templates::{self, Locale},
+};
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+/// Window over which reset requests are counted, in minutes
+const LIMIT_WINDOW: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    ui_locales: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ForgotBody {
+    email: String,
+    ui_locales: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetQuery {
+    token: String,
+    ui_locales: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckBody {
+    password: String,
+    username: Option<String>,
+}
+
+/// Strength of a password the policy accepts
+#[derive(Debug, Serialize)]
+struct Accepted {
+    score: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetForm {
+    token: String,
+    password: String,
+    ui_locales: Option<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let limits: &'static MailLimits = Box::leak(Box::new(MailLimits::new(
+        config.password.reset_limit_per_address,
+        config.password.reset_limit_per_ip,
+        Duration::minutes(LIMIT_WINDOW),
+    )));
+
+    let page = warp::path!("password" / "forgot")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |query: PageQuery, accept_language: Option<String>| {
+            page(query, accept_language, config)
+        });
+    let forgot_form = warp::path!("password" / "forgot")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |body: ForgotBody, ip: Option<String>, accept_language: Option<String>| {
+                forgot_form(body, ip, accept_language, limits, config, pool)
+            },
+        );
+    let forgot_json = warp::path!("password" / "forgot")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |body: ForgotBody, ip: Option<String>, accept_language: Option<String>| {
+                forgot_json(body, ip, accept_language, limits, config, pool)
+            },
+        );
+    let reset_page = warp::path!("password" / "reset")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |query: ResetQuery, accept_language: Option<String>| {
+            reset_page(query, accept_language, config, pool)
+        });
+    let reset = warp::path!("password" / "reset")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::header::optional("User-Agent"))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: ResetForm, user_agent: Option<String>, accept_language: Option<String>| {
+                reset(form, user_agent, accept_language, config, pool)
+            },
+        );
+    let check = warp::path!("password" / "check")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(move |body: CheckBody| check(body, config));
+    (page)
+        .or(forgot_form)
+        .or(forgot_json)
+        .or(reset_page)
+        .or(reset)
+        .or(check)
+}
+
+/// Asks the user for the address a reset link should be sent to
+#[tracing::instrument(level = "debug")]
+async fn page(
+    query: PageQuery,
+    accept_language: Option<String>,
+    config: &'static Config,
+) -> Result<Response, Rejection> {
+    enabled(config)?;
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+    let locale = Locale::negotiate(&languages, config).await.or_ise()?;
+    render_forgot(None, None, &locale, config).await
+}
+
+/// Mails a reset link from the hosted page, telling the user it was sent whether the address is known or not
+#[tracing::instrument(level = "debug", skip(limits))]
+async fn forgot_form(
+    body: ForgotBody,
+    ip: Option<String>,
+    accept_language: Option<String>,
+    limits: &'static MailLimits,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let mail_config = enabled(config)?;
+    let languages = templates::languages(body.ui_locales.as_deref(), accept_language.as_deref());
+    let locale = Locale::negotiate(&languages, config).await.or_ise()?;
+
+    let email = body.email.trim();
+    let (error, status) = if !emails::valid(email) {
+        ("forgot_password.invalid_email", StatusCode::BAD_REQUEST)
+    } else if !limits.attempt(email, ip.as_deref()) {
+        (
+            "forgot_password.rate_limited",
+            StatusCode::TOO_MANY_REQUESTS,
+        )
+    } else {
+        let response = render_forgot(Some("forgot_password.sent"), None, &locale, config).await?;
+        password_resets::request(email.to_owned(), locale, mail_config, config, pool);
+        return Ok(response);
+    };
+
+    let mut response = render_forgot(None, Some(error), &locale, config).await?;
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+/// Mails a reset link, always succeeding so addresses can't be probed
+#[tracing::instrument(level = "debug", skip(limits))]
+async fn forgot_json(
+    body: ForgotBody,
+    ip: Option<String>,
+    accept_language: Option<String>,
+    limits: &'static MailLimits,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let mail_config = enabled(config)?;
+    let email = body.email.trim();
+    if !emails::valid(email) {
+        None.or_json(
+            JsonError {
+                error: "invalid email",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+    if !limits.attempt(email, ip.as_deref()) {
+        None.or_json(
+            JsonError {
+                error: "too many requests",
+            },
+            StatusCode::TOO_MANY_REQUESTS,
+        )?;
+    }
+
+    let locale = Locale::negotiate(
+        &templates::languages(body.ui_locales.as_deref(), accept_language.as_deref()),
+        config,
+    )
+    .await
+    .or_ise()?;
+    password_resets::request(email.to_owned(), locale, mail_config, config, pool);
+    Ok(StatusCode::OK)
+}
+
+/// Asks the user who followed a reset link for their new password
+#[tracing::instrument(level = "debug", skip(query))]
+async fn reset_page(
+    query: ResetQuery,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+    password_resets::check(&query.token, config, pool)
+        .await
+        .or_ise()?
+        .or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+
+    let locale = Locale::negotiate(&languages, config).await.or_ise()?;
+    render_reset(&query.token, None, &[], &locale, config).await
+}
+
+/// Sets the new password, logging the user out everywhere
+#[tracing::instrument(level = "debug", skip(form))]
+async fn reset(
+    form: ResetForm,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let languages = templates::languages(form.ui_locales.as_deref(), accept_language.as_deref());
+    let pending = password_resets::check(&form.token, config, pool)
+        .await
+        .or_ise()?
+        .or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+    let locale = Locale::negotiate(&languages, config).await.or_ise()?;
+
+    let user = User::select(&pending.user_id, pool)
+        .await
+        .or_ise()?
+        .or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+    let user_inputs: Vec<&str> = user
+        .username
+        .iter()
+        .chain(&user.email)
+        .map(String::as_str)
+        .collect();
+    let valid = password_policy::validate(&form.password, &user_inputs, &config.password)
+        .await
+        .or_ise()?;
+    if let Err(feedback) = valid {
+        let mut response = render_reset(
+            &form.token,
+            Some(feedback.rejected.key()),
+            &feedback.hints(),
+            &locale,
+            config,
+        )
+        .await?;
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(response);
+    }
+
+    let completed = password_resets::complete(
+        &pending,
+        &form.password,
+        user_agent.as_deref(),
+        config,
+        pool,
+    )
+    .await
+    .or_ise()?;
+    if !completed {
+        None.or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+    }
+
+    templates::render("password_reset", &[], &locale, None, config)
+        .await
+        .or_ise()
+}
+
+/// Checks a password against the policy before a client submits it, returning why it was rejected
+/// along with hints to pick a better one
+#[tracing::instrument(level = "debug", skip(body))]
+async fn check(body: CheckBody, config: &'static Config) -> Result<impl Reply, Rejection> {
+    let user_inputs: Vec<&str> = body.username.iter().map(String::as_str).collect();
+    let valid = password_policy::validate(&body.password, &user_inputs, &config.password)
+        .await
+        .or_ise()?;
+    match valid {
+        Ok(score) => Ok(warp::reply::with_status(
+            warp::reply::json(&Accepted { score }),
+            StatusCode::OK,
+        )),
+        Err(feedback) => Ok(warp::reply::with_status(
+            warp::reply::json(&feedback),
+            StatusCode::BAD_REQUEST,
+        )),
+    }
+}
+
+async fn render_forgot(
+    notice: Option<&str>,
+    error: Option<&str>,
+    locale: &Locale,
+    config: &'static Config,
+) -> Result<Response, Rejection> {
+    let notice = match notice {
+        Some(notice) => format!(r#"<p class="notice">{}</p>"#, locale.text(notice, &[])),
+        None => String::new(),
+    };
+    let error = match error {
+        Some(error) => format!(r#"<p class="error">{}</p>"#, locale.text(error, &[])),
+        None => String::new(),
+    };
+    templates::render(
+        "forgot_password",
+        &[("notice", &notice), ("error", &error)],
+        locale,
+        None,
+        config,
+    )
+    .await
+    .or_ise()
+}
+
+async fn render_reset(
+    token: &str,
+    error: Option<&str>,
+    hints: &[String],
+    locale: &Locale,
+    config: &'static Config,
+) -> Result<Response, Rejection> {
+    let error = match error {
+        Some(error) => format!(
+            r#"<p class="error">{}</p>{}"#,
+            locale.text(error, &[]),
+            password_policy::hints_html(hints)
+        ),
+        None => String::new(),
+    };
+    templates::render(
+        "reset_password",
+        &[("token", token), ("error", &error)],
+        locale,
+        None,
+        config,
+    )
+    .await
+    .or_ise()
+}
+
+fn enabled(config: &'static Config) -> Result<&'static MailConfig, Rejection> {
+    config.mail.as_ref().or_nf()
+}
@@ -0,0 +1,375 @@
+//! Dynamic client registration, as described by RFC 7591 and RFC 7592
+
+use crate::{
+    clients,
+    config::{Config, RegistrationConfig},
+    db::{Client, ClientMetadata, NewClient},
+    errors::{JsonError, TryExt},
+    password, providers, redirect,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+const GRANT_TYPE: &str = "authorization_code";
+const RESPONSE_TYPE: &str = "code";
+const AUTH_METHOD_POST: &str = "client_secret_post";
+const AUTH_METHOD_NONE: &str = "none";
+
+const INVALID_METADATA: JsonError = JsonError {
+    error: "invalid_client_metadata",
+};
+
+#[derive(Debug, Deserialize)]
+struct MetadataBody {
+    redirect_uris: Vec<String>,
+    #[serde(default)]
+    grant_types: Option<Vec<String>>,
+    #[serde(default)]
+    response_types: Option<Vec<String>>,
+    #[serde(default)]
+    token_endpoint_auth_method: Option<String>,
+    client_name: Option<String>,
+    logo_uri: Option<String>,
+    scope: Option<String>,
+    #[serde(default)]
+    post_logout_redirect_uris: Vec<String>,
+    backchannel_logout_uri: Option<String>,
+}
+
+/// Metadata body once validated
+#[derive(Debug)]
+struct Metadata {
+    redirect_uris: Vec<String>,
+    public: bool,
+    client_name: Option<String>,
+    logo_uri: Option<String>,
+    scopes: Vec<String>,
+    post_logout_redirect_uris: Vec<String>,
+    backchannel_logout_uri: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RegistrationResponse {
+    client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    client_id_issued_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret_expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registration_access_token: Option<String>,
+    registration_client_uri: String,
+    redirect_uris: Vec<String>,
+    grant_types: [&'static str; 1],
+    response_types: [&'static str; 1],
+    token_endpoint_auth_method: &'static str,
+    client_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logo_uri: Option<String>,
+    scope: String,
+    post_logout_redirect_uris: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backchannel_logout_uri: Option<String>,
+}
+
+impl RegistrationResponse {
+    fn new(client: Client, config: &Config) -> Self {
+        Self {
+            registration_client_uri: format!("{}/register/{}", config.root_uri, client.id),
+            client_id: client.id,
+            client_secret: None,
+            client_id_issued_at: client.inserted_at.timestamp(),
+            client_secret_expires_at: None,
+            registration_access_token: None,
+            redirect_uris: client.redirect_urls,
+            grant_types: [GRANT_TYPE],
+            response_types: [RESPONSE_TYPE],
+            token_endpoint_auth_method: match client.secret {
+                Some(_) => AUTH_METHOD_POST,
+                None => AUTH_METHOD_NONE,
+            },
+            client_name: client.name,
+            logo_uri: client.logo_uri,
+            scope: client.scopes.join(" "),
+            post_logout_redirect_uris: client.post_logout_redirect_urls,
+            backchannel_logout_uri: client.backchannel_logout_url,
+        }
+    }
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let register = warp::path!("register")
+        .and(warp::post())
+        .and(bearer())
+        .and(warp::body::json())
+        .and_then(move |token: String, body: MetadataBody| register(token, body, config, pool));
+    let read = warp::path!("register" / String)
+        .and(warp::get())
+        .and(bearer())
+        .and_then(move |id: String, token: String| read(id, token, config, pool));
+    let update = warp::path!("register" / String)
+        .and(warp::put())
+        .and(bearer())
+        .and(warp::body::json())
+        .and_then(move |id: String, token: String, body: MetadataBody| {
+            update(id, token, body, config, pool)
+        });
+    let delete = warp::path!("register" / String)
+        .and(warp::delete())
+        .and(bearer())
+        .and_then(move |id: String, token: String| delete(id, token, config, pool));
+    (register).or(read).or(update).or(delete)
+}
+
+/// Extracts the raw bearer token from the authorization header
+fn bearer() -> impl Filter<Extract = (String,), Error = Rejection> + Clone + 'static {
+    warp::header("Authorization").and_then(|auth: String| async move {
+        if !auth.starts_with("Bearer ") {
+            None.or_json(
+                JsonError {
+                    error: "invalid_token",
+                },
+                StatusCode::UNAUTHORIZED,
+            )?;
+        }
+        Ok::<_, Rejection>(auth[7..].to_owned())
+    })
+}
+
+#[tracing::instrument(level = "debug", skip(token))]
+async fn register(
+    token: String,
+    body: MetadataBody,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let registration = enabled(config)?;
+    if !registration.initial_access_tokens.contains(&token) {
+        None.or_json(
+            JsonError {
+                error: "invalid_token",
+            },
+            StatusCode::UNAUTHORIZED,
+        )?;
+    }
+    let metadata = validate(body, registration)?;
+
+    let id = clients::generate_id();
+    let secret = if metadata.public {
+        None
+    } else {
+        Some(clients::generate_secret())
+    };
+    let secret_hash = match &secret {
+        Some(secret) => Some(password::hash(secret, &config.hash).await.or_ise()?),
+        None => None,
+    };
+    let registration_token = clients::generate_secret();
+    let registration_token_hash = password::hash(&registration_token, &config.hash)
+        .await
+        .or_ise()?;
+
+    let client = Client::insert(
+        NewClient {
+            id: &id,
+            name: metadata.client_name.as_deref().unwrap_or(&id),
+            logo_uri: metadata.logo_uri.as_deref(),
+            secret: secret_hash.as_deref(),
+            redirect_urls: &metadata.redirect_uris,
+            scopes: &metadata.scopes,
+            trusted: false,
+            registration_token: Some(&registration_token_hash),
+            post_logout_redirect_urls: &metadata.post_logout_redirect_uris,
+            backchannel_logout_url: metadata.backchannel_logout_uri.as_deref(),
+            primary_color: None,
+            background_color: None,
+            theme: None,
+            acr_values: &[],
+        },
+        pool,
+    )
+    .await
+    .or_ise()?;
+
+    let response = RegistrationResponse {
+        client_secret_expires_at: secret.as_ref().map(|_| 0),
+        client_secret: secret,
+        registration_access_token: Some(registration_token),
+        ..RegistrationResponse::new(client, config)
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        StatusCode::CREATED,
+    ))
+}
+
+#[tracing::instrument(level = "debug", skip(token))]
+async fn read(
+    id: String,
+    token: String,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let client = authorize(&id, &token, config, pool).await?;
+    Ok(warp::reply::json(&RegistrationResponse::new(
+        client, config,
+    )))
+}
+
+#[tracing::instrument(level = "debug", skip(token))]
+async fn update(
+    id: String,
+    token: String,
+    body: MetadataBody,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let client = authorize(&id, &token, config, pool).await?;
+    let metadata = validate(body, enabled(config)?)?;
+    if metadata.public != client.secret.is_none() {
+        None.or_json(INVALID_METADATA, StatusCode::BAD_REQUEST)?;
+    }
+
+    let client = Client::update_metadata(
+        &id,
+        ClientMetadata {
+            name: metadata.client_name.as_deref().unwrap_or(&id),
+            logo_uri: metadata.logo_uri.as_deref(),
+            redirect_urls: &metadata.redirect_uris,
+            scopes: &metadata.scopes,
+            post_logout_redirect_urls: &metadata.post_logout_redirect_uris,
+            backchannel_logout_url: metadata.backchannel_logout_uri.as_deref(),
+        },
+        pool,
+    )
+    .await
+    .or_ise()?
+    .or_nf()?;
+    Ok(warp::reply::json(&RegistrationResponse::new(
+        client, config,
+    )))
+}
+
+#[tracing::instrument(level = "debug", skip(token))]
+async fn delete(
+    id: String,
+    token: String,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    authorize(&id, &token, config, pool).await?;
+    Client::delete(&id, pool).await.or_ise()?.or_nf()?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn enabled(config: &'static Config) -> Result<&'static RegistrationConfig, Rejection> {
+    config.registration.as_ref().or_nf()
+}
+
+/// Selects a registered client, making sure the registration access token matches
+async fn authorize(
+    id: &str,
+    token: &str,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Client, Rejection> {
+    enabled(config)?;
+
+    // Don't leak whether the client exists to callers without a valid token
+    let client = Client::select(id, pool).await.or_ise()?;
+    let hash = client.as_ref().and_then(|c| c.registration_token.clone());
+    let valid = match hash {
+        Some(hash) => password::verify(hash, token, &config.hash).await.or_ise()?,
+        None => {
+            password::verify_nothing(token, &config.hash)
+                .await
+                .or_ise()?;
+            false
+        }
+    };
+
+    match client {
+        Some(client) if valid => Ok(client),
+        _ => None.or_json(
+            JsonError {
+                error: "invalid_token",
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+    }
+}
+
+/// Validates the metadata sent by a client, only supporting the authorization code flow
+fn validate(body: MetadataBody, config: &RegistrationConfig) -> Result<Metadata, Rejection> {
+    if body.redirect_uris.is_empty()
+        || !body
+            .redirect_uris
+            .iter()
+            .chain(&body.post_logout_redirect_uris)
+            .all(|u| redirect::valid_registration(u))
+    {
+        None.or_json(
+            JsonError {
+                error: "invalid_redirect_uri",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+
+    // Logout tokens are sent to the URI and logos are shown on hosted pages, by anonymous clients
+    if body
+        .backchannel_logout_uri
+        .iter()
+        .chain(&body.logo_uri)
+        .any(|u| !clients::valid_public_url(u))
+    {
+        None.or_json(INVALID_METADATA, StatusCode::BAD_REQUEST)?;
+    }
+
+    if body
+        .grant_types
+        .map_or(false, |g| g.iter().any(|g| g != GRANT_TYPE))
+        || body
+            .response_types
+            .map_or(false, |r| r.iter().any(|r| r != RESPONSE_TYPE))
+    {
+        None.or_json(INVALID_METADATA, StatusCode::BAD_REQUEST)?;
+    }
+
+    let public = match body.token_endpoint_auth_method.as_deref() {
+        None | Some(AUTH_METHOD_POST) => Some(false),
+        Some(AUTH_METHOD_NONE) => Some(true),
+        Some(_) => None,
+    }
+    .or_json(INVALID_METADATA, StatusCode::BAD_REQUEST)?;
+
+    if body
+        .client_name
+        .as_ref()
+        .map_or(false, |n| n.chars().count() > clients::MAX_LEN)
+    {
+        None.or_json(INVALID_METADATA, StatusCode::BAD_REQUEST)?;
+    }
+
+    let scopes = match &body.scope {
+        Some(scope) => providers::split_scope(scope),
+        None => config.scopes.clone(),
+    };
+    if scopes.iter().any(|s| !config.scopes.contains(s)) {
+        None.or_json(INVALID_METADATA, StatusCode::BAD_REQUEST)?;
+    }
+
+    Ok(Metadata {
+        redirect_uris: body.redirect_uris,
+        public,
+        client_name: body.client_name,
+        logo_uri: body.logo_uri,
+        scopes,
+        post_logout_redirect_uris: body.post_logout_redirect_uris,
+        backchannel_logout_uri: body.backchannel_logout_uri,
+    })
+}
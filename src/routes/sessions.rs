@@ -0,0 +1,296 @@
+use crate::{
+    accounts,
+    config::Config,
+    db::{audit_events, AuditEvent, Client, Session},
+    errors::TryExt,
+    jwt, logout,
+    providers::TokenJwt,
+    redirect, routes, sessions,
+    templates::{self, Locale},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use url::Url;
+use warp::{
+    http::{header, HeaderValue, StatusCode, Uri},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EndSessionQuery {
+    id_token_hint: Option<String>,
+    client_id: Option<String>,
+    post_logout_redirect_uri: Option<String>,
+    state: Option<String>,
+    ui_locales: Option<String>,
+}
+
+/// Logout waiting for the user to confirm it, only valid for the session it was shown for
+#[derive(Debug, Serialize, Deserialize)]
+struct EndSessionJwt {
+    query: EndSessionQuery,
+    sid: String,
+}
+
+impl jwt::Purpose for EndSessionJwt {
+    const PURPOSE: &'static str = "end_session";
+}
+
+#[derive(Debug, Deserialize)]
+struct EndSessionForm {
+    request: String,
+}
+
+/// Logout request once validated
+#[derive(Debug)]
+struct Logout {
+    client: Option<Client>,
+    redirect_uri: Option<Uri>,
+    languages: Vec<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let end_session = warp::path!("end_session")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::cookie::optional(sessions::COOKIE))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |query: EndSessionQuery,
+                  cookie: Option<String>,
+                  accept_language: Option<String>| {
+                end_session(query, cookie, accept_language, config, pool)
+            },
+        );
+    let confirm = warp::path!("end_session")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::cookie::optional(sessions::COOKIE))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: EndSessionForm, cookie: Option<String>, accept_language: Option<String>| {
+                confirm(form, cookie, accept_language, config, pool)
+            },
+        );
+    let list = warp::path!("me" / "sessions")
+        .and(warp::get())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |token: TokenJwt| list(token, pool));
+    let terminate = warp::path!("me" / "sessions" / String)
+        .and(warp::delete())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |id: String, token: TokenJwt| terminate(id, token, pool));
+    (end_session).or(confirm).or(list).or(terminate)
+}
+
+/// Logs the user out of Vaulth, then sends them back to the client if it asked for it
+/// Unless the client proves the logout comes from it with a token it received for the user,
+/// the user is asked to confirm it, so other sites can't log them out.
+#[tracing::instrument(level = "debug", skip(cookie))]
+async fn end_session(
+    mut query: EndSessionQuery,
+    cookie: Option<String>,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+
+    // The hint also tells which client is asking, expired ones being ignored
+    let hint: Option<TokenJwt> = match &query.id_token_hint {
+        Some(hint) => jwt::decode(hint.clone(), &config.token).await.or_ise()?,
+        None => None,
+    };
+    if let Some(hint) = &hint {
+        match &query.client_id {
+            Some(client_id) if *client_id != hint.client_id => {
+                None.or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+            }
+            Some(_) => (),
+            None => query.client_id = Some(hint.client_id.clone()),
+        }
+    }
+    let logout = validate(&query, languages, pool).await?;
+
+    let session = sessions::current(cookie.clone(), config, pool)
+        .await
+        .or_ise()?;
+    if let Some(session) = session {
+        if hint.map_or(true, |h| h.sub != session.user_id) {
+            let request = jwt::encode(
+                EndSessionJwt {
+                    query,
+                    sid: session.id,
+                },
+                &config.token,
+            )
+            .await
+            .or_ise()?;
+            let locale = Locale::negotiate(&logout.languages, config)
+                .await
+                .or_ise()?;
+            let logo = logout
+                .client
+                .as_ref()
+                .map(templates::logo)
+                .unwrap_or_default();
+            return templates::render(
+                "logout",
+                &[("logo", &logo), ("request", &request)],
+                &locale,
+                logout.client.as_ref(),
+                config,
+            )
+            .await
+            .or_ise();
+        }
+    }
+
+    finish(logout, query.client_id, cookie, config, pool).await
+}
+
+/// Logs the user out once they confirmed it
+#[tracing::instrument(level = "debug", skip(cookie))]
+async fn confirm(
+    form: EndSessionForm,
+    cookie: Option<String>,
+    accept_language: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    let request: Option<EndSessionJwt> = jwt::decode(form.request, &config.token).await.or_ise()?;
+    let request = request.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let EndSessionJwt { query, sid } = request;
+    let languages = templates::languages(query.ui_locales.as_deref(), accept_language.as_deref());
+    let logout = validate(&query, languages, pool).await?;
+
+    // Users who logged in again since the form was shown are asked again
+    let session = sessions::current(cookie.clone(), config, pool)
+        .await
+        .or_ise()?;
+    if session.map_or(false, |s| s.id != sid) {
+        None.or_page(
+            "invalid_request",
+            StatusCode::BAD_REQUEST,
+            &logout.languages,
+        )?;
+    }
+
+    finish(logout, query.client_id, cookie, config, pool).await
+}
+
+/// Checks the client and where the user should be sent before actually logging them out
+async fn validate(
+    query: &EndSessionQuery,
+    languages: Vec<String>,
+    pool: &'static PgPool,
+) -> Result<Logout, Rejection> {
+    let client = match &query.client_id {
+        Some(client_id) => Some(
+            Client::select_enabled(client_id, pool)
+                .await
+                .or_ise()?
+                .or_page("invalid_client", StatusCode::BAD_REQUEST, &languages)?,
+        ),
+        None => None,
+    };
+
+    let redirect_uri = match &query.post_logout_redirect_uri {
+        Some(uri) => {
+            let client =
+                client
+                    .as_ref()
+                    .or_page("invalid_request", StatusCode::BAD_REQUEST, &languages)?;
+            if !redirect::matches(&client.post_logout_redirect_urls, uri) {
+                None.or_page(
+                    "invalid_post_logout_redirect_uri",
+                    StatusCode::BAD_REQUEST,
+                    &languages,
+                )?;
+            }
+
+            let mut uri = Url::parse(uri).or_ise()?;
+            if let Some(state) = &query.state {
+                uri.query_pairs_mut().append_pair("state", state);
+            }
+            Some(Uri::from_maybe_shared(uri.into_string()).or_ise()?)
+        }
+        None => None,
+    };
+
+    Ok(Logout {
+        client,
+        redirect_uri,
+        languages,
+    })
+}
+
+/// Ends the session, then sends the user back to the client or tells them they are logged out
+async fn finish(
+    logout: Logout,
+    client_id: Option<String>,
+    cookie: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<Response, Rejection> {
+    if let Some(session) = sessions::end(cookie, config, pool).await.or_ise()? {
+        logout::notify(&session, pool).await.or_ise()?;
+        AuditEvent::insert(
+            &session.user_id,
+            audit_events::LOGOUT,
+            client_id.as_deref(),
+            None,
+            pool,
+        )
+        .await
+        .or_ise()?;
+    }
+
+    let mut response = match logout.redirect_uri {
+        Some(uri) => warp::redirect::temporary(uri).into_response(),
+        None => {
+            let locale = Locale::negotiate(&logout.languages, config)
+                .await
+                .or_ise()?;
+            templates::render("logged_out", &[], &locale, logout.client.as_ref(), config)
+                .await
+                .or_ise()?
+        }
+    };
+    let cookie = HeaderValue::from_str(&sessions::clear_cookie(config)).or_ise()?;
+    response.headers_mut().append(header::SET_COOKIE, cookie);
+    Ok(response)
+}
+
+#[tracing::instrument(level = "debug")]
+async fn list(token: TokenJwt, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    let sessions = Session::select_active_by_user(&token.sub, pool)
+        .await
+        .or_ise()?;
+    Ok(warp::reply::json(&sessions))
+}
+
+#[tracing::instrument(level = "debug")]
+async fn terminate(
+    id: String,
+    token: TokenJwt,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let session = Session::delete_for_user(&id, &token.sub, pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    logout::notify(&session, pool).await.or_ise()?;
+    AuditEvent::insert(&token.sub, audit_events::LOGOUT, None, None, pool)
+        .await
+        .or_ise()?;
+    Ok(StatusCode::NO_CONTENT)
+}
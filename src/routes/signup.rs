@@ -0,0 +1,323 @@
+//! Hosted signup page, the only way new users are registered
+
+use crate::{
+    config::Config,
+    db::{self, audit_events, AuditEvent, Client, User},
+    emails,
+    errors::{OAuthError, TryExt},
+    ids, jwt, password, password_policy,
+    providers::{
+        self,
+        oauth::{self, SharedResources},
+        Authentication, SignupJwt,
+    },
+    templates::{self, Locale},
+    usernames::{self, Unavailable},
+};
+use serde::Deserialize;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+struct SignupQuery {
+    request: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignupForm {
+    request: String,
+    username: String,
+    password: Option<String>,
+}
+
+pub fn handler(
+    shared: SharedResources,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let page = warp::path!("signup")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(move |query: SignupQuery, accept_language: Option<String>| {
+            page(query, accept_language, shared)
+        });
+    let signup = warp::path!("signup")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::header::optional("User-Agent"))
+        .and(warp::header::optional("Accept-Language"))
+        .and_then(
+            move |form: SignupForm, user_agent: Option<String>, accept_language: Option<String>| {
+                signup(form, user_agent, accept_language, shared)
+            },
+        );
+    (page).or(signup)
+}
+
+/// Asks the user to pick a username, and a password unless they come from a provider
+#[tracing::instrument(level = "debug")]
+async fn page(
+    query: SignupQuery,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let (request, client, languages) = decode(&query.request, accept_language, shared).await?;
+    render(
+        &query.request,
+        &request,
+        &client,
+        None,
+        &[],
+        &languages,
+        shared.global_config,
+    )
+    .await
+}
+
+/// Registers the user, then resumes the authorization they started
+#[tracing::instrument(level = "debug", skip(form))]
+async fn signup(
+    form: SignupForm,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<Response, Rejection> {
+    let config = shared.global_config;
+    let (request, client, languages) = decode(&form.request, accept_language, shared).await?;
+    let params = &request.params;
+
+    // Submitting the form twice, or from two tabs, logs in to the account created the first time
+    if let Some(auth) = registered(&request, shared).await? {
+        return oauth::log_in(params, auth, &client, user_agent.as_deref(), shared).await;
+    }
+
+    let username = usernames::check(&form.username, None, config, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    let username = match username {
+        Ok(username) => username,
+        Err(unavailable) => {
+            let key = match unavailable {
+                Unavailable::Invalid => "signup.invalid_username",
+                Unavailable::Reserved => "signup.reserved_username",
+                Unavailable::Blocked => "signup.inappropriate_username",
+                Unavailable::Taken | Unavailable::Released => "signup.username_taken",
+            };
+            return rejected(
+                &form.request,
+                &request,
+                &client,
+                key,
+                &[],
+                &languages,
+                config,
+            )
+            .await;
+        }
+    };
+
+    // Users coming from a provider log in with it, others need a password
+    let hash = match (&request.auth, form.password.as_deref()) {
+        (Some(_), _) => None,
+        (None, Some(password)) if !password.is_empty() => {
+            let valid = password_policy::validate(password, &[&username.name], &config.password)
+                .await
+                .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+            if let Err(feedback) = valid {
+                return rejected(
+                    &form.request,
+                    &request,
+                    &client,
+                    feedback.rejected.key(),
+                    &feedback.hints(),
+                    &languages,
+                    config,
+                )
+                .await;
+            }
+            Some(password::hash(password, &config.hash).await.or_redirect(
+                OAuthError::ServerError,
+                "internal server error",
+                params,
+            )?)
+        }
+        (None, _) => {
+            return rejected(
+                &form.request,
+                &request,
+                &client,
+                "signup.missing_password",
+                &[],
+                &languages,
+                config,
+            )
+            .await;
+        }
+    };
+
+    let id = ids::generate(&config.account);
+    let provider = request
+        .auth
+        .as_ref()
+        .map(|a| (a.provider_name.as_str(), a.provider_id.as_str()));
+    let user = match User::insert(
+        &id,
+        &username.name,
+        &username.skeleton,
+        hash.as_deref(),
+        provider,
+        shared.pool,
+    )
+    .await
+    {
+        Ok(user) => user,
+        // Another submission of the same request registered the identity in the meantime
+        Err(e) if db::unique_violation(&e) => match registered(&request, shared).await? {
+            Some(auth) => {
+                return oauth::log_in(params, auth, &client, user_agent.as_deref(), shared).await
+            }
+            None => Err(e).or_redirect(OAuthError::ServerError, "internal server error", params)?,
+        },
+        Err(e) => Err(e).or_redirect(OAuthError::ServerError, "internal server error", params)?,
+    };
+    AuditEvent::insert(
+        &user.id,
+        audit_events::SIGNUP,
+        Some(&client.id),
+        user_agent.as_deref(),
+        shared.pool,
+    )
+    .await
+    .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    if let Some(email) = &request.email {
+        emails::import(&user.id, email, shared.pool)
+            .await
+            .or_redirect(OAuthError::ServerError, "internal server error", params)?;
+    }
+
+    let mut auth = request
+        .auth
+        .unwrap_or_else(|| Authentication::new(providers::LOCAL, &user.id, None));
+    auth.user_id = Some(user.id);
+    oauth::log_in(params, auth, &client, user_agent.as_deref(), shared).await
+}
+
+/// Decodes a signup request, along with the client and languages it is shown with
+async fn decode(
+    request: &str,
+    accept_language: Option<String>,
+    shared: SharedResources,
+) -> Result<(SignupJwt, Client, Vec<String>), Rejection> {
+    let config = shared.global_config;
+    let request: Option<SignupJwt> = jwt::decode(request.to_owned(), &config.token)
+        .await
+        .or_ise()?;
+    let request = request.or_page(
+        "invalid_request",
+        StatusCode::BAD_REQUEST,
+        &templates::languages(None, accept_language.as_deref()),
+    )?;
+    let params = &request.params;
+    let languages = templates::languages(params.ui_locales.as_deref(), accept_language.as_deref());
+    let client = Client::select_enabled(&params.client_id, shared.pool)
+        .await
+        .or_redirect(OAuthError::ServerError, "internal server error", params)?
+        .or_redirect(OAuthError::InvalidRequest, "invalid client_id", params)?;
+    Ok((request, client, languages))
+}
+
+/// Identity of a request coming from a provider, along with the user already registered with it if any
+async fn registered(
+    request: &SignupJwt,
+    shared: SharedResources,
+) -> Result<Option<Authentication>, Rejection> {
+    let auth = match &request.auth {
+        Some(auth) => auth,
+        None => return Ok(None),
+    };
+    let user_id = User::select_by_provider(&auth.provider_name, &auth.provider_id, shared.pool)
+        .await
+        .or_redirect(
+            OAuthError::ServerError,
+            "internal server error",
+            &request.params,
+        )?;
+    Ok(user_id.map(|user_id| Authentication {
+        user_id: Some(user_id),
+        ..auth.clone()
+    }))
+}
+
+/// Shows the signup page again with an error, and hints to fix it
+async fn rejected(
+    encoded: &str,
+    request: &SignupJwt,
+    client: &Client,
+    error: &str,
+    hints: &[String],
+    languages: &[String],
+    config: &'static Config,
+) -> Result<Response, Rejection> {
+    let mut response = render(
+        encoded,
+        request,
+        client,
+        Some(error),
+        hints,
+        languages,
+        config,
+    )
+    .await?;
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    Ok(response)
+}
+
+/// Renders the signup page, with the branding of the client
+/// The request is passed on as it came so retrying doesn't extend its lifetime
+async fn render(
+    encoded: &str,
+    request: &SignupJwt,
+    client: &Client,
+    error: Option<&str>,
+    hints: &[String],
+    languages: &[String],
+    config: &'static Config,
+) -> Result<Response, Rejection> {
+    let params = &request.params;
+    let locale = Locale::negotiate(languages, config).await.or_redirect(
+        OAuthError::ServerError,
+        "internal server error",
+        params,
+    )?;
+
+    let password = match request.auth {
+        Some(_) => String::new(),
+        None => format!(
+            r#"<input type="password" name="password" placeholder="{}" autocomplete="new-password" required>"#,
+            locale.text("signup.password", &[]),
+        ),
+    };
+    let error = match error {
+        Some(error) => format!(
+            r#"<p class="error">{}</p>{}"#,
+            locale.text(error, &[]),
+            password_policy::hints_html(hints)
+        ),
+        None => String::new(),
+    };
+
+    templates::render(
+        "signup",
+        &[
+            ("client", &client.name),
+            ("logo", &templates::logo(client)),
+            ("error", &error),
+            ("password", &password),
+            ("request", encoded),
+            ("query", &params.to_query()),
+        ],
+        &locale,
+        Some(client),
+        config,
+    )
+    .await
+    .or_redirect(OAuthError::ServerError, "internal server error", params)
+}
@@ -1,9 +1,14 @@
 use crate::{
+    acr, clients,
     config::Config,
-    db::User,
+    db::{AuthorizationCode, Client, User},
+    emails,
     errors::{JsonError, TryExt},
     jwt,
-    providers::{CodeJwt, TokenJwt},
+    lockouts::{Key, Lockouts},
+    pkce,
+    providers::{self, CodeJwt, TokenJwt},
+    routes,
 };
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
@@ -12,8 +17,10 @@ use warp::{http::StatusCode, Filter, Rejection, Reply};
 #[derive(Debug, Deserialize)]
 pub struct TokenRequestBody {
     client_id: String,
-    client_secret: String,
+    client_secret: Option<String>,
     code: String,
+    /// PKCE verifier, required when the authorization request had a challenge
+    code_verifier: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -25,23 +32,25 @@ struct SuccessResponse {
 pub fn handler(
     config: &'static Config,
     pool: &'static PgPool,
+    lockouts: &'static Lockouts,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
-    let token = warp::path!("token")
+    warp::path!("token")
         .and(warp::body::json())
-        .and_then(move |body: TokenRequestBody| token(body, config, pool));
-    let token_user = warp::path!("token" / String)
-        .and(warp::body::json())
-        .and_then(move |user: String, body: TokenRequestBody| token_user(user, body, config, pool));
-    (token).or(token_user)
+        .and(routes::client_ip(config))
+        .and_then(move |body: TokenRequestBody, ip: Option<String>| {
+            token(body, ip, config, pool, lockouts)
+        })
 }
 
-#[tracing::instrument(level = "debug")]
+#[tracing::instrument(level = "debug", skip(body))]
 async fn token(
     body: TokenRequestBody,
+    ip: Option<String>,
     config: &'static Config,
     pool: &'static PgPool,
+    lockouts: &'static Lockouts,
 ) -> Result<impl Reply, Rejection> {
-    let code = verify(body, config).await?;
+    let code = verify(body, ip, config, pool, lockouts).await?;
     let user: String = User::select_by_provider(&code.provider_name, &code.provider_id, pool)
         .await
         .or_ise()?
@@ -51,72 +60,69 @@ async fn token(
             },
             StatusCode::BAD_REQUEST,
         )?;
+    enabled(&user, pool).await?;
 
-    let token = jwt::encode(TokenJwt { sub: user }, &config.token)
-        .await
-        .or_ise()?;
+    // Contact details are only shared with clients that were granted access to them
+    let (email, email_verified) = if providers::split_scope(&code.scope)
+        .iter()
+        .any(|s| s == emails::SCOPE)
+    {
+        let user = User::select(&user, pool).await.or_ise()?.or_nf()?;
+        let verified = user.email.as_ref().map(|_| user.email_verified);
+        (user.email, verified)
+    } else {
+        (None, None)
+    };
+
+    let token = jwt::encode(
+        TokenJwt {
+            sub: user,
+            client_id: code.client_id,
+            scope: code.scope,
+            auth_time: code.auth_time,
+            acr: Some(acr::reached(&code.amr).to_owned()),
+            amr: code.amr,
+            email,
+            email_verified,
+        },
+        &config.token,
+    )
+    .await
+    .or_ise()?;
     Ok(warp::reply::json(&SuccessResponse {
         access_token: token,
         expires_in: config.token.duration,
     }))
 }
 
-#[tracing::instrument(level = "debug")]
-async fn token_user(
-    given_user: String,
+/// Checks the code along with the secret of the client, counting failed attempts at guessing secrets,
+/// and the PKCE verifier when the code was requested with a challenge
+/// Codes can only be redeemed once, even when the verifier is wrong.
+#[tracing::instrument(level = "debug", skip(body))]
+async fn verify(
     body: TokenRequestBody,
+    ip: Option<String>,
     config: &'static Config,
     pool: &'static PgPool,
-) -> Result<impl Reply, Rejection> {
-    let code = verify(body, config).await?;
-    let user: Option<String> =
-        User::select_by_provider(&code.provider_name, &code.provider_id, pool)
-            .await
-            .or_ise()?;
-
-    if let Some(user) = user {
-        if user != given_user {
-            None.or_json(
-                JsonError {
-                    error: "mismatched users",
-                },
-                StatusCode::BAD_REQUEST,
-            )?;
-        }
-
-        let token = jwt::encode(TokenJwt { sub: user }, &config.token)
-            .await
-            .or_ise()?;
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&SuccessResponse {
-                access_token: token,
-                expires_in: config.token.duration,
-            }),
-            StatusCode::OK,
-        ));
-    }
-
-    User::register_by_provider(&given_user, &code.provider_name, &code.provider_id, pool)
-        .await
-        .or_ise()?;
-
-    let token = jwt::encode(TokenJwt { sub: given_user }, &config.token)
-        .await
-        .or_ise()?;
-    Ok(warp::reply::with_status(
-        warp::reply::json(&SuccessResponse {
-            access_token: token,
-            expires_in: config.token.duration,
-        }),
-        StatusCode::CREATED,
-    ))
-}
+    lockouts: &'static Lockouts,
+) -> Result<CodeJwt, Rejection> {
+    let TokenRequestBody {
+        client_id,
+        client_secret,
+        code,
+        code_verifier,
+    } = body;
+    let code: CodeJwt = jwt::decode(code, &config.token).await.or_ise()?.or_json(
+        JsonError {
+            error: "invalid code",
+        },
+        StatusCode::BAD_REQUEST,
+    )?;
 
-#[tracing::instrument(level = "debug")]
-async fn verify(body: TokenRequestBody, config: &'static Config) -> Result<CodeJwt, Rejection> {
-    let code: CodeJwt = jwt::decode(body.code, &config.token)
+    let client = Client::select_enabled(&code.client_id, pool)
         .await
         .or_ise()?
+        .filter(|c| c.id == client_id)
         .or_json(
             JsonError {
                 error: "invalid code",
@@ -124,14 +130,28 @@ async fn verify(body: TokenRequestBody, config: &'static Config) -> Result<CodeJ
             StatusCode::BAD_REQUEST,
         )?;
 
-    let client = config.clients.get(&code.client_id).or_json(
-        JsonError {
-            error: "invalid code",
-        },
-        StatusCode::BAD_REQUEST,
-    )?;
-
-    if body.client_secret != client.client_secret {
+    // Public clients have no secret to guess
+    let keys: Vec<Key> = match client.secret {
+        Some(_) => Some(Key::Client(&client.id))
+            .into_iter()
+            .chain(ip.as_deref().map(Key::Ip))
+            .collect(),
+        None => Vec::new(),
+    };
+    let recorded = match lockouts.record(&keys).await.or_ise()? {
+        Ok(recorded) => recorded,
+        Err(_) => None.or_json(
+            JsonError {
+                error: "too many failed attempts",
+            },
+            StatusCode::TOO_MANY_REQUESTS,
+        )?,
+    };
+    if !clients::authenticate(&client, client_secret.as_deref(), config, pool)
+        .await
+        .or_ise()?
+    {
+        lockouts.fail(&recorded).await.or_ise()?;
         None.or_json(
             JsonError {
                 error: "invalid client_secret",
@@ -139,6 +159,43 @@ async fn verify(body: TokenRequestBody, config: &'static Config) -> Result<CodeJ
             StatusCode::BAD_REQUEST,
         )?;
     }
+    lockouts.succeed(&keys).await.or_ise()?;
+
+    if !AuthorizationCode::consume(&code.jti, pool).await.or_ise()? {
+        None.or_json(
+            JsonError {
+                error: "invalid code",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+    // Codes of public clients are always bound to a verifier, the client having no secret
+    let verified = match (&code.code_challenge, &code_verifier) {
+        (Some(challenge), Some(verifier)) => pkce::verify(verifier, challenge),
+        (Some(_), None) => false,
+        (None, _) => client.secret.is_some(),
+    };
+    if !verified {
+        None.or_json(
+            JsonError {
+                error: "invalid code_verifier",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
 
     Ok(code)
 }
+
+/// Makes sure a user hasn't been disabled since the code was issued
+async fn enabled(user_id: &str, pool: &'static PgPool) -> Result<(), Rejection> {
+    if User::disabled(user_id, pool).await.or_ise()? {
+        None.or_json(
+            JsonError {
+                error: "user disabled",
+            },
+            StatusCode::FORBIDDEN,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+//! Setup of authenticator apps and recovery codes by users, through the API
+
+use crate::{
+    accounts,
+    config::{Config, TotpConfig},
+    db::{audit_events, AuditEvent, RecoveryCode, User},
+    errors::{JsonError, TryExt},
+    lockouts::{self, Key, Lockouts},
+    providers::TokenJwt,
+    routes, totp,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+/// Code from the authenticator app of the user, or a recovery code when disabling
+#[derive(Debug, Deserialize)]
+struct CodeBody {
+    code: String,
+}
+
+/// Secret to add to an authenticator app
+#[derive(Debug, Serialize)]
+struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+/// Recovery codes, only shown once
+#[derive(Debug, Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+pub fn handler(
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+    let enroll = warp::path!("me" / "totp")
+        .and(warp::post())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and_then(move |token: TokenJwt| enroll(token, config, pool));
+    let confirm = warp::path!("me" / "totp" / "confirm")
+        .and(warp::post())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::body::json())
+        .and(warp::header::optional("User-Agent"))
+        .and_then(
+            move |token: TokenJwt, body: CodeBody, user_agent: Option<String>| {
+                confirm(token, body, user_agent, config, pool)
+            },
+        );
+    let disable = warp::path!("me" / "totp")
+        .and(warp::delete())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::body::json())
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("User-Agent"))
+        .and_then(
+            move |token: TokenJwt,
+                  body: CodeBody,
+                  ip: Option<String>,
+                  user_agent: Option<String>| {
+                disable(token, body, ip, user_agent, config, pool, lockouts)
+            },
+        );
+    let regenerate = warp::path!("me" / "totp" / "recovery-codes")
+        .and(warp::post())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::body::json())
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("User-Agent"))
+        .and_then(
+            move |token: TokenJwt,
+                  body: CodeBody,
+                  ip: Option<String>,
+                  user_agent: Option<String>| {
+                regenerate(token, body, ip, user_agent, config, pool, lockouts)
+            },
+        );
+    (enroll).or(confirm).or(disable).or(regenerate)
+}
+
+/// Generates a secret for the user to add to their app, replacing any unconfirmed one
+#[tracing::instrument(level = "debug")]
+async fn enroll(
+    token: TokenJwt,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let totp_config = enabled(config)?;
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    if user.totp_enabled() {
+        None.or_json(
+            JsonError {
+                error: "totp already enabled",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+
+    let enrollment = totp::enroll(&user, totp_config).or_ise()?;
+    User::update_totp(&user.id, Some(&enrollment.encrypted), pool)
+        .await
+        .or_ise()?
+        .or_nf()?;
+    Ok(warp::reply::json(&Enrollment {
+        secret: enrollment.secret,
+        uri: enrollment.uri,
+    }))
+}
+
+/// Starts requiring codes once the user proves their app works, returning their recovery codes
+#[tracing::instrument(level = "debug", skip(body))]
+async fn confirm(
+    token: TokenJwt,
+    body: CodeBody,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let totp_config = enabled(config)?;
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    if user.totp_secret.is_none() || user.totp_enabled() {
+        None.or_json(
+            JsonError {
+                error: "no pending totp",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+
+    let step = totp::verify(&user, &body.code, totp_config)
+        .or_ise()?
+        .or_json(
+            JsonError {
+                error: "invalid code",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    User::confirm_totp(&user.id, step, pool)
+        .await
+        .or_ise()?
+        .or_json(
+            JsonError {
+                error: "no pending totp",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    let recovery_codes = totp::generate_recovery_codes(&user.id, config, pool)
+        .await
+        .or_ise()?;
+    AuditEvent::insert(
+        &user.id,
+        audit_events::TOTP_ENABLED,
+        None,
+        user_agent.as_deref(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(warp::reply::json(&RecoveryCodes { recovery_codes }))
+}
+
+/// Stops requiring codes, after checking a code or a recovery code
+#[tracing::instrument(level = "debug", skip(body))]
+async fn disable(
+    token: TokenJwt,
+    body: CodeBody,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<impl Reply, Rejection> {
+    enabled(config)?;
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    if !user.totp_enabled() {
+        // Only an unconfirmed secret may be left, which doesn't need a code to be removed
+        User::update_totp(&user.id, None, pool).await.or_ise()?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    let attempt = Attempt {
+        client_id: &token.client_id,
+        ip: ip.as_deref(),
+        user_agent: user_agent.as_deref(),
+    };
+    check(&user, &body.code, attempt, config, pool, lockouts).await?;
+
+    User::update_totp(&user.id, None, pool).await.or_ise()?;
+    RecoveryCode::delete_by_user(&user.id, pool)
+        .await
+        .or_ise()?;
+    AuditEvent::insert(
+        &user.id,
+        audit_events::TOTP_DISABLED,
+        None,
+        user_agent.as_deref(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the recovery codes of the user, after checking a code or a recovery code
+#[tracing::instrument(level = "debug", skip(body))]
+async fn regenerate(
+    token: TokenJwt,
+    body: CodeBody,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<impl Reply, Rejection> {
+    enabled(config)?;
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    if !user.totp_enabled() {
+        None.or_json(
+            JsonError {
+                error: "totp not enabled",
+            },
+            StatusCode::CONFLICT,
+        )?;
+    }
+    let attempt = Attempt {
+        client_id: &token.client_id,
+        ip: ip.as_deref(),
+        user_agent: user_agent.as_deref(),
+    };
+    check(&user, &body.code, attempt, config, pool, lockouts).await?;
+
+    let recovery_codes = totp::generate_recovery_codes(&user.id, config, pool)
+        .await
+        .or_ise()?;
+    AuditEvent::insert(
+        &user.id,
+        audit_events::RECOVERY_CODES_GENERATED,
+        None,
+        user_agent.as_deref(),
+        pool,
+    )
+    .await
+    .or_ise()?;
+    Ok(warp::reply::json(&RecoveryCodes { recovery_codes }))
+}
+
+/// Where a code was sent from, to count failed attempts and report lockouts
+#[derive(Debug, Clone, Copy)]
+struct Attempt<'a> {
+    client_id: &'a str,
+    ip: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// Makes sure the user still has their second factor before changing it
+/// Failed attempts are counted like those of the two-factor page, since codes can be guessed here too.
+async fn check(
+    user: &User,
+    code: &str,
+    attempt: Attempt<'_>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<(), Rejection> {
+    let keys: Vec<Key> = Some(Key::Mfa(&user.id))
+        .into_iter()
+        .chain(attempt.ip.map(Key::Ip))
+        .collect();
+    let recorded = match lockouts.record(&keys).await.or_ise()? {
+        Ok(recorded) => recorded,
+        Err(_) => None.or_json(
+            JsonError {
+                error: "too many failed attempts",
+            },
+            StatusCode::TOO_MANY_REQUESTS,
+        )?,
+    };
+
+    if !totp::check(user, code, config, pool).await.or_ise()? {
+        if lockouts.fail(&recorded).await.or_ise()? {
+            lockouts::locked_out(
+                &user.id,
+                attempt.client_id,
+                attempt.user_agent,
+                &[],
+                config,
+                pool,
+            )
+            .await
+            .or_ise()?;
+        }
+        None.or_json(
+            JsonError {
+                error: "invalid code",
+            },
+            StatusCode::BAD_REQUEST,
+        )?;
+    }
+    lockouts.succeed(&keys).await.or_ise()?;
+    Ok(())
+}
+
+fn enabled(config: &'static Config) -> Result<&'static TotpConfig, Rejection> {
+    config.totp.as_ref().or_nf()
+}
@@ -1,56 +1,305 @@
 use crate::{
-    config::Config,
-    db::User,
+    accounts,
+    config::{Config, ProfileConfig},
+    db::{audit_events, AuditEvent, User},
+    emails,
     errors::{JsonError, TryExt},
-    jwt,
+    lockouts::Lockouts,
+    profile,
     providers::TokenJwt,
+    routes,
+    usernames::{self, Unavailable},
 };
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::PgPool;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
+/// Profile fields to update, absent fields are left untouched and null ones are removed
+#[derive(Debug, Deserialize)]
+pub struct ProfileBody {
+    username: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    about: Option<Option<String>>,
+}
+
+/// Password confirming an account deletion, unnecessary right after logging in
+#[derive(Debug, Default, Deserialize)]
+struct DeleteBody {
+    password: Option<String>,
+}
+
+/// Profile of the current user, along with their private fields
+#[derive(Serialize)]
+pub struct Me {
+    #[serde(flatten)]
+    user: User,
+    /// Left out of `/me` replies unless the `email` scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+    /// Whether the user has to enter a code from their authenticator app after logging in
+    totp_enabled: bool,
+}
+
+impl From<User> for Me {
+    fn from(user: User) -> Self {
+        Self {
+            email: user.email.clone(),
+            email_verified: Some(user.email_verified),
+            totp_enabled: user.totp_enabled(),
+            user,
+        }
+    }
+}
+
+impl Me {
+    /// Profile of the user, leaving out their address unless `email` is set
+    pub fn new(user: User, email: bool) -> Self {
+        let mut me = Self::from(user);
+        if !email {
+            me.email = None;
+            me.email_verified = None;
+        }
+        me
+    }
+}
+
+/// Profile of a user as shown to anyone, leaving out whether their account is disabled or being deleted
+#[derive(Serialize)]
+struct PublicUser<'a> {
+    id: &'a str,
+    inserted_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    about: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    google_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    microsoft_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facebook_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    twitter_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    github_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discord_id: Option<&'a str>,
+}
+
+impl<'a> From<&'a User> for PublicUser<'a> {
+    fn from(user: &'a User) -> Self {
+        Self {
+            id: &user.id,
+            inserted_at: user.inserted_at,
+            updated_at: user.updated_at,
+            username: user.username.as_deref(),
+            name: user.name.as_deref(),
+            about: user.about.as_deref(),
+            google_id: user.google_id.as_deref(),
+            microsoft_id: user.microsoft_id.as_deref(),
+            facebook_id: user.facebook_id.as_deref(),
+            twitter_id: user.twitter_id.as_deref(),
+            github_id: user.github_id.as_deref(),
+            discord_id: user.discord_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Availability {
+    available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+/// Distinguishes null fields from absent ones
+fn nullable<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
 #[tracing::instrument(level = "debug")]
 pub fn handler(
     config: &'static Config,
     pool: &'static PgPool,
+    lockouts: &'static Lockouts,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
-    let user = warp::path!("users" / String).and_then(move |id: String| user(id, pool));
+    let user = warp::path!("users" / String)
+        .and(warp::get())
+        .and_then(move |id: String| user(id, pool));
+    let available = warp::path!("users" / "available" / String)
+        .and(warp::get())
+        .and_then(move |name: String| available(name, config, pool));
     let me = warp::path!("me")
-        .and(warp::header("Authorization"))
-        .and_then(move |auth: String| me(auth, config, pool));
-    (user).or(me)
+        .and(warp::get())
+        .and(routes::authenticated(profile::SCOPE, config))
+        .and_then(move |token: TokenJwt| me(token, pool));
+    let update_me = warp::path!("me")
+        .and(warp::patch())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(warp::body::json())
+        .and_then(move |token: TokenJwt, body: ProfileBody| {
+            let email = token.granted(emails::SCOPE);
+            update(token.sub, body, email, config, pool)
+        });
+    let delete_me = warp::path!("me")
+        .and(warp::delete())
+        .and(routes::authenticated(accounts::SCOPE, config))
+        .and(
+            warp::body::json()
+                .or(warp::any().map(DeleteBody::default))
+                .unify(),
+        )
+        .and(routes::client_ip(config))
+        .and(warp::header::optional("User-Agent"))
+        .and_then(
+            move |token: TokenJwt,
+                  body: DeleteBody,
+                  ip: Option<String>,
+                  user_agent: Option<String>| {
+                delete_me(token, body, ip, user_agent, config, pool, lockouts)
+            },
+        );
+    (user).or(available).or(me).or(update_me).or(delete_me)
 }
 
 #[tracing::instrument(level = "debug")]
 async fn user(id: String, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
-    let user = User::select(&id, pool).await.or_ise()?.or_nf()?;
-    Ok(warp::reply::json(&user))
+    // Disabled accounts and those pending deletion are hidden as if they were already gone
+    let user = User::select(&id, pool)
+        .await
+        .or_ise()?
+        .filter(User::active)
+        .or_nf()?;
+    Ok(warp::reply::json(&PublicUser::from(&user)))
 }
 
+/// Tells whether a new user could pick a username
 #[tracing::instrument(level = "debug")]
-async fn me(
-    auth: String,
+async fn available(
+    name: String,
     config: &'static Config,
     pool: &'static PgPool,
 ) -> Result<impl Reply, Rejection> {
-    if !auth.starts_with("Bearer ") {
-        None.or_json(
-            JsonError {
-                error: "invalid authorization header",
-            },
-            StatusCode::BAD_REQUEST,
-        )?;
-    }
+    let availability = match percent_decode_str(&name).decode_utf8() {
+        Ok(name) => usernames::check(&name, None, config, pool).await.or_ise()?,
+        Err(_) => Err(Unavailable::Invalid),
+    };
+    Ok(warp::reply::json(&Availability {
+        available: availability.is_ok(),
+        reason: availability.err().map(Unavailable::reason),
+    }))
+}
 
-    let token: TokenJwt = jwt::decode(auth[7..].to_owned(), &config.token)
+#[tracing::instrument(level = "debug")]
+async fn me(token: TokenJwt, pool: &'static PgPool) -> Result<impl Reply, Rejection> {
+    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
+    Ok(warp::reply::json(&Me::new(
+        user,
+        token.granted(emails::SCOPE),
+    )))
+}
+
+/// Deletes the account of the user, making sure they recently proved their identity
+#[tracing::instrument(level = "debug", skip(body))]
+async fn delete_me(
+    token: TokenJwt,
+    body: DeleteBody,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    config: &'static Config,
+    pool: &'static PgPool,
+    lockouts: &'static Lockouts,
+) -> Result<impl Reply, Rejection> {
+    routes::reauthenticate(
+        &token,
+        body.password.as_deref(),
+        ip.as_deref(),
+        user_agent.as_deref(),
+        config,
+        pool,
+        lockouts,
+    )
+    .await?;
+
+    accounts::delete(&token.sub, config, pool)
         .await
         .or_ise()?
-        .or_json(
-            JsonError {
-                error: "invalid token",
-            },
-            StatusCode::UNAUTHORIZED,
-        )?;
+        .or_nf()?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    let user = User::select(&token.sub, pool).await.or_ise()?.or_nf()?;
-    Ok(warp::reply::json(&user))
+/// Validates and applies a profile update, replying with the address of the user only if `email` is set
+#[tracing::instrument(level = "debug")]
+pub async fn update(
+    id: String,
+    body: ProfileBody,
+    email: bool,
+    config: &'static Config,
+    pool: &'static PgPool,
+) -> Result<impl Reply, Rejection> {
+    let name = validate(body.name, profile::name, config)?;
+    let about = validate(body.about, profile::about, config)?;
+
+    if let Some(username) = body.username {
+        let username = usernames::check(&username, Some(&id), config, pool)
+            .await
+            .or_ise()?;
+        let username = match username {
+            Ok(username) => username,
+            Err(unavailable) => None.or_json(
+                JsonError {
+                    error: unavailable.reason(),
+                },
+                StatusCode::BAD_REQUEST,
+            )?,
+        };
+        User::update_username(&id, &username.name, &username.skeleton, pool)
+            .await
+            .or_ise()?
+            .or_nf()?;
+        AuditEvent::insert(&id, audit_events::USERNAME_CHANGED, None, None, pool)
+            .await
+            .or_ise()?;
+    }
+
+    let user = User::update_profile(
+        &id,
+        name.as_ref().map(Option::as_deref),
+        about.as_ref().map(Option::as_deref),
+        pool,
+    )
+    .await
+    .or_ise()?
+    .or_nf()?;
+    AuditEvent::insert(&id, audit_events::PROFILE_UPDATED, None, None, pool)
+        .await
+        .or_ise()?;
+    Ok(warp::reply::json(&Me::new(user, email)))
+}
+
+fn validate(
+    value: Option<Option<String>>,
+    normalize: fn(&str, &ProfileConfig) -> Result<Option<String>, &'static str>,
+    config: &'static Config,
+) -> Result<Option<Option<String>>, Rejection> {
+    match value {
+        Some(Some(value)) => match normalize(&value, &config.profile) {
+            Ok(value) => Ok(Some(value)),
+            Err(error) => None.or_json(JsonError { error }, StatusCode::BAD_REQUEST),
+        },
+        Some(None) => Ok(Some(None)),
+        None => Ok(None),
+    }
 }
@@ -0,0 +1,124 @@
+use crate::{
+    config::Config,
+    db::{audit_events, AuditEvent, NewSession, Session},
+    jwt,
+    providers::Authentication,
+};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Name of the cookie holding the session
+pub const COOKIE: &str = "vaulth_session";
+
+/// Length of generated session IDs
+const ID_LEN: usize = 43;
+
+/// Signed content of the session cookie
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionJwt {
+    sid: String,
+}
+
+impl jwt::Purpose for SessionJwt {
+    const PURPOSE: &'static str = "session";
+}
+
+/// Extracts the ID of the session referred to by the session cookie, if it is validly signed
+async fn id(cookie: Option<String>, config: &'static Config) -> Result<Option<String>> {
+    let cookie = match cookie {
+        Some(cookie) => cookie,
+        None => return Ok(None),
+    };
+    let jwt: Option<SessionJwt> = jwt::decode(cookie, &config.token).await?;
+    Ok(jwt.map(|jwt| jwt.sid))
+}
+
+/// Finds the active session referred to by the session cookie, if any
+#[tracing::instrument(level = "debug", skip(cookie))]
+pub async fn current(
+    cookie: Option<String>,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<Option<Session>> {
+    let id = match id(cookie, config).await? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let session = Session::select_active(&id, pool).await?;
+    if session.is_some() {
+        Session::touch(&id, pool).await?;
+    }
+    Ok(session)
+}
+
+/// Starts a new session for a user who just authenticated,
+/// returning it along with the matching `Set-Cookie` header value
+#[tracing::instrument(level = "debug")]
+pub async fn create(
+    user_id: &str,
+    auth: &Authentication,
+    user_agent: Option<&str>,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<(Session, String)> {
+    let duration = Duration::minutes(config.session.duration);
+    let id: String = OsRng.sample_iter(&Alphanumeric).take(ID_LEN).collect();
+
+    let session = Session::insert(
+        NewSession {
+            id: &id,
+            user_id,
+            expires_at: Utc::now() + duration,
+            provider_name: &auth.provider_name,
+            provider_id: &auth.provider_id,
+            user_agent,
+            amr: &auth.amr,
+        },
+        pool,
+    )
+    .await?;
+    AuditEvent::insert(user_id, audit_events::LOGIN, None, user_agent, pool).await?;
+
+    let value = jwt::encode_for(SessionJwt { sid: id }, duration, &config.token).await?;
+    Ok((session, cookie(&value, duration.num_seconds(), config)))
+}
+
+/// Ends the session referred to by the session cookie, if any
+#[tracing::instrument(level = "debug", skip(cookie))]
+pub async fn end(
+    cookie: Option<String>,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<Option<Session>> {
+    match id(cookie, config).await? {
+        Some(id) => Ok(Session::delete(&id, pool).await?),
+        None => Ok(None),
+    }
+}
+
+/// Returns a `Set-Cookie` header value removing the session cookie
+pub fn clear_cookie(config: &Config) -> String {
+    cookie("", 0, config)
+}
+
+fn cookie(value: &str, max_age: i64, config: &Config) -> String {
+    cookie_header(COOKIE, value, max_age, config)
+}
+
+/// Returns a `Set-Cookie` header value for a cookie only sent to Vaulth,
+/// secure if Vaulth is served over HTTPS
+pub fn cookie_header(name: &str, value: &str, max_age: i64, config: &Config) -> String {
+    let secure = if config.root_uri.starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    };
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax{}",
+        name, value, max_age, secure
+    )
+}
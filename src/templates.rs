@@ -0,0 +1,353 @@
+//! Rendering and localization of the HTML pages shown to users
+//!
+//! Built-in templates can be overridden by `<name>.html` files in the configured templates directory,
+//! or in a subdirectory named after the theme of a client.
+//! Templates insert escaped values with `{{ name }}`, raw HTML with `{{{ name }}}`
+//! and translated text with `{{ t.key }}`.
+//!
+//! Translations are read from `<language>.json` files in the configured locales directory,
+//! missing keys falling back to the built-in English text.
+
+use crate::{config::Config, db::Client, html};
+use anyhow::{anyhow, Result};
+use std::{cmp::Ordering, collections::HashMap, io};
+use tokio::fs;
+use warp::reply::Response;
+
+const TEMPLATES: &[(&str, &str)] = &[
+    ("consent", include_str!("../templates/consent.html")),
+    (
+        "email_verified",
+        include_str!("../templates/email_verified.html"),
+    ),
+    ("error", include_str!("../templates/error.html")),
+    (
+        "forgot_password",
+        include_str!("../templates/forgot_password.html"),
+    ),
+    ("logged_out", include_str!("../templates/logged_out.html")),
+    ("login", include_str!("../templates/login.html")),
+    ("logout", include_str!("../templates/logout.html")),
+    (
+        "magic_link_sent",
+        include_str!("../templates/magic_link_sent.html"),
+    ),
+    ("mfa", include_str!("../templates/mfa.html")),
+    (
+        "password_reset",
+        include_str!("../templates/password_reset.html"),
+    ),
+    (
+        "reset_password",
+        include_str!("../templates/reset_password.html"),
+    ),
+    ("signup", include_str!("../templates/signup.html")),
+];
+
+/// Language of the built-in text
+const BUILTIN_LANGUAGE: &str = "en";
+const BUILTIN_STRINGS: &str = include_str!("../locales/en.json");
+
+const DEFAULT_PRIMARY_COLOR: &str = "#24292e";
+const DEFAULT_BACKGROUND_COLOR: &str = "#ffffff";
+
+/// Translated text in the language picked for a user
+#[derive(Debug)]
+pub struct Locale {
+    pub language: String,
+    strings: HashMap<String, String>,
+}
+
+impl Locale {
+    /// Picks the first available language out of the preferences of the user,
+    /// falling back to the default one
+    #[tracing::instrument(level = "debug")]
+    pub async fn negotiate(preferences: &[String], config: &Config) -> Result<Self> {
+        let mut locale = Self {
+            language: BUILTIN_LANGUAGE.to_owned(),
+            strings: serde_json::from_str(BUILTIN_STRINGS)?,
+        };
+        let dir = match &config.ui.locales {
+            Some(dir) => dir,
+            None => return Ok(locale),
+        };
+
+        let default = [config.ui.default_locale.clone()];
+        for language in preferences.iter().chain(&default) {
+            let path = dir.join(format!("{}.json", language));
+            match fs::read_to_string(&path).await {
+                Ok(contents) => {
+                    let strings: HashMap<String, String> = serde_json::from_str(&contents)?;
+                    locale.language = language.clone();
+                    locale.strings.extend(strings);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    if language == BUILTIN_LANGUAGE {
+                        break;
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(locale)
+    }
+
+    /// Returns the translated text for a key as HTML, with the variables escaped
+    pub fn text(&self, key: &str, vars: &[(&str, &str)]) -> String {
+        match self.strings.get(key) {
+            Some(text) => substitute(text, |name, raw| {
+                let value = lookup(vars, name);
+                if raw {
+                    value.to_owned()
+                } else {
+                    html::escape(value)
+                }
+            }),
+            None => html::escape(key),
+        }
+    }
+}
+
+/// Lists the languages preferred by the user, from the `ui_locales` parameter then the `Accept-Language` header
+/// Region specific languages are followed by their generic version, like `fr-ca` then `fr`
+pub fn languages(ui_locales: Option<&str>, accept_language: Option<&str>) -> Vec<String> {
+    let mut weighted: Vec<(&str, f32)> = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|l| {
+            let mut parts = l.split(';');
+            let tag = parts.next()?.trim();
+            let q = match parts.find_map(|p| p.trim().strip_prefix("q=")) {
+                Some(q) => q.parse().ok()?,
+                None => 1.0,
+            };
+            Some((tag, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    weighted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+    let mut languages = Vec::new();
+    for tag in ui_locales
+        .unwrap_or_default()
+        .split(' ')
+        .chain(weighted.into_iter().map(|(tag, _)| tag))
+    {
+        // Tags end up in file paths
+        if tag.is_empty()
+            || tag.len() > 35
+            || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            continue;
+        }
+
+        let tag = tag.to_ascii_lowercase();
+        let generic = tag.split('-').next().unwrap_or_default().to_owned();
+        for language in vec![tag, generic] {
+            if !languages.contains(&language) {
+                languages.push(language);
+            }
+        }
+    }
+    languages
+}
+
+/// Renders a page, using the theme of the client if any
+#[tracing::instrument(level = "debug", skip(vars, locale))]
+pub async fn render(
+    name: &str,
+    vars: &[(&str, &str)],
+    locale: &Locale,
+    client: Option<&Client>,
+    config: &Config,
+) -> Result<Response> {
+    let template = load(name, client.and_then(|c| c.theme.as_deref()), config).await?;
+
+    // Colors are validated when the client is created
+    let theme = [
+        ("lang", locale.language.as_str()),
+        (
+            "primary_color",
+            client
+                .and_then(|c| c.primary_color.as_deref())
+                .unwrap_or(DEFAULT_PRIMARY_COLOR),
+        ),
+        (
+            "background_color",
+            client
+                .and_then(|c| c.background_color.as_deref())
+                .unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        ),
+    ];
+    let vars: Vec<(&str, &str)> = vars.iter().chain(&theme).copied().collect();
+    Ok(html::page(fill(&template, &vars, locale)))
+}
+
+/// Renders the logo of a client, if it has one
+pub fn logo(client: &Client) -> String {
+    match &client.logo_uri {
+        Some(uri) => format!(r#"<img src="{}" alt="">"#, html::escape(uri)),
+        None => String::new(),
+    }
+}
+
+/// Reads an overridden template, falling back to the built-in one
+async fn load(name: &str, theme: Option<&str>, config: &Config) -> Result<String> {
+    if let Some(dir) = &config.ui.templates {
+        let file = format!("{}.html", name);
+        let mut paths = Vec::with_capacity(2);
+        if let Some(theme) = theme {
+            paths.push(dir.join(theme).join(&file));
+        }
+        paths.push(dir.join(&file));
+
+        for path in paths {
+            match fs::read_to_string(&path).await {
+                Ok(template) => return Ok(template),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    TEMPLATES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, template)| (*template).to_owned())
+        .ok_or_else(|| anyhow!("unknown template {}", name))
+}
+
+/// Fills the placeholders of a template with escaped values, raw HTML and translated text
+fn fill(template: &str, vars: &[(&str, &str)], locale: &Locale) -> String {
+    substitute(template, |name, raw| {
+        if let Some(key) = name.strip_prefix("t.") {
+            return locale.text(key, vars);
+        }
+        let value = lookup(vars, name);
+        if raw {
+            value.to_owned()
+        } else {
+            html::escape(value)
+        }
+    })
+}
+
+fn lookup<'a>(vars: &[(&str, &'a str)], name: &str) -> &'a str {
+    vars.iter()
+        .find(|(n, _)| *n == name)
+        .map_or("", |(_, value)| *value)
+}
+
+/// Replaces the `{{ name }}` and `{{{ name }}}` placeholders of a template
+fn substitute(template: &str, mut value: impl FnMut(&str, bool) -> String) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+
+        let raw = rest[start..].starts_with("{{{");
+        let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+        let after = &rest[start + open.len()..];
+        match after.find(close) {
+            Some(end) => {
+                result.push_str(&value(after[..end].trim(), raw));
+                rest = &after[end + close.len()..];
+            }
+            None => {
+                result.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients;
+
+    fn locale() -> Locale {
+        let mut strings: HashMap<String, String> = serde_json::from_str(BUILTIN_STRINGS).unwrap();
+        strings.insert(
+            "test.greeting".to_owned(),
+            "Hello <b>{{ name }}</b>".to_owned(),
+        );
+        strings.insert("test.raw".to_owned(), "{{{ link }}}".to_owned());
+        Locale {
+            language: BUILTIN_LANGUAGE.to_owned(),
+            strings,
+        }
+    }
+
+    #[test]
+    fn escaped_by_default() {
+        let vars = [
+            ("name", r#"<script>alert("x")</script>"#),
+            ("title", "a' onload='b"),
+        ];
+        assert_eq!(
+            fill("<p>{{ name }}</p>", &vars, &locale()),
+            "<p>&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;</p>"
+        );
+        assert_eq!(
+            fill("<img alt='{{title}}'>", &vars, &locale()),
+            "<img alt='a&#x27; onload=&#x27;b'>"
+        );
+        // Unknown placeholders are left empty and unclosed ones as they are
+        assert_eq!(fill("{{ missing }}|{{ name", &vars, &locale()), "|{{ name");
+    }
+
+    #[test]
+    fn raw_output() {
+        let vars = [("logo", r#"<img src="logo.png">"#)];
+        assert_eq!(
+            fill("{{{ logo }}} {{ logo }}", &vars, &locale()),
+            r#"<img src="logo.png"> &lt;img src=&quot;logo.png&quot;&gt;"#
+        );
+    }
+
+    #[test]
+    fn translated_text() {
+        let locale = locale();
+        let vars = [("name", "<i>"), ("link", "<a href=\"/\">")];
+        // Translations are HTML, their variables are escaped unless inserted raw
+        assert_eq!(
+            fill("{{ t.test.greeting }}", &vars, &locale),
+            "Hello <b>&lt;i&gt;</b>"
+        );
+        assert_eq!(fill("{{ t.test.raw }}", &vars, &locale), "<a href=\"/\">");
+        // Missing keys are shown escaped
+        assert_eq!(fill("{{ t.<x> }}", &vars, &locale), "&lt;x&gt;");
+    }
+
+    #[test]
+    fn theme_names() {
+        assert!(clients::valid_theme("dark"));
+        assert!(clients::valid_theme("brand-2_blue"));
+        assert!(!clients::valid_theme(""));
+        assert!(!clients::valid_theme(".."));
+        assert!(!clients::valid_theme("../../etc"));
+        assert!(!clients::valid_theme("a/b"));
+        assert!(!clients::valid_theme("a\\b"));
+        assert!(!clients::valid_theme("dark.html"));
+        assert!(!clients::valid_theme(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn locale_tags() {
+        assert_eq!(
+            languages(Some("fr-CA"), Some("de;q=0.5, en-GB,  es;q=0")),
+            ["fr-ca", "fr", "en-gb", "en", "de"]
+        );
+        // Tags end up in file paths, so anything but letters, digits and dashes is dropped
+        assert_eq!(
+            languages(Some("../../etc/passwd fr"), Some("en/../x, ., de\0")),
+            ["fr"]
+        );
+        assert_eq!(languages(Some(&"a".repeat(36)), None), Vec::<String>::new());
+        assert_eq!(languages(None, Some("it;q=abc, nl")), ["nl"]);
+    }
+}
@@ -0,0 +1,295 @@
+//! Time-based one-time passwords from authenticator apps, as described by RFC 6238, used as a second factor
+//!
+//! Secrets are encrypted with AES-256-GCM before being stored, bound to the ID of their user
+//! so they can't be moved to another account. Users also get one-time recovery codes,
+//! stored hashed like passwords, in case they lose their app.
+
+use crate::{
+    config::{Config, TotpConfig},
+    db::{RecoveryCode, User},
+    password,
+};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hmac,
+};
+use sqlx::PgPool;
+use url::form_urlencoded;
+
+/// Length of generated secrets, in bytes, as recommended by RFC 4226
+const SECRET_LEN: usize = 20;
+/// Number of digits in codes
+const DIGITS: u32 = 6;
+/// Duration of a time step, in seconds
+const PERIOD: i64 = 30;
+/// Number of steps before and after the current one codes are accepted for, to account for clock drift
+const SKEW: i64 = 1;
+/// Number of recovery codes users get
+const RECOVERY_CODES: usize = 10;
+/// Length of each half of recovery codes
+const RECOVERY_CODE_HALF_LEN: usize = 5;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Secret being set up, along with how to add it to an authenticator app
+#[derive(Debug)]
+pub struct Enrollment {
+    /// Encrypted secret to store
+    pub encrypted: String,
+    /// Secret encoded in base32, for users typing it in
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    pub uri: String,
+}
+
+/// Generates a new secret for a user
+pub fn enroll(user: &User, totp_config: &TotpConfig) -> Result<Enrollment> {
+    let mut secret = [0; SECRET_LEN];
+    OsRng.fill(&mut secret);
+    let encoded = base32(&secret);
+
+    let account = user.username.as_deref().unwrap_or(&user.id);
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("secret", &encoded)
+        .append_pair("issuer", &totp_config.issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &PERIOD.to_string())
+        .finish();
+    let label: String =
+        form_urlencoded::byte_serialize(format!("{}:{}", totp_config.issuer, account).as_bytes())
+            .collect();
+
+    Ok(Enrollment {
+        encrypted: encrypt(&secret, &user.id, totp_config)?,
+        secret: encoded,
+        uri: format!("otpauth://totp/{}?{}", label.replace('+', "%20"), query),
+    })
+}
+
+/// Checks a code against the secret of a user, whether confirmed or not,
+/// returning the time step it matched so it can't be used twice
+pub fn verify(user: &User, code: &str, totp_config: &TotpConfig) -> Result<Option<i64>> {
+    let encrypted = match &user.totp_secret {
+        Some(encrypted) => encrypted,
+        None => return Ok(None),
+    };
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let code: u32 = code.parse()?;
+    let secret = decrypt(encrypted, &user.id, totp_config)?;
+
+    let current = Utc::now().timestamp() / PERIOD;
+    let step = (current - SKEW..=current + SKEW)
+        .filter(|step| user.totp_last_step.map_or(true, |last| *step > last))
+        .find(|step| hotp(&secret, *step as u64) == code);
+    Ok(step)
+}
+
+/// Checks the second factor of a user logging in, either a code from their app or a recovery code,
+/// using it up so it can't be replayed
+#[tracing::instrument(level = "debug", skip(user, code, config))]
+pub async fn check(
+    user: &User,
+    code: &str,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<bool> {
+    let totp_config = config
+        .totp
+        .as_ref()
+        .ok_or_else(|| anyhow!("TOTP isn't configured"))?;
+    if !user.totp_enabled() {
+        return Ok(false);
+    }
+
+    if let Some(step) = verify(user, code, totp_config)? {
+        return Ok(User::use_totp_step(&user.id, step, pool).await?);
+    }
+
+    // Recovery codes are told apart from app codes by their dash
+    if code.contains('-') {
+        let code = code.trim().to_ascii_lowercase();
+        for recovery_code in RecoveryCode::select_unused(&user.id, pool).await? {
+            if password::verify(recovery_code.code.clone(), &code, &config.hash).await? {
+                return Ok(RecoveryCode::consume(recovery_code.id, pool).await?);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Replaces the recovery codes of a user, returning the new ones so they can be shown once
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn generate_recovery_codes(
+    user_id: &str,
+    config: &'static Config,
+    pool: &PgPool,
+) -> Result<Vec<String>> {
+    let half = || -> String {
+        OsRng
+            .sample_iter(&Alphanumeric)
+            .take(RECOVERY_CODE_HALF_LEN)
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    };
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    let mut hashes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let code = format!("{}-{}", half(), half());
+        hashes.push(password::hash(&code, &config.hash).await?);
+        codes.push(code);
+    }
+
+    RecoveryCode::replace(user_id, &hashes, pool).await?;
+    Ok(codes)
+}
+
+/// Computes an HOTP value as described by RFC 4226
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+
+    // Dynamic truncation
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Encodes bytes in base32 as described by RFC 4648, without padding like authenticator apps expect
+fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// Checks that the encryption key is valid, so a bad one fails at startup instead of when users set up an app
+pub fn check_key(totp_config: &TotpConfig) -> Result<()> {
+    key(totp_config).map_err(|e| {
+        anyhow!(
+            "invalid TOTP encryption key ({}), generate one with `openssl rand -base64 32`",
+            e
+        )
+    })?;
+    Ok(())
+}
+
+fn key(totp_config: &TotpConfig) -> Result<LessSafeKey> {
+    let key = base64::decode(&totp_config.encryption_key)?;
+    let key = UnboundKey::new(&AES_256_GCM, &key)
+        .map_err(|_| anyhow!("TOTP encryption key must be 32 bytes long"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Encrypts a secret, returning the nonce followed by the ciphertext, encoded in base64
+fn encrypt(secret: &[u8], user_id: &str, totp_config: &TotpConfig) -> Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill(&mut nonce);
+
+    let mut data = secret.to_vec();
+    key(totp_config)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(user_id.as_bytes()),
+            &mut data,
+        )
+        .map_err(|_| anyhow!("couldn't encrypt TOTP secret"))?;
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(data);
+    Ok(base64::encode(encrypted))
+}
+
+fn decrypt(encrypted: &str, user_id: &str, totp_config: &TotpConfig) -> Result<Vec<u8>> {
+    let mut data = base64::decode(encrypted)?;
+    if data.len() < NONCE_LEN {
+        bail!("invalid TOTP secret");
+    }
+    let mut ciphertext = data.split_off(NONCE_LEN);
+    let nonce =
+        Nonce::try_assume_unique_for_key(&data).map_err(|_| anyhow!("invalid TOTP secret"))?;
+
+    let secret = key(totp_config)?
+        .open_in_place(nonce, Aad::from(user_id.as_bytes()), &mut ciphertext)
+        .map_err(|_| anyhow!("couldn't decrypt TOTP secret"))?;
+    Ok(secret.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn rfc6238_vectors() {
+        // The RFC lists 8 digit codes, of which 6 digit ones are the last digits
+        let expected = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, code) in expected.iter() {
+            let counter = (time / PERIOD) as u64;
+            assert_eq!(hotp(SECRET, counter), code % 10u32.pow(DIGITS));
+        }
+    }
+
+    #[test]
+    fn base32_without_padding() {
+        assert_eq!(base32(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn encryption_keys() {
+        let config = |key: &str| TotpConfig {
+            issuer: "Vaulth".to_owned(),
+            encryption_key: key.to_owned(),
+        };
+        assert!(check_key(&config(&base64::encode([7; 32]))).is_ok());
+        assert!(check_key(&config(&base64::encode([7; 16]))).is_err());
+        assert!(check_key(&config("REPLACE-WITH-OUTPUT-OF-openssl-rand-base64-32")).is_err());
+
+        let config = config(&base64::encode([7; 32]));
+        let encrypted = encrypt(SECRET, "user", &config).unwrap();
+        assert_eq!(decrypt(&encrypted, "user", &config).unwrap(), SECRET);
+    }
+}
@@ -0,0 +1,280 @@
+//! Validation and availability of usernames
+//!
+//! Usernames are compared through their skeleton, a case-folded form where lookalike characters
+//! are mapped to the same one, so `Admin`, `admin` and `аdmin` with a Cyrillic `а` can't coexist.
+
+use crate::{
+    config::Config,
+    db::{User, UsernameRelease},
+    profile,
+};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Maximum length of usernames, matching the database column
+pub const MAX_LEN: usize = 64;
+
+/// Names nobody can pick, which could be mistaken for the service itself
+const RESERVED: &[&str] = &[
+    "abuse",
+    "account",
+    "admin",
+    "administrator",
+    "anonymous",
+    "api",
+    "help",
+    "login",
+    "me",
+    "moderator",
+    "noreply",
+    "null",
+    "official",
+    "postmaster",
+    "root",
+    "security",
+    "settings",
+    "signup",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+    "users",
+    "vaulth",
+    "webmaster",
+];
+
+/// Characters mapped to the ASCII letter they look like, after case folding
+const CONFUSABLES: &[(char, char)] = &[
+    ('0', 'o'),
+    ('1', 'l'),
+    ('5', 's'),
+    ('i', 'l'),
+    ('|', 'l'),
+    // Cyrillic
+    ('а', 'a'),
+    ('в', 'b'),
+    ('е', 'e'),
+    ('һ', 'h'),
+    ('н', 'h'),
+    ('і', 'l'),
+    ('ӏ', 'l'),
+    ('ј', 'j'),
+    ('к', 'k'),
+    ('м', 'm'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('ԁ', 'd'),
+    ('ѕ', 's'),
+    ('с', 'c'),
+    ('т', 't'),
+    ('у', 'y'),
+    ('х', 'x'),
+    // Greek
+    ('α', 'a'),
+    ('ε', 'e'),
+    ('ι', 'l'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('τ', 't'),
+    ('υ', 'u'),
+    ('χ', 'x'),
+];
+
+/// Letter sequences that look like a single letter
+const CONFUSABLE_SEQUENCES: &[(&str, &str)] = &[("rn", "m"), ("vv", "w"), ("cl", "d")];
+
+/// Why a username can't be picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    Invalid,
+    Reserved,
+    Blocked,
+    Taken,
+    Released,
+}
+
+impl Unavailable {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::Invalid => "invalid username",
+            Self::Reserved => "reserved username",
+            Self::Blocked => "inappropriate username",
+            Self::Taken => "username taken",
+            Self::Released => "username recently used",
+        }
+    }
+}
+
+/// Normalized username along with its skeleton
+#[derive(Debug)]
+pub struct Username {
+    pub name: String,
+    pub skeleton: String,
+}
+
+/// Checks whether a user, or a new one if absent, can pick a username
+#[tracing::instrument(level = "debug")]
+pub async fn check(
+    value: &str,
+    user_id: Option<&str>,
+    config: &Config,
+    pool: &PgPool,
+) -> Result<Result<Username, Unavailable>> {
+    let username = match validate(value, config) {
+        Ok(username) => username,
+        Err(unavailable) => return Ok(Err(unavailable)),
+    };
+
+    let holder = User::select_by_skeleton(&username.skeleton, pool).await?;
+    if holder.is_some() && holder.as_deref() != user_id {
+        return Ok(Err(Unavailable::Taken));
+    }
+    let since = Utc::now() - Duration::days(config.usernames.reuse_delay);
+    if UsernameRelease::released_by_other(&username.skeleton, user_id, since, pool).await? {
+        return Ok(Err(Unavailable::Released));
+    }
+    Ok(Ok(username))
+}
+
+/// Recomputes the skeletons of stored usernames, which the database can't do itself,
+/// so ones stored before or computed differently are compared the same way as new ones
+/// Users whose username now looks like the one of another user keep their previous skeleton
+/// and are logged, so an administrator can rename one of them.
+#[tracing::instrument(level = "debug")]
+pub async fn refresh_skeletons(pool: &PgPool) -> Result<()> {
+    let users = User::select_usernames(pool).await?;
+    let mut skeletons: Vec<String> = users.iter().filter_map(|(_, _, s)| s.clone()).collect();
+    for (id, username, stored) in users {
+        let skeleton = skeleton(&username);
+        if stored.as_ref() == Some(&skeleton) {
+            continue;
+        }
+        if skeletons.contains(&skeleton) {
+            tracing::warn!(
+                "username of user {} looks like the one of another user, rename one of them",
+                id
+            );
+            continue;
+        }
+        User::update_skeleton(&id, &skeleton, pool).await?;
+        skeletons.retain(|s| Some(s) != stored.as_ref());
+        skeletons.push(skeleton);
+    }
+
+    for (username, stored) in UsernameRelease::select_usernames(pool).await? {
+        let skeleton = skeleton(&username);
+        if skeleton != stored {
+            UsernameRelease::update_skeleton(&username, &skeleton, pool).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives users registered before usernames existed their ID as username when it can be one,
+/// logging the IDs of the others which can't log in with a username until they pick one
+#[tracing::instrument(level = "debug")]
+pub async fn backfill(config: &Config, pool: &PgPool) -> Result<()> {
+    let mut invalid = Vec::new();
+    for id in User::select_without_username(pool).await? {
+        match check(&id, Some(&id), config, pool).await? {
+            Ok(username) => {
+                User::update_username(&id, &username.name, &username.skeleton, pool).await?;
+            }
+            Err(_) => invalid.push(id),
+        }
+    }
+    if !invalid.is_empty() {
+        tracing::warn!(
+            "users whose ID isn't a valid username were left without one: {}",
+            invalid.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Validates a username against the configured rules, without checking whether it is taken
+fn validate(value: &str, config: &Config) -> Result<Username, Unavailable> {
+    let rules = &config.usernames;
+    let name: String = value.trim().nfc().collect();
+
+    let len = name.chars().count();
+    if len < rules.min_length
+        || len > rules.max_length.min(MAX_LEN)
+        || name.chars().any(char::is_control)
+        || !rules.pattern.is_match(&name)
+    {
+        return Err(Unavailable::Invalid);
+    }
+
+    let skeleton = skeleton(&name);
+    if skeleton.chars().count() > MAX_LEN {
+        return Err(Unavailable::Invalid);
+    }
+    if RESERVED
+        .iter()
+        .copied()
+        .chain(rules.reserved.iter().map(String::as_str))
+        .any(|r| self::skeleton(r) == skeleton)
+    {
+        return Err(Unavailable::Reserved);
+    }
+    if profile::blocked(&name, &config.profile) {
+        return Err(Unavailable::Blocked);
+    }
+    Ok(Username { name, skeleton })
+}
+
+/// Folds a username into the form it is compared with,
+/// removing accents and case then mapping lookalike characters
+fn skeleton(name: &str) -> String {
+    let mut skeleton: String = name
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| {
+            CONFUSABLES
+                .iter()
+                .find(|(from, _)| *from == c)
+                .map_or(c, |(_, to)| *to)
+        })
+        .collect();
+    for (from, to) in CONFUSABLE_SEQUENCES {
+        skeleton = skeleton.replace(from, to);
+    }
+    skeleton
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_and_accents() {
+        assert_eq!(skeleton("Admin"), skeleton("admin"));
+        assert_eq!(skeleton("Café"), "cafe");
+        assert_eq!(skeleton("cafe\u{301}"), "cafe");
+        assert_eq!(skeleton("ＡＤＭＩＮ"), skeleton("admin"));
+    }
+
+    #[test]
+    fn lookalikes() {
+        assert_eq!(skeleton("аdmin"), skeleton("admin"));
+        assert_eq!(skeleton("аԁmіn"), skeleton("admin"));
+        assert_eq!(skeleton("paypa1"), skeleton("paypal"));
+        assert_eq!(skeleton("l0l"), "lol");
+        assert_eq!(skeleton("rnodern"), "modem");
+        assert_eq!(skeleton("vvill"), skeleton("will"));
+        assert_eq!(skeleton("clown"), "down");
+    }
+
+    #[test]
+    fn distinct_names() {
+        assert_ne!(skeleton("alice"), skeleton("bob"));
+        assert_ne!(skeleton("p4ypal"), skeleton("paypal"));
+        assert_ne!(skeleton("admin_"), skeleton("admin"));
+    }
+}
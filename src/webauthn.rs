@@ -0,0 +1,747 @@
+//! Passkeys and security keys, through the WebAuthn ceremonies described by https://www.w3.org/TR/webauthn-2/
+//!
+//! Challenges are handed out signed, and stored until they are answered so each can only be used once,
+//! which keeps assertions from being replayed even with authenticators that don't count signatures.
+//! Attestation isn't requested, so any authenticator can be registered, software ones included,
+//! and verifying a ceremony only needs the configuration, which makes it easy to drive from tests.
+
+use crate::{
+    config::{Config, WebAuthnConfig},
+    db::{User, WebAuthnChallenge, WebAuthnCredential},
+    html, jwt,
+};
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use rand::{rngs::OsRng, Rng};
+use ring::{
+    digest,
+    signature::{self, RsaPublicKeyComponents, UnparsedPublicKey},
+};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use serde_json::json;
+use sqlx::PgPool;
+use std::convert::TryFrom;
+use url::Url;
+
+/// Duration for which challenges can be answered, in minutes
+const CHALLENGE_DURATION: i64 = 5;
+/// Length of challenges, in bytes
+const CHALLENGE_LEN: usize = 32;
+/// Maximum length of credential IDs, in bytes
+const MAX_ID_LEN: usize = 1023;
+
+/// COSE signature algorithms supported, by order of preference
+const ES256: i64 = -7;
+const EDDSA: i64 = -8;
+const RS256: i64 = -257;
+
+/// Flags of authenticator data
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Asks the browser for a passkey when the form of a hosted page is submitted,
+/// then posts the assertion along with the rest of the form
+const SCRIPT: &str = r#"(function () {
+  var form = document.getElementById("passkey");
+  if (!window.PublicKeyCredential) {
+    form.hidden = true;
+    return;
+  }
+  var decode = function (text) {
+    return Uint8Array.from(atob(text.replace(/-/g, "+").replace(/_/g, "/")), function (c) {
+      return c.charCodeAt(0);
+    });
+  };
+  var encode = function (buffer) {
+    return btoa(String.fromCharCode.apply(null, new Uint8Array(buffer)))
+      .replace(/\+/g, "-")
+      .replace(/\//g, "_")
+      .replace(/=+$/, "");
+  };
+  form.addEventListener("submit", function (event) {
+    event.preventDefault();
+    var options = JSON.parse(form.dataset.options);
+    options.challenge = decode(options.challenge);
+    options.allowCredentials.forEach(function (credential) {
+      credential.id = decode(credential.id);
+    });
+    navigator.credentials.get({ publicKey: options }).then(function (credential) {
+      var response = credential.response;
+      form.credential.value = JSON.stringify({
+        id: credential.id,
+        response: {
+          clientDataJSON: encode(response.clientDataJSON),
+          authenticatorData: encode(response.authenticatorData),
+          signature: encode(response.signature),
+          userHandle: response.userHandle ? encode(response.userHandle) : null
+        }
+      });
+      form.submit();
+    }, function () {});
+  });
+})();"#;
+
+/// Challenge the browser has to get signed by an authenticator
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeJwt {
+    pub webauthn_challenge: String,
+}
+
+impl jwt::Purpose for ChallengeJwt {
+    const PURPOSE: &'static str = "webauthn_challenge";
+}
+
+/// Public key credential as sent by browsers, with binary fields encoded in base64url
+#[derive(Debug, Deserialize)]
+pub struct Credential<R> {
+    pub id: String,
+    pub response: R,
+}
+
+/// Response of an authenticator creating a credential
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// Response of an authenticator signing a challenge
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    /// ID of the user the credential belongs to, only sent for discoverable credentials
+    pub user_handle: Option<String>,
+}
+
+/// Data the browser passes to the authenticator
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Credential that went through registration, ready to be stored
+#[derive(Debug)]
+pub struct NewCredential {
+    pub id: String,
+    /// Public key in the COSE format
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// Passkey that signed a challenge
+#[derive(Debug)]
+pub struct Assertion {
+    pub credential: WebAuthnCredential,
+    /// Whether the authenticator verified the user, with a PIN or biometrics
+    pub user_verified: bool,
+}
+
+/// Data the authenticator signs, as described by https://www.w3.org/TR/webauthn-2/#sctn-authenticator-data
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// ID and public key of the credential, only present when it was just created
+    credential: Option<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> AuthenticatorData<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let rp_id_hash = data.get(..32)?;
+        let flags = *data.get(32)?;
+        let sign_count = u32::from_be_bytes([
+            *data.get(33)?,
+            *data.get(34)?,
+            *data.get(35)?,
+            *data.get(36)?,
+        ]);
+
+        let credential = if flags & ATTESTED_CREDENTIAL != 0 {
+            // Skip the AAGUID, which identifies the model of authenticator
+            let rest = data.get(37 + 16..)?;
+            let len = u16::from_be_bytes([*rest.get(0)?, *rest.get(1)?]) as usize;
+            if len > MAX_ID_LEN {
+                return None;
+            }
+            let id = rest.get(2..2 + len)?;
+
+            // The key may be followed by extensions, so it is parsed to know where it ends
+            let rest = rest.get(2 + len..)?;
+            let mut deserializer = serde_cbor::Deserializer::from_slice(rest);
+            Value::deserialize(&mut deserializer).ok()?;
+            Some((id, &rest[..deserializer.byte_offset()]))
+        } else {
+            None
+        };
+
+        Some(Self {
+            rp_id_hash,
+            flags,
+            sign_count,
+            credential,
+        })
+    }
+}
+
+/// Public key of a credential, along with the algorithm it verifies signatures with
+enum PublicKey {
+    /// Uncompressed P-256 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+impl PublicKey {
+    /// Parses a key in the COSE format, as described by RFC 8152
+    fn parse(cose: &[u8]) -> Option<Self> {
+        let map = match serde_cbor::from_slice(cose).ok()? {
+            Value::Map(map) => map,
+            _ => return None,
+        };
+        let get = |label: i128| map.get(&Value::Integer(label));
+        let bytes = |label: i128, len: Option<usize>| match get(label) {
+            Some(Value::Bytes(bytes)) if len.map_or(true, |len| bytes.len() == len) => {
+                Some(bytes.clone())
+            }
+            _ => None,
+        };
+        let alg = match get(3)? {
+            Value::Integer(alg) => i64::try_from(*alg).ok()?,
+            _ => return None,
+        };
+
+        // Key types are 1 for octet key pairs, 2 for elliptic curves and 3 for RSA
+        match (get(1)?, alg, get(-1)) {
+            (Value::Integer(2), ES256, Some(Value::Integer(1))) => {
+                let mut point = vec![0x04];
+                point.extend(bytes(-2, Some(32))?);
+                point.extend(bytes(-3, Some(32))?);
+                Some(Self::Es256(point))
+            }
+            (Value::Integer(1), EDDSA, Some(Value::Integer(6))) => {
+                Some(Self::Ed25519(bytes(-2, Some(32))?))
+            }
+            (Value::Integer(3), RS256, _) => Some(Self::Rs256 {
+                n: bytes(-1, None)?,
+                e: bytes(-2, None)?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            Self::Es256(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, signature)
+                .is_ok(),
+            Self::Ed25519(key) => UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, signature)
+                .is_ok(),
+            Self::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+/// Issues a challenge, returning it along with the token it has to be sent back with
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn challenge(config: &Config, pool: &PgPool) -> Result<(String, String)> {
+    let mut challenge = [0; CHALLENGE_LEN];
+    OsRng.fill(&mut challenge);
+    let challenge = encode(&challenge);
+
+    WebAuthnChallenge::delete_expired(pool).await?;
+    let duration = Duration::minutes(CHALLENGE_DURATION);
+    WebAuthnChallenge::insert(&challenge, duration, pool).await?;
+    let token = jwt::encode_for(
+        ChallengeJwt {
+            webauthn_challenge: challenge.clone(),
+        },
+        duration,
+        &config.token,
+    )
+    .await?;
+    Ok((challenge, token))
+}
+
+/// Returns the challenge a token was issued for, unless it expired or was already answered,
+/// after which it can't be answered again whether the ceremony succeeds or not
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn redeem_challenge(
+    token: String,
+    config: &Config,
+    pool: &PgPool,
+) -> Result<Option<String>> {
+    let token: Option<ChallengeJwt> = jwt::decode(token, &config.token).await?;
+    let challenge = match token {
+        Some(token) => token.webauthn_challenge,
+        None => return Ok(None),
+    };
+    if !WebAuthnChallenge::consume(&challenge, pool).await? {
+        return Ok(None);
+    }
+    Ok(Some(challenge))
+}
+
+/// Options browsers create a passkey for a user with, excluding the authenticators they already registered
+pub fn creation_options(
+    user: &User,
+    challenge: &str,
+    existing: &[WebAuthnCredential],
+    webauthn_config: &WebAuthnConfig,
+    config: &Config,
+) -> Result<serde_json::Value> {
+    let name = user.username.as_deref().unwrap_or(&user.id);
+    let algorithms: Vec<serde_json::Value> = [ES256, EDDSA, RS256]
+        .iter()
+        .map(|alg| json!({ "type": "public-key", "alg": alg }))
+        .collect();
+
+    Ok(json!({
+        "rp": {
+            "id": rp_id(webauthn_config, config)?,
+            "name": webauthn_config.rp_name,
+        },
+        "user": {
+            "id": encode(user.id.as_bytes()),
+            "name": name,
+            "displayName": user.name.as_deref().unwrap_or(name),
+        },
+        "challenge": challenge,
+        "pubKeyCredParams": algorithms,
+        "timeout": Duration::minutes(CHALLENGE_DURATION).num_milliseconds(),
+        "excludeCredentials": descriptors(existing),
+        "authenticatorSelection": {
+            "residentKey": "preferred",
+            "requireResidentKey": false,
+            "userVerification": "preferred",
+        },
+        "attestation": "none",
+    }))
+}
+
+/// Options browsers get a passkey with, any discoverable one if none are allowed explicitly
+pub fn request_options(
+    challenge: &str,
+    allowed: &[WebAuthnCredential],
+    webauthn_config: &WebAuthnConfig,
+    config: &Config,
+) -> Result<serde_json::Value> {
+    Ok(json!({
+        "rpId": rp_id(webauthn_config, config)?,
+        "challenge": challenge,
+        "timeout": Duration::minutes(CHALLENGE_DURATION).num_milliseconds(),
+        "allowCredentials": descriptors(allowed),
+        "userVerification": "preferred",
+    }))
+}
+
+/// Checks a newly created credential answers the challenge,
+/// returning it unless the browser or authenticator sent something invalid
+pub fn register(
+    credential: &Credential<AttestationResponse>,
+    challenge: &str,
+    webauthn_config: &WebAuthnConfig,
+    config: &Config,
+) -> Result<Option<NewCredential>> {
+    let rp_id = rp_id(webauthn_config, config)?;
+    let origins = origins(webauthn_config, config)?;
+    Ok(verify_attestation(
+        &credential.response,
+        challenge,
+        &rp_id,
+        &origins,
+    ))
+}
+
+/// Checks a passkey signed the challenge, for any user or only the given one,
+/// then records its use so clones of authenticators with a counter get noticed
+#[tracing::instrument(level = "debug", skip(credential, config))]
+pub async fn authenticate(
+    credential: &str,
+    challenge: &str,
+    user_id: Option<&str>,
+    config: &Config,
+    pool: &PgPool,
+) -> Result<Option<Assertion>> {
+    let webauthn_config = config
+        .webauthn
+        .as_ref()
+        .ok_or_else(|| anyhow!("WebAuthn isn't configured"))?;
+    let credential: Credential<AssertionResponse> = match serde_json::from_str(credential) {
+        Ok(credential) => credential,
+        Err(_) => return Ok(None),
+    };
+    let id = match decode(&credential.id) {
+        Some(id) => encode(&id),
+        None => return Ok(None),
+    };
+
+    let stored = match WebAuthnCredential::select(&id, pool).await? {
+        Some(stored) if user_id.map_or(true, |id| id == stored.user_id) => stored,
+        _ => return Ok(None),
+    };
+    let rp_id = rp_id(webauthn_config, config)?;
+    let origins = origins(webauthn_config, config)?;
+    let (sign_count, user_verified) =
+        match verify_assertion(&stored, &credential.response, challenge, &rp_id, &origins) {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+    if !WebAuthnCredential::update_sign_count(&stored.id, sign_count.into(), pool).await? {
+        return Ok(None);
+    }
+    Ok(Some(Assertion {
+        credential: stored,
+        user_verified,
+    }))
+}
+
+/// Builds the form of a hosted page letting the user log in with a passkey
+pub fn form(
+    action: &str,
+    request: &str,
+    challenge: &str,
+    options: &serde_json::Value,
+    label: &str,
+) -> String {
+    format!(
+        r#"<form id="passkey" method="post" action="{}" data-options="{}"><input type="hidden" name="request" value="{}"><input type="hidden" name="challenge" value="{}"><input type="hidden" name="credential"><button type="submit">{}</button></form><script>{}</script>"#,
+        action,
+        html::escape(&options.to_string()),
+        html::escape(request),
+        html::escape(challenge),
+        label,
+        SCRIPT,
+    )
+}
+
+/// Verifies the registration of a credential as described by https://www.w3.org/TR/webauthn-2/#sctn-registering-a-new-credential
+fn verify_attestation(
+    response: &AttestationResponse,
+    challenge: &str,
+    rp_id: &str,
+    origins: &[String],
+) -> Option<NewCredential> {
+    let client_data = decode(&response.client_data_json)?;
+    if !check_client_data(&client_data, "webauthn.create", challenge, origins) {
+        return None;
+    }
+
+    // The attestation statement is ignored since none was requested
+    let attestation = match serde_cbor::from_slice(&decode(&response.attestation_object)?).ok()? {
+        Value::Map(map) => map,
+        _ => return None,
+    };
+    let data = match attestation.get(&Value::Text("authData".to_owned()))? {
+        Value::Bytes(data) => data,
+        _ => return None,
+    };
+    let data = AuthenticatorData::parse(data)?;
+    if !check_authenticator_data(&data, rp_id) {
+        return None;
+    }
+
+    let (id, public_key) = data.credential?;
+    // Keys that couldn't be used later on are rejected right away
+    PublicKey::parse(public_key)?;
+    Some(NewCredential {
+        id: encode(id),
+        public_key: public_key.to_vec(),
+        sign_count: data.sign_count,
+    })
+}
+
+/// Verifies an assertion as described by https://www.w3.org/TR/webauthn-2/#sctn-verifying-assertion,
+/// returning the new signature counter and whether the user was verified
+fn verify_assertion(
+    credential: &WebAuthnCredential,
+    response: &AssertionResponse,
+    challenge: &str,
+    rp_id: &str,
+    origins: &[String],
+) -> Option<(u32, bool)> {
+    // Discoverable credentials tell who they belong to, which must match
+    if let Some(handle) = response.user_handle.as_deref().filter(|h| !h.is_empty()) {
+        if decode(handle)? != credential.user_id.as_bytes() {
+            return None;
+        }
+    }
+
+    let client_data = decode(&response.client_data_json)?;
+    if !check_client_data(&client_data, "webauthn.get", challenge, origins) {
+        return None;
+    }
+    let mut message = decode(&response.authenticator_data)?;
+    let data = AuthenticatorData::parse(&message)?;
+    if !check_authenticator_data(&data, rp_id) {
+        return None;
+    }
+    let result = (data.sign_count, data.flags & USER_VERIFIED != 0);
+
+    // Authenticators sign their data followed by the hash of the client data
+    message.extend_from_slice(digest::digest(&digest::SHA256, &client_data).as_ref());
+    let key = PublicKey::parse(&credential.public_key)?;
+    if !key.verify(&message, &decode(&response.signature)?) {
+        return None;
+    }
+    Some(result)
+}
+
+fn check_client_data(data: &[u8], kind: &str, challenge: &str, origins: &[String]) -> bool {
+    match serde_json::from_slice::<ClientData>(data) {
+        Ok(data) => {
+            data.kind == kind && data.challenge == challenge && origins.contains(&data.origin)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Checks the credential is bound to Vaulth and the user touched their authenticator
+fn check_authenticator_data(data: &AuthenticatorData, rp_id: &str) -> bool {
+    data.rp_id_hash == digest::digest(&digest::SHA256, rp_id.as_bytes()).as_ref()
+        && data.flags & USER_PRESENT != 0
+}
+
+fn descriptors(credentials: &[WebAuthnCredential]) -> Vec<serde_json::Value> {
+    credentials
+        .iter()
+        .map(|c| json!({ "type": "public-key", "id": c.id }))
+        .collect()
+}
+
+fn rp_id(webauthn_config: &WebAuthnConfig, config: &Config) -> Result<String> {
+    match &webauthn_config.rp_id {
+        Some(rp_id) => Ok(rp_id.clone()),
+        None => Url::parse(&config.root_uri)?
+            .host_str()
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("root URI has no host")),
+    }
+}
+
+fn origins(webauthn_config: &WebAuthnConfig, config: &Config) -> Result<Vec<String>> {
+    if !webauthn_config.origins.is_empty() {
+        return Ok(webauthn_config.origins.clone());
+    }
+    Ok(vec![Url::parse(&config.root_uri)?
+        .origin()
+        .ascii_serialization()])
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(text: &str) -> Option<Vec<u8>> {
+    base64::decode_config(text.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use std::collections::BTreeMap;
+
+    const RP_ID: &str = "example.com";
+    const ORIGIN: &str = "https://example.com";
+    const CHALLENGE: &str = "c2lnbi1tZS1wbGVhc2U";
+    const USER_ID: &str = "user";
+
+    /// Authenticator keeping a single P-256 credential in memory, like a browser's virtual authenticator
+    struct SoftwareAuthenticator {
+        id: Vec<u8>,
+        key: EcdsaKeyPair,
+        rng: SystemRandom,
+        sign_count: u32,
+    }
+
+    impl SoftwareAuthenticator {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+            Self {
+                id: b"software-credential".to_vec(),
+                key,
+                rng,
+                sign_count: 0,
+            }
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let point = self.key.public_key().as_ref();
+            let mut map = BTreeMap::new();
+            map.insert(Value::Integer(1), Value::Integer(2));
+            map.insert(Value::Integer(3), Value::Integer(ES256.into()));
+            map.insert(Value::Integer(-1), Value::Integer(1));
+            map.insert(Value::Integer(-2), Value::Bytes(point[1..33].to_vec()));
+            map.insert(Value::Integer(-3), Value::Bytes(point[33..].to_vec()));
+            serde_cbor::to_vec(&Value::Map(map)).unwrap()
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8, attested: bool) -> Vec<u8> {
+            let mut data = digest::digest(&digest::SHA256, rp_id.as_bytes())
+                .as_ref()
+                .to_vec();
+            data.push(flags | if attested { ATTESTED_CREDENTIAL } else { 0 });
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if attested {
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.id);
+                data.extend(self.cose_key());
+            }
+            data
+        }
+
+        fn create(&self, challenge: &str, origin: &str) -> AttestationResponse {
+            let mut attestation = BTreeMap::new();
+            attestation.insert(
+                Value::Text("fmt".to_owned()),
+                Value::Text("none".to_owned()),
+            );
+            attestation.insert(
+                Value::Text("attStmt".to_owned()),
+                Value::Map(BTreeMap::new()),
+            );
+            attestation.insert(
+                Value::Text("authData".to_owned()),
+                Value::Bytes(self.authenticator_data(RP_ID, USER_PRESENT | USER_VERIFIED, true)),
+            );
+            AttestationResponse {
+                client_data_json: encode(&client_data("webauthn.create", challenge, origin)),
+                attestation_object: encode(&serde_cbor::to_vec(&Value::Map(attestation)).unwrap()),
+            }
+        }
+
+        fn get(&mut self, challenge: &str, rp_id: &str, flags: u8) -> AssertionResponse {
+            self.sign_count += 1;
+            let client_data = client_data("webauthn.get", challenge, ORIGIN);
+            let data = self.authenticator_data(rp_id, flags, false);
+            let mut message = data.clone();
+            message.extend_from_slice(digest::digest(&digest::SHA256, &client_data).as_ref());
+            let signature = self.key.sign(&self.rng, &message).unwrap();
+            AssertionResponse {
+                client_data_json: encode(&client_data),
+                authenticator_data: encode(&data),
+                signature: encode(signature.as_ref()),
+                user_handle: Some(encode(USER_ID.as_bytes())),
+            }
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "type": kind,
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        }))
+        .unwrap()
+    }
+
+    fn register(authenticator: &SoftwareAuthenticator) -> WebAuthnCredential {
+        let response = authenticator.create(CHALLENGE, ORIGIN);
+        let created =
+            verify_attestation(&response, CHALLENGE, RP_ID, &[ORIGIN.to_owned()]).unwrap();
+        WebAuthnCredential {
+            id: created.id,
+            user_id: USER_ID.to_owned(),
+            name: "Software".to_owned(),
+            inserted_at: Utc::now(),
+            last_used_at: None,
+            public_key: created.public_key,
+            sign_count: created.sign_count.into(),
+        }
+    }
+
+    #[test]
+    fn registration() {
+        let authenticator = SoftwareAuthenticator::new();
+        let credential = register(&authenticator);
+        assert_eq!(credential.id, encode(&authenticator.id));
+        assert_eq!(credential.sign_count, 0);
+
+        let origins = [ORIGIN.to_owned()];
+        let response = authenticator.create("other-challenge", ORIGIN);
+        assert!(verify_attestation(&response, CHALLENGE, RP_ID, &origins).is_none());
+        let response = authenticator.create(CHALLENGE, "https://evil.com");
+        assert!(verify_attestation(&response, CHALLENGE, RP_ID, &origins).is_none());
+        let response = authenticator.create(CHALLENGE, ORIGIN);
+        assert!(verify_attestation(&response, CHALLENGE, "evil.com", &origins).is_none());
+    }
+
+    #[test]
+    fn assertion() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let credential = register(&authenticator);
+        let origins = [ORIGIN.to_owned()];
+
+        let response = authenticator.get(CHALLENGE, RP_ID, USER_PRESENT | USER_VERIFIED);
+        assert_eq!(
+            verify_assertion(&credential, &response, CHALLENGE, RP_ID, &origins),
+            Some((1, true))
+        );
+        let response = authenticator.get(CHALLENGE, RP_ID, USER_PRESENT);
+        assert_eq!(
+            verify_assertion(&credential, &response, CHALLENGE, RP_ID, &origins),
+            Some((2, false))
+        );
+    }
+
+    #[test]
+    fn invalid_assertions() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let credential = register(&authenticator);
+        let origins = [ORIGIN.to_owned()];
+        let verify = |response: &AssertionResponse| {
+            verify_assertion(&credential, response, CHALLENGE, RP_ID, &origins)
+        };
+
+        let response = authenticator.get("other-challenge", RP_ID, USER_PRESENT);
+        assert!(verify(&response).is_none());
+        let response = authenticator.get(CHALLENGE, "evil.com", USER_PRESENT);
+        assert!(verify(&response).is_none());
+        let response = authenticator.get(CHALLENGE, RP_ID, USER_VERIFIED);
+        assert!(verify(&response).is_none());
+
+        let mut response = authenticator.get(CHALLENGE, RP_ID, USER_PRESENT);
+        response.user_handle = Some(encode(b"someone-else"));
+        assert!(verify(&response).is_none());
+
+        // Signatures cover the counter, which can't be rolled back without the key
+        let mut response = authenticator.get(CHALLENGE, RP_ID, USER_PRESENT);
+        let mut data = decode(&response.authenticator_data).unwrap();
+        data[36] = 0;
+        response.authenticator_data = encode(&data);
+        assert!(verify(&response).is_none());
+
+        // Same credential ID, signed by another key
+        let mut other = SoftwareAuthenticator::new();
+        let response = other.get(CHALLENGE, RP_ID, USER_PRESENT);
+        assert!(verify(&response).is_none());
+    }
+}